@@ -1,4 +1,6 @@
 use clap::Parser;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::path::PathBuf;
 
 #[derive(Parser, Debug)]
 #[command(name = "DSVA Node")]
@@ -8,7 +10,124 @@ pub struct Args {
     #[arg(short, long)]
     pub port: u16,
 
+    /// Address to listen on (0.0.0.0 for all interfaces)
+    #[arg(long, default_value = "127.0.0.1")]
+    pub host: Ipv4Addr,
+
+    /// Address the other nodes reach me at (ip or ip:port), announced by discovery and gossip.
+    /// Defaults to the host and port I listen on
+    #[arg(long)]
+    pub advertise: Option<String>,
+
     /// List of friends to connect to (format: port or ip:port)
     #[arg(short, long, value_delimiter = ',')]
     pub friends: Vec<String>,
+
+    /// Max concurrent outbound requests (and inbound handlers)
+    #[arg(long, default_value_t = 64, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    pub max_in_flight: usize,
+
+    /// How many times an idempotent message is resent before giving up
+    #[arg(long, default_value_t = 3)]
+    pub retries: u32,
+
+    /// Delay before the first retry in milliseconds, doubled for each next one
+    #[arg(long, default_value_t = 200)]
+    pub retry_backoff_ms: u64,
+
+    /// Time the whole tree has to report its power after `cal`, in milliseconds
+    #[arg(long, default_value_t = 10000)]
+    pub calc_timeout_ms: u64,
+
+    /// Interval of heartbeats between parent and children, in milliseconds
+    #[arg(long, default_value_t = 1000)]
+    pub heartbeat_interval_ms: u64,
+
+    /// Missed heartbeats in a row before a friend is suspected
+    #[arg(long, default_value_t = 2)]
+    pub suspect_after: u32,
+
+    /// Missed heartbeats in a row before a friend is declared dead
+    #[arg(long, default_value_t = 5)]
+    pub dead_after: u32,
+
+    /// How many children of the leader keep a copy of its job state
+    #[arg(long, default_value_t = 1)]
+    pub standbys: usize,
+
+    /// Ranges with fewer candidates left are not split for idle nodes
+    #[arg(long, default_value_t = 1000)]
+    pub steal_min_size: usize,
+
+    /// How often workers report how far their search got, in milliseconds
+    #[arg(long, default_value_t = 2000)]
+    pub progress_interval_ms: u64,
+
+    /// How the leader hands out work: split = one part per node by power, lease = small chunks on request
+    #[arg(long, default_value = "split", value_parser = ["split", "lease"])]
+    pub scheduler: String,
+
+    /// Candidates per chunk with the lease scheduler
+    #[arg(long, default_value_t = 100000)]
+    pub chunk_size: usize,
+
+    /// How long a chunk stays leased without a progress report, in milliseconds
+    #[arg(long, default_value_t = 10000)]
+    pub lease_ms: u64,
+
+    /// Directory for checkpoints of jobs and of my position, nothing is written when not set
+    #[arg(long)]
+    pub state_dir: Option<PathBuf>,
+
+    /// How often checkpoints are written, in milliseconds
+    #[arg(long, default_value_t = 5000)]
+    pub checkpoint_interval_ms: u64,
+
+    /// Jobs the leader runs at the same time, further ones wait in its queue by priority
+    #[arg(long, default_value_t = 2)]
+    pub max_jobs: usize,
+
+    /// Candidates a worker searches of one job (times the job's share) before switching to another job
+    #[arg(long, default_value_t = 1000000)]
+    pub slice_size: usize,
+
+    /// Release the whole tree (every node back to idle) once the leader's last job is over
+    #[arg(long)]
+    pub release_on_finish: bool,
+
+    /// How often nodes exchange their member lists, in milliseconds (0 = no gossip)
+    #[arg(long, default_value_t = 1000)]
+    pub gossip_interval_ms: u64,
+
+    /// How many nodes get my member list every gossip round
+    #[arg(long, default_value_t = 2)]
+    pub gossip_fanout: usize,
+
+    /// Members learned by gossip become friends until a node has this many
+    #[arg(long, default_value_t = 8)]
+    pub max_friends: usize,
+
+    /// Find friends on the LAN by UDP multicast, no --friends needed
+    #[arg(long)]
+    pub discovery: bool,
+
+    /// Multicast group and port of the discovery
+    #[arg(long, default_value = "239.255.77.77:7777")]
+    pub discovery_group: SocketAddrV4,
+
+    /// Interface the discovery runs on (127.0.0.1 for a single machine)
+    #[arg(long, default_value = "0.0.0.0")]
+    pub discovery_interface: Ipv4Addr,
+
+    /// How often a node announces itself, in milliseconds
+    #[arg(long, default_value_t = 2000)]
+    pub discovery_interval_ms: u64,
+
+    /// Only nodes with the same cluster name find each other
+    #[arg(long, default_value = "dsva")]
+    pub cluster_name: String,
+
+    /// Shared secret of the cluster, announcements without it are ignored
+    #[arg(long)]
+    pub cluster_key: Option<String>,
 }
@@ -0,0 +1,180 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use tokio::time::MissedTickBehavior;
+
+use crate::communication::handle_solve_response_message;
+use crate::messages::{decode_parts, encode_parts, parse_message, JobMessage, Message, SolveResponseMessage};
+use crate::problem::{Job, PartOfAProblem, PartOfAProblemState};
+use crate::utils::{Node, NodeState};
+
+// Checkpoint files, all of them plain text:
+//   job-<job id>.ckpt       written by the leader - the JOB message of the job, then its parts (like in messages)
+//   worker-<address>.ckpt   written by every worker - job_id|start|position|end of the part it searches
+
+/// Periodically writes the leader's job and my current position to `--state-dir`, if it is set.
+/// Files are replaced atomically, so a crash in the middle of a write leaves the previous checkpoint.
+pub async fn run_checkpoints(node: Node) {
+    let Some(dir) = node.config.state_dir.clone() else {
+        return;
+    };
+    let created = {
+        let dir = dir.clone();
+        tokio::task::spawn_blocking(move || fs::create_dir_all(dir)).await.unwrap_or_else(|e| Err(io::Error::other(e)))
+    };
+    if let Err(e) = created {
+        eprintln!("Can't create state dir {}: {}", dir.display(), e);
+        return;
+    }
+    let mut interval = tokio::time::interval(Duration::from_millis(node.config.checkpoint_interval_ms));
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    // what was written last time, unchanged state isn't written again
+    let mut last_leader: Option<u64> = None;
+    let mut last_worker: Option<String> = None;
+    loop {
+        tokio::select! {
+            _ = node.shutdown.cancelled() => break,
+            _ = interval.tick() => {}
+        }
+        let version = node.leader_version.load(Ordering::SeqCst);
+        let leader_jobs: Vec<(Job, Vec<PartOfAProblem>)> = match &*node.state.lock().unwrap() {
+            NodeState::LEADER { jobs } => jobs.iter().map(|job| (job.problem.job.clone(), job.parts.clone())).collect(),
+            _ => Vec::new(),
+        };
+        if last_leader != Some(version) {
+            let mut written = true;
+            for (job, parts) in leader_jobs {
+                let contents = format!("{}\n{}\n", JobMessage::new(node.address.clone(), node.address.clone(), &job).serialize(), encode_parts(&parts));
+                if let Err(e) = write_in_background(job_file(&dir, &job.id), contents).await {
+                    eprintln!("Checkpoint of job {} failed: {}", job.id, e);
+                    written = false;
+                }
+                // ended while it was being written
+                if !node.state.lock().unwrap().is_active_job(&job.id) {
+                    let path = job_file(&dir, &job.id);
+                    let _ = tokio::task::spawn_blocking(move || fs::remove_file(path)).await;
+                }
+            }
+            if written {
+                last_leader = Some(version);
+            }
+        }
+        let worker = node.solving_part_of_a_problem.lock().unwrap().clone()
+            .filter(|part| part.state == PartOfAProblemState::Solving)
+            .map(|part| format!("{}|{}|{}|{}", part.job_id, part.start, node.current_position.load(Ordering::SeqCst), node.current_end.load(Ordering::SeqCst)));
+        if let Some(contents) = worker
+            && last_worker.as_ref() != Some(&contents) {
+            match write_in_background(worker_file(&dir, &node.address), contents.clone()).await {
+                Ok(()) => last_worker = Some(contents),
+                Err(e) => eprintln!("Checkpoint of my position failed: {}", e),
+            }
+        }
+    }
+}
+
+// a slow disk would hold up a runtime worker (heartbeats, requests) for the whole write and sync
+async fn write_in_background(path: PathBuf, contents: String) -> io::Result<()> {
+    tokio::task::spawn_blocking(move || write_atomic(&path, &contents)).await.unwrap_or_else(|e| Err(io::Error::other(e)))
+}
+
+// temporary file in the same directory, synced and renamed over the old one
+fn write_atomic(path: &Path, contents: &str) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    {
+        let mut file = fs::File::create(&tmp)?;
+        file.write_all(contents.as_bytes())?;
+        file.sync_all()?;
+    }
+    fs::rename(&tmp, path)?;
+    // the rename itself is durable once the directory is synced
+    if let Some(dir) = path.parent() {
+        fs::File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+fn job_file(dir: &Path, job_id: &str) -> PathBuf {
+    dir.join(format!("job-{}.ckpt", file_name(job_id)))
+}
+
+fn worker_file(dir: &Path, address: &str) -> PathBuf {
+    dir.join(format!("worker-{}.ckpt", file_name(address)))
+}
+
+// job ids and addresses contain ':' and '#'
+fn file_name(s: &str) -> String {
+    s.chars().map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '-' { c } else { '_' }).collect()
+}
+
+/// Reads a job checkpoint written by the leader. Ranges that were being searched are searched again,
+/// their holders are gone after a restart.
+pub fn load_job(path: &Path) -> Result<(Job, Vec<PartOfAProblem>), String> {
+    let contents = fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let mut lines = contents.lines();
+    let job = lines.next()
+        .and_then(parse_message)
+        .and_then(|message| message.as_any().downcast_ref::<JobMessage>().map(|job_message| job_message.to_job()))
+        .ok_or("first line is not a job")?;
+    let parts = lines.next()
+        .and_then(decode_parts)
+        .ok_or("second line is not a list of parts")?
+        .into_iter()
+        .map(|part| match part.state {
+            PartOfAProblemState::SearchedAndNotFound => part,
+            _ => PartOfAProblem { state: PartOfAProblemState::NotDistributed, ..part },
+        })
+        .collect();
+    Ok((job, parts))
+}
+
+/// After a restart a worker may know how far it got in a job the leader resumed.
+/// That beginning of its old part is reported as searched, like any other solve response.
+pub async fn report_checkpointed_progress(node: &Node, job: &Job) {
+    let Some(dir) = node.config.state_dir.as_ref() else {
+        return;
+    };
+    let Ok(contents) = fs::read_to_string(worker_file(dir, &node.address)) else {
+        return;
+    };
+    let fields: Vec<&str> = contents.trim().split('|').collect();
+    let [job_id, start, position, _end] = fields[..] else {
+        return;
+    };
+    let (Ok(start), Ok(position)) = (start.parse::<usize>(), position.parse::<usize>()) else {
+        return;
+    };
+    if job_id != job.id || position <= start {
+        return;
+    }
+    println!("Checkpoint says I searched {}..={} of job {}", start, position - 1, job.id);
+    let report = SolveResponseMessage {
+        from: node.address.clone(),
+        to: node.address.clone(),
+        job_id: job.id.clone(),
+        start,
+        end: position - 1,
+        solution: None,
+        space_searched: true,
+    };
+    handle_solve_response_message(node, Box::new(report)).await;
+}
+
+/// The job is over, nothing to resume. None = all jobs, only my position is removed then.
+pub fn remove_checkpoints(node: &Node, job_id: Option<&str>) {
+    let Some(dir) = node.config.state_dir.as_ref() else {
+        return;
+    };
+    if let Some(job_id) = job_id {
+        let _ = fs::remove_file(job_file(dir, job_id));
+    }
+    // my position may be in another job
+    let worker = worker_file(dir, &node.address);
+    let mine = fs::read_to_string(&worker)
+        .is_ok_and(|contents| job_id.is_none_or(|job_id| contents.split('|').next() == Some(job_id)));
+    if mine {
+        let _ = fs::remove_file(worker);
+    }
+}
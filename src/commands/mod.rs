@@ -1,20 +1,24 @@
+mod tree;
+
 use std::io::{self, BufRead};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
 use crate::Node;
-use crate::communication::handle_solve_response_message;
 use crate::communication::stop_cal_and_propagate;
 use crate::messages;
-use crate::problem::PartOfAProblem;
-use crate::problem::update_state_of_parts;
+use crate::problem::{Job, SUPPORTED_ALGORITHMS};
 use crate::utils::{parse_address, NodeState};
 
 use messages::{PingMessage};
 use messages::send_message;
-use crate::communication::calculate_total_power;
+use crate::communication::{collect_tree, join_tree, leave_tree, release_and_propagate, start_power_calculation, take_snapshot};
+use tree::{render_ascii, render_dot, render_json};
 
 use crate::problem::{Problem};
 use crate::problem::Combinable;
 
-use crate::communication::{send_parts_to_friends, assign_parts_to_self_and_friends};
+use crate::communication::{end_job, pause_job, resume_job, submit_job, pause_and_propagate, resume_and_propagate};
+use crate::checkpoint::{load_job, report_checkpointed_progress};
 
 pub fn process_commands(_node: &Node) {
     let stdin = io::stdin();
@@ -29,9 +33,18 @@ pub fn process_commands(_node: &Node) {
         let parts: Vec<&str> = line.split_whitespace().collect();
 
         match parts[0] {
+            // main finishes what is in flight and exits
             "die" => {
                 println!("Shutting down node...");
-                std::process::exit(0);
+                _node.shutdown.cancel();
+                return;
+            }
+            "leave" => {
+                if _node.runtime.block_on(leave_tree(_node)) {
+                    println!("Left the tree, shutting down node...");
+                    _node.shutdown.cancel();
+                    return;
+                }
             }
             "info" => {
                 _node.print_info();
@@ -53,9 +66,27 @@ pub fn process_commands(_node: &Node) {
             "solve" => {
                 handle_solve_command(_node, parts);
             }
+            "pause" => {
+                handle_pause_command(_node);
+            }
+            "resume" => {
+                handle_resume_command(_node, parts);
+            }
+            "job" => {
+                handle_job_command(_node, parts);
+            }
+            "tree" => {
+                handle_tree_command(_node, parts);
+            }
+            "snapshot" => {
+                handle_snapshot_command(_node, parts);
+            }
+            "release" | "reset" => {
+                _node.runtime.block_on(release_and_propagate(_node));
+            }
             // should not be called manually on worker
             "stop" => {
-                stop_cal_and_propagate(_node);
+                _node.runtime.block_on(stop_cal_and_propagate(_node));
             }
             _ => {
                 println!("Unknown command: {}", line);
@@ -77,7 +108,7 @@ fn handle_ping_command(_node: &Node, parts: Vec<&str>) {
         from: _node.address.clone(),
         to: address,
     };
-    send_message(&message, _node);
+    _node.runtime.block_on(send_message(&message, _node));
 }
 
 fn handle_connect_command(_node: &Node, parts: Vec<&str>) {
@@ -92,6 +123,8 @@ fn handle_connect_command(_node: &Node, parts: Vec<&str>) {
     // now ping it
     let address_str = address.to_string();
     handle_ping_command(_node, vec!["ping", &address_str]);
+    // started late - a tree that already runs a job takes me in
+    _node.runtime.block_on(join_tree(_node, &address));
 }
 
 fn handle_calculate_command(_node: &Node) {
@@ -101,18 +134,22 @@ fn handle_calculate_command(_node: &Node) {
     }
     println!("Starting calculation...");
 
-    // set to leader
-    _node.set_state_leader();
-    // calculate power
-    let total_power = calculate_total_power(_node);
-    println!("Total calculated power: {}", total_power);
+    // set to leader, the tree is built by a new wave
+    let wave = _node.new_wave_id();
+    if _node.try_join_wave(&wave, &_node.address).is_err() {
+        println!("Node was taken by another wave meanwhile.");
+        return;
+    }
+    println!("Starting wave {}", wave);
+    // calculate power, the total is printed once the whole tree answered
+    _node.runtime.block_on(start_power_calculation(_node, None, _node.config.calc_timeout_ms));
 }
 
 
 
 fn handle_solve_command(_node: &Node, parts: Vec<&str>) {
-    if parts.len() < 4 {
-        println!("Usage: solve <alphabet> <min_len> <max_len> <target_hash>");
+    if parts.len() < 5 {
+        println!("Usage: solve <alphabet> <min_len> <max_len> <target_hash>[,<target_hash>...] [algorithm] [priority] [share]");
         println!("Example: solve abc 2 3 ca978112ca1bbdcafac231b39a23dc4da786eff8147c4e72b9807785afee48bb");
         return;
     }
@@ -121,6 +158,10 @@ fn handle_solve_command(_node: &Node, parts: Vec<&str>) {
         return;
     }
     let alphabet = parts[1].to_string();
+    if alphabet.is_empty() {
+        println!("Alphabet can't be empty");
+        return;
+    }
     let min_length = match parts[2].parse::<usize>() {
         Ok(n) => n,
         Err(_) => {
@@ -129,94 +170,173 @@ fn handle_solve_command(_node: &Node, parts: Vec<&str>) {
         }
     };
     let max_length = match parts[3].parse::<usize>() {
-        Ok(n) => n,
-        Err(_) => {
+        Ok(n) if n >= min_length => n,
+        _ => {
             println!("Invalid max_length: {}", parts[3]);
             return;
         }
     };
-    let hash = parts[4].to_string();
-    let start = alphabet.chars().next().unwrap().to_string().repeat(min_length);
-    let end = alphabet.chars().last().unwrap().to_string().repeat(max_length);
-    let start_copy = start.clone();
-    let end_copy = end.clone();
-    let problem = Problem::new(alphabet, start, end, hash);
+    let targets: Vec<String> = parts[4].split(',').map(|t| t.to_lowercase()).collect();
+    let algorithm = parts.get(5).unwrap_or(&"sha256").to_string();
+    if !SUPPORTED_ALGORITHMS.contains(&algorithm.as_str()) {
+        println!("Unsupported algorithm {}, use one of {:?}", algorithm, SUPPORTED_ALGORITHMS);
+        return;
+    }
+    let Ok(priority) = parts.get(6).unwrap_or(&"0").parse::<u32>() else {
+        println!("Invalid priority: {}", parts[6]);
+        return;
+    };
+    let share = match parts.get(7).unwrap_or(&"1").parse::<u32>() {
+        Ok(n) if n > 0 => n,
+        _ => {
+            println!("Invalid share: {}", parts[7]);
+            return;
+        }
+    };
+    let started_ms = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+    let job = Job {
+        id: format!("{}#{}", _node.address, started_ms),
+        algorithm,
+        alphabet,
+        min_len: min_length,
+        max_len: max_length,
+        targets,
+        chunk_size: _node.config.chunk_size,
+        priority,
+        share,
+    };
+    let problem = Problem::new(job.clone());
     println!("Problem defined: {:?}", problem);
     println!("Total combinations to try: {}", problem.total_combinations());
-    // save problem to node state
-    let mut state = _node.state.lock().unwrap();
-    if let NodeState::LEADER { problem: node_problem, parts: node_parts } = &mut *state {
-        *node_problem = Some(problem.clone());
-        *node_parts = vec![
-            PartOfAProblem::new_from_problem(&problem, start_copy, end_copy)
-        ];
-    }
-    drop(state);
-    // distributing
-    let mut available_power = _node.friends.lock().unwrap().iter().filter(|friend | friend.is_child()).map(|friend| friend.power).sum::<u32>();
-    available_power += _node.power;
-    let parts = problem.divide_into_n(available_power as usize);
-    println!("Divided into {} parts.", parts.len());
-    for (i, part) in parts.iter().enumerate() {
-        println!("Part {}: {:?}, combinations: {}", i, part, part.total_combinations());
-    }
-    assign_parts_to_self_and_friends(_node, parts);
-    send_parts_to_friends(_node);
-    // solve my part in new thread
-    println!("LEADER started solving problem...");
-    let mut problem_part = _node.solving_part_of_a_problem.lock().unwrap().as_ref().unwrap().clone();
-    problem_part.state = crate::problem::PartOfAProblemState::Solving;
-    _node.stop_flag.store(false, std::sync::atomic::Ordering::SeqCst);
-    let stop_flag = _node.stop_flag.clone();
-    // setting state of my part as solving
-    _node.solving_part_of_a_problem.lock().unwrap().as_mut().unwrap().state = crate::problem::PartOfAProblemState::Solving;
-    // updating leader parts state
-    {
-        let mut binding = _node.state.lock().unwrap();
-        if let NodeState::LEADER { problem: _, parts } = &mut *binding {
-            update_state_of_parts(parts, &problem_part);
-        } else {
-            panic!("Node is not leader anymore!");
+    // every node of the tree learns the job when it starts, parts then only carry its id
+    let whole_part = job.whole_part();
+    _node.runtime.block_on(submit_job(_node, job, vec![whole_part]));
+}
+
+// the whole tree as seen right now, printed or written to a file
+fn handle_tree_command(_node: &Node, parts: Vec<&str>) {
+    if !_node.is_leader() {
+        println!("Only leader can show the tree.");
+        return;
+    }
+    let render = match parts.get(1).copied().unwrap_or("ascii") {
+        "ascii" => render_ascii,
+        "json" => render_json,
+        "dot" => render_dot,
+        _ => {
+            println!("Usage: tree [ascii|json|dot] [<file>]");
+            return;
         }
+    };
+    let entries = _node.runtime.block_on(collect_tree(_node));
+    let output = render(&entries);
+    match parts.get(2) {
+        Some(path) => match std::fs::write(path, &output) {
+            Ok(()) => println!("Tree of {} nodes written to {}", entries.len(), path),
+            Err(e) => println!("Failed to write {}: {}", path, e),
+        },
+        None => print!("{}", output),
     }
+}
 
-    // Clone the entire node to move into the thread
-    let node_clone = _node.clone();
-    std::thread::spawn(move || {
-        let mut problem = Problem::new_from_part(&problem_part);
-        // Pass stop_flag from node (as AtomicBool)
-        // Now you can use node_clone inside the thread
-        match problem.brute_force(&*stop_flag) {
-            Some(solution) => {
-                let message = messages::SolveResponseMessage {
-                    from: node_clone.address.clone(),
-                    to: node_clone.address.clone(), // to leader
-                    start: problem_part.start.clone(),
-                    end: problem_part.end.clone(),
-                    solution: Some(solution),
-                    space_searched: true,
-                };
-                handle_solve_response_message(&node_clone, Box::new(message));
-            },
-            None => {
-                let message = messages::SolveResponseMessage {
-                    from: node_clone.address.clone(),
-                    to: node_clone.address.clone(), // to leader
-                    start: problem_part.start.clone(),
-                    end: problem_part.end.clone(),
-                    solution: None,
-                    space_searched: true,
-                };
-                let space_searched = !node_clone.stop_flag.load(std::sync::atomic::Ordering::SeqCst);
-                if space_searched {
-                    handle_solve_response_message(&node_clone, Box::new(message));
-                    node_clone.solving_part_of_a_problem.lock().unwrap().as_mut().unwrap().state = crate::problem::PartOfAProblemState::SearchedAndNotFound;
-                } else {
-                    // not needed anymore - found elsewhere or calculations stopped...
-                    // node_clone.solving_part_of_a_problem.lock().unwrap().as_mut().unwrap().state = crate::problem::PartOfAProblemState::NotDistributed;
-                }
-                stop_flag.store(true, std::sync::atomic::Ordering::SeqCst);
-            },
+// consistent state of the whole cluster, for looking at offline
+fn handle_snapshot_command(_node: &Node, parts: Vec<&str>) {
+    if !_node.is_leader() {
+        println!("Only leader can take a snapshot.");
+        return;
+    }
+    let (id, snapshot) = _node.runtime.block_on(take_snapshot(_node));
+    let path = parts.get(1).map_or_else(|| format!("snapshot-{}.txt", id.replace([':', '@'], "_")), |path| path.to_string());
+    match std::fs::write(&path, snapshot) {
+        Ok(()) => println!("Snapshot {} written to {}", id, path),
+        Err(e) => println!("Failed to write {}: {}", path, e),
+    }
+}
+
+fn handle_pause_command(_node: &Node) {
+    if !_node.is_leader() {
+        println!("Only leader can pause the search.");
+        return;
+    }
+    _node.runtime.block_on(pause_and_propagate(_node));
+}
+
+// without a file the paused search continues, with one a checkpointed job is loaded
+fn handle_resume_command(_node: &Node, parts: Vec<&str>) {
+    if !_node.is_leader() {
+        println!("Only leader can resume.");
+        return;
+    }
+    if parts.len() < 2 {
+        if !_node.paused.load(std::sync::atomic::Ordering::SeqCst) {
+            println!("Usage: resume [<job-file>]");
+            println!("Example: resume state/job-127.0.0.1_3000_1700000000000.ckpt");
+            return;
         }
-    });
-}
\ No newline at end of file
+        _node.runtime.block_on(resume_and_propagate(_node));
+        return;
+    }
+    let (job, job_parts) = match load_job(Path::new(parts[1])) {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            println!("Can't load checkpoint {}: {}", parts[1], e);
+            return;
+        }
+    };
+    println!("Resuming job {}: {:?}", job.id, job);
+    println!("Checkpointed parts: {:?}", job_parts);
+    _node.runtime.block_on(submit_job(_node, job.clone(), job_parts));
+    // my own position from before the restart
+    _node.runtime.block_on(report_checkpointed_progress(_node, &job));
+}
+
+fn handle_job_command(_node: &Node, parts: Vec<&str>) {
+    let usage = || {
+        println!("Usage: job list | job pause <job-id> | job resume <job-id> | job cancel <job-id>");
+        println!("The job id can be shortened to the part after '#'");
+    };
+    if !_node.is_leader() {
+        println!("Only leader has jobs.");
+        return;
+    }
+    if parts.get(1) == Some(&"list") {
+        let state = _node.state.lock().unwrap();
+        let NodeState::LEADER { jobs } = &*state else {
+            return;
+        };
+        if jobs.is_empty() {
+            println!("No jobs");
+        }
+        for job in jobs.iter() {
+            println!("{} - {:?}, priority {}, share {}", job.problem.job.id, job.status, job.problem.job.priority, job.problem.job.share);
+            print!("{}", _node.progress_summary(job));
+        }
+        return;
+    }
+    let (Some(action), Some(given_id)) = (parts.get(1), parts.get(2)) else {
+        usage();
+        return;
+    };
+    let job_id = {
+        let state = _node.state.lock().unwrap();
+        let NodeState::LEADER { jobs } = &*state else {
+            return;
+        };
+        jobs.iter()
+            .map(|job| job.problem.job.id.clone())
+            .find(|id| id == given_id || id.rsplit('#').next() == Some(given_id))
+    };
+    let Some(job_id) = job_id else {
+        println!("Unknown job {}", given_id);
+        return;
+    };
+    match *action {
+        "pause" => _node.runtime.block_on(pause_job(_node, &job_id)),
+        "resume" => _node.runtime.block_on(resume_job(_node, &job_id)),
+        "cancel" => {
+            println!("Cancelling job {}", job_id);
+            _node.runtime.block_on(end_job(_node, &job_id));
+        }
+        _ => usage(),
+    }
+}
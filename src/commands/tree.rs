@@ -0,0 +1,229 @@
+use std::collections::HashSet;
+
+use crate::utils::TreeEntry;
+
+// Renders the entries collected by the `tree` command. Entries point to their parent,
+// the ones whose parent isn't among them (the leader) are the roots.
+
+pub fn render_ascii(entries: &[TreeEntry]) -> String {
+    let mut output = String::new();
+    for root in forest(entries) {
+        output.push_str(&describe(&root));
+        output.push('\n');
+        ascii_children(&root, "", &mut output);
+    }
+    output
+}
+
+fn ascii_children(branch: &Branch, prefix: &str, output: &mut String) {
+    for (i, child) in branch.children.iter().enumerate() {
+        let last = i + 1 == branch.children.len();
+        output.push_str(&format!("{}{} {}\n", prefix, if last { "└──" } else { "├──" }, describe(child)));
+        ascii_children(child, &format!("{}{}", prefix, if last { "    " } else { "│   " }), output);
+    }
+}
+
+fn describe(branch: &Branch) -> String {
+    let entry = branch.entry;
+    let mut line = format!("{} {}, power {} (subtree {}), {} solver thread{}",
+        entry.address, entry.state, entry.power, branch.power(), entry.threads, if entry.threads == 1 { "" } else { "s" });
+    match &entry.part {
+        Some((job_id, start, end, position)) => line.push_str(&format!(", job {} {}..={} at {} ({:.1}%)", job_id, start, end, position, percent(*start, *end, *position))),
+        None => line.push_str(", not searching"),
+    }
+    if entry.pending > 0 {
+        line.push_str(&format!(", {} parts pending", entry.pending));
+    }
+    line
+}
+
+pub fn render_json(entries: &[TreeEntry]) -> String {
+    let roots: Vec<String> = forest(entries).iter().map(json_entry).collect();
+    // a single tree unless some parent didn't answer
+    if roots.len() == 1 {
+        format!("{}\n", roots[0])
+    } else {
+        format!("[{}]\n", roots.join(","))
+    }
+}
+
+fn json_entry(branch: &Branch) -> String {
+    let entry = branch.entry;
+    let part = match &entry.part {
+        Some((job_id, start, end, position)) => format!("{{\"job_id\":{},\"start\":{},\"end\":{},\"position\":{},\"percent\":{:.1}}}",
+            quote(job_id), start, end, position, percent(*start, *end, *position)),
+        None => "null".to_string(),
+    };
+    let children: Vec<String> = branch.children.iter().map(json_entry).collect();
+    format!("{{\"address\":{},\"parent\":{},\"state\":{},\"power\":{},\"subtree_power\":{},\"threads\":{},\"part\":{},\"pending\":{},\"children\":[{}]}}",
+        quote(&entry.address), entry.parent.as_deref().map_or("null".to_string(), quote), quote(&entry.state),
+        entry.power, branch.power(), entry.threads, part, entry.pending, children.join(","))
+}
+
+pub fn render_dot(entries: &[TreeEntry]) -> String {
+    let mut output = String::from("digraph tree {\n    node [shape=box];\n");
+    let forest = forest(entries);
+    let mut branches: Vec<&Branch> = forest.iter().collect();
+    let mut edges = Vec::new();
+    while let Some(branch) = branches.pop() {
+        let entry = branch.entry;
+        let mut lines = vec![entry.address.clone(), format!("{}, power {} (subtree {})", entry.state, entry.power, branch.power())];
+        if let Some((job_id, start, end, position)) = &entry.part {
+            lines.push(format!("{} {}..={}", job_id, start, end));
+            lines.push(format!("{:.1}%", percent(*start, *end, *position)));
+        }
+        let label = lines.iter().map(|line| escape(line)).collect::<Vec<_>>().join("\\n");
+        let style = if entry.state == "UNREACHABLE" { ", style=dashed, color=red" } else { "" };
+        output.push_str(&format!("    {} [label=\"{}\"{}];\n", quote(&entry.address), label, style));
+        for child in branch.children.iter().rev() {
+            edges.push(format!("    {} -> {};\n", quote(&entry.address), quote(&child.entry.address)));
+            branches.push(child);
+        }
+    }
+    for edge in edges {
+        output.push_str(&edge);
+    }
+    output.push_str("}\n");
+    output
+}
+
+// an entry with the children under it
+struct Branch<'a> {
+    entry: &'a TreeEntry,
+    children: Vec<Branch<'a>>,
+}
+
+impl Branch<'_> {
+    fn power(&self) -> u32 {
+        self.entry.power + self.children.iter().map(|child| child.power()).sum::<u32>()
+    }
+}
+
+// Every address shows up once. A node that changed its parent during the collection can be reported twice,
+// or its entries can even form a cycle - whatever the roots don't reach starts a tree of its own.
+fn forest(entries: &[TreeEntry]) -> Vec<Branch<'_>> {
+    let mut visited = HashSet::new();
+    let mut forest = Vec::new();
+    for entry in roots(entries).into_iter().chain(entries.iter()) {
+        if visited.insert(entry.address.as_str()) {
+            forest.push(branch(entries, entry, &mut visited));
+        }
+    }
+    forest
+}
+
+fn branch<'a>(entries: &'a [TreeEntry], entry: &'a TreeEntry, visited: &mut HashSet<&'a str>) -> Branch<'a> {
+    let children: Vec<&TreeEntry> = entries.iter()
+        .filter(|e| e.parent.as_ref() == Some(&entry.address))
+        .filter(|e| visited.insert(e.address.as_str()))
+        .collect();
+    Branch {
+        entry,
+        children: children.into_iter().map(|child| branch(entries, child, visited)).collect(),
+    }
+}
+
+fn roots(entries: &[TreeEntry]) -> Vec<&TreeEntry> {
+    entries.iter()
+        .filter(|entry| entry.parent.as_ref().is_none_or(|parent| !entries.iter().any(|e| e.address == *parent)))
+        .collect()
+}
+
+// of the part the node is searching now
+fn percent(start: usize, end: usize, position: usize) -> f64 {
+    let total = end.saturating_sub(start) + 1;
+    position.saturating_sub(start).min(total) as f64 * 100.0 / total as f64
+}
+
+// JSON and DOT strings escape the same way
+fn quote(s: &str) -> String {
+    format!("\"{}\"", escape(s))
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(address: &str, parent: Option<&str>, power: u32) -> TreeEntry {
+        TreeEntry {
+            address: address.to_string(),
+            parent: parent.map(|parent| parent.to_string()),
+            state: if parent.is_some() { "WORKER" } else { "LEADER" }.to_string(),
+            power,
+            threads: 1,
+            part: None,
+            pending: 0,
+        }
+    }
+
+    fn tree() -> Vec<TreeEntry> {
+        vec![
+            entry("a", None, 1),
+            entry("b", Some("a"), 2),
+            entry("c", Some("b"), 3),
+            entry("d", Some("a"), 4),
+        ]
+    }
+
+    #[test]
+    fn ascii_shows_the_tree_with_subtree_power() {
+        let mut entries = tree();
+        entries[2].part = Some(("job".to_string(), 0, 99, 50));
+        let output = render_ascii(&entries);
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].starts_with("a LEADER, power 1 (subtree 10)"));
+        assert!(lines[1].starts_with("├── b WORKER, power 2 (subtree 5)"));
+        assert!(lines[2].starts_with("│   └── c WORKER, power 3 (subtree 3)"));
+        assert!(lines[2].ends_with("job job 0..=99 at 50 (50.0%)"));
+        assert!(lines[3].starts_with("└── d WORKER"));
+    }
+
+    #[test]
+    fn json_nests_the_children() {
+        let output = render_json(&tree());
+        assert!(output.starts_with("{\"address\":\"a\",\"parent\":null,"));
+        assert!(output.contains("\"subtree_power\":10,"));
+        assert!(output.contains("\"children\":[{\"address\":\"c\",\"parent\":\"b\","));
+        assert_eq!(output.matches("\"address\"").count(), 4);
+    }
+
+    #[test]
+    fn dot_has_an_edge_per_child() {
+        let mut entries = tree();
+        entries[3].state = "UNREACHABLE".to_string();
+        let output = render_dot(&entries);
+        for edge in ["\"a\" -> \"b\";", "\"b\" -> \"c\";", "\"a\" -> \"d\";"] {
+            assert!(output.contains(edge), "{}", edge);
+        }
+        assert_eq!(output.matches(" -> ").count(), 3);
+        assert!(output.contains("\"d\" [label=\"d\\nUNREACHABLE, power 4 (subtree 4)\", style=dashed, color=red];"));
+    }
+
+    #[test]
+    fn node_reported_twice_shows_once() {
+        // c moved from b to d while the tree was collected
+        let mut entries = tree();
+        entries.push(entry("c", Some("d"), 3));
+        let output = render_ascii(&entries);
+        assert_eq!(output.matches(" c WORKER").count(), 1);
+        assert!(output.starts_with("a LEADER, power 1 (subtree 10)"));
+        assert_eq!(render_json(&entries).matches("\"address\":\"c\"").count(), 1);
+        assert_eq!(render_dot(&entries).matches(" -> \"c\"").count(), 1);
+    }
+
+    #[test]
+    fn cycle_is_rendered_once() {
+        let entries = vec![entry("a", None, 1), entry("x", Some("y"), 1), entry("y", Some("x"), 1)];
+        let output = render_ascii(&entries);
+        assert_eq!(output.lines().count(), 3);
+        assert!(output.contains("x WORKER, power 1 (subtree 2)"));
+        assert!(output.contains("└── y WORKER"));
+        assert!(render_json(&entries).starts_with('['));
+        assert_eq!(render_dot(&entries).matches(" -> ").count(), 1);
+    }
+}
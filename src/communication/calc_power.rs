@@ -1,68 +1,236 @@
-use crate::utils::Node;
+use std::time::{Duration, Instant};
 
-use crate::messages::{CalculatePowerMessage, send_message, CalculateResponseMessage};
-use crate::utils::FriendType;
+use crate::utils::{Node, PowerCalculation};
 
-pub fn calculate_total_power(node: &Node) -> u32 {
-    println!("Calculating total power...");
-    let mut total_power = node.power; // my power is 1
-    let mut handles = vec![];
-    let mut friend_addresses = vec![];
+use crate::messages::{send_message, AckMessage, CalcRejectMessage, CalculatePowerMessage, CalculateResponseMessage, Message, WaveLostMessage};
+use crate::utils::{wave_beats, wave_leader, FriendType};
+use super::fan_out;
+
+// every level of the tree gets this much less time than its parent, so children answer before the parent's deadline
+const CALC_LEVEL_MARGIN_MS: u64 = 500;
+const CALC_MIN_BUDGET_MS: u64 = 500;
 
-    // Collect friend addresses (and their indices) before spawning threads
-    let friends = node.friends.lock().unwrap();
+/// Starts calculating the power of this node's subtree - the echo of the wave I joined.
+/// Friends that accept the CALC answer later with their own CALC_RESPONSE; once all of them answered
+/// (or the budget ran out) the total goes to `parent`, or is printed when this node is the leader.
+/// Friends that reject it (already in this or another wave) are not my children.
+pub async fn start_power_calculation(node: &Node, parent: Option<String>, budget_ms: u64) {
+    println!("Calculating total power...");
+    let leader = node.leader.lock().unwrap().clone().unwrap_or_else(|| node.address.clone());
+    let Some(wave) = node.wave.lock().unwrap().clone() else {
+        return;
+    };
+    let children_budget_ms = budget_ms.saturating_sub(CALC_LEVEL_MARGIN_MS).max(CALC_MIN_BUDGET_MS);
+    let deadline = Instant::now() + Duration::from_millis(children_budget_ms);
+    // Collect friend addresses before querying them
     let friends_addresses: Vec<String> = {
-        friends.iter().map(|f| f.address.clone()).collect()
+        let friends = node.friends.lock().unwrap();
+        friends.iter()
+            .map(|f| f.address.clone())
+            .filter(|address| Some(address) != parent.as_ref())
+            .collect()
     };
-    drop(friends);
-    for friend in friends_addresses.iter() {
-        let friend_address_clone = friend.to_string();
-        let node_clone = node.clone();
-        let handle = std::thread::spawn(move || {
-            println!("Querying power from friend: {}", friend_address_clone);
-            let message = CalculatePowerMessage {
-                from: node_clone.address.clone(),
-                to: friend_address_clone.clone(),
-            };
-            let response = send_message(&message, &node_clone);
-            if let Some(response_message) = response {
-                if let Some(calc_msg) = response_message.as_any().downcast_ref::<CalculateResponseMessage>() {
-                    println!("Received power {} from {}", calc_msg.power, friend_address_clone);
-                    return calc_msg.power;
+    // everyone is waited for up front, a fast child may answer before the fan-out below returns
+    *node.power_calculation.lock().unwrap() = Some(PowerCalculation {
+        parent: parent.clone(),
+        waiting: friends_addresses.iter().cloned().collect(),
+        total: node.power, // my power is 1
+        deadline,
+    });
+
+    let messages = friends_addresses.into_iter()
+        .map(|friend_address| {
+            println!("Querying power from friend: {}", friend_address);
+            CalculatePowerMessage {
+                from: node.address.clone(),
+                to: friend_address,
+                budget_ms: children_budget_ms,
+                leader: leader.clone(),
+                wave: wave.clone(),
+            }
+        })
+        .collect();
+    let results = fan_out(node, messages).await;
+
+    // ACK = friend joined and will answer later, anything else = not my child
+    let mut not_children = Vec::new();
+    let mut lost_to = None;
+    {
+        let mut calculation = node.power_calculation.lock().unwrap();
+        let Some(calculation) = calculation.as_mut() else {
+            return;
+        };
+        for (address, response) in results {
+            match response {
+                Some(response) if response.as_any().is::<AckMessage>() => {
+                    println!("Friend {} joined, waiting for its power", address);
+                    continue;
                 }
+                Some(response) => match response.as_any().downcast_ref::<CalcRejectMessage>() {
+                    Some(reject) if reject.joined.as_ref() == Some(&wave) => println!("Friend {} is already in the tree", address),
+                    Some(reject) => {
+                        println!("Friend {} is taken by wave {:?}", address, reject.joined);
+                        // it keeps its wave only if that one beats mine (or already runs jobs)
+                        if let Some(joined) = reject.joined.as_ref().filter(|joined| wave_beats(joined, &wave)) {
+                            lost_to = Some(joined.clone());
+                        }
+                    }
+                    None => println!("Friend {} is already taken", address),
+                },
+                None => println!("Failed to get power from {}", address),
             }
-            println!("Failed to get power from {}", friend_address_clone);
-            0
+            calculation.waiting.remove(&address);
+            not_children.push(address);
+        }
+    }
+    mark_not_children(node, &not_children);
+    if let Some(winner) = lost_to {
+        if wave_leader(&wave) == node.address {
+            lose_wave(node, &wave, &winner).await;
+        } else {
+            send_wave_lost(node, wave_leader(&wave), &wave, &winner).await;
+        }
+    }
+
+    // deadline for this level
+    let node_clone = node.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep_until(deadline.into()).await;
+        let expired = node_clone.power_calculation.lock().unwrap()
+            .as_ref()
+            .is_some_and(|calculation| calculation.deadline <= Instant::now());
+        if expired {
+            println!("Power calculation deadline reached, not waiting for the rest");
+            finish_power_calculation(&node_clone).await;
+        }
+    });
+    try_finish_power_calculation(node).await;
+}
+
+pub async fn handle_calculate_response(node: &Node, _message: Box<dyn Message>) {
+    let calc_msg = _message.as_any().downcast_ref::<CalculateResponseMessage>().unwrap();
+    println!("Received power {} from {}", calc_msg.power, calc_msg.from);
+    if node.wave.lock().unwrap().as_ref() != Some(&calc_msg.wave) {
+        println!("Power from {} is for wave {}, not mine, ignoring it", calc_msg.from, calc_msg.wave);
+        return;
+    }
+    if calc_msg.power > 0 {
+        let mut friends = node.friends.lock().unwrap();
+        let Some(friend) = friends.iter_mut().find(|f| f.address() == calc_msg.from) else {
+            println!("Power from {} who is not my friend, ignoring it", calc_msg.from);
+            return;
+        };
+        friend.friend_type = FriendType::Child;
+        friend.power = calc_msg.power;
+    }
+    {
+        let mut calculation = node.power_calculation.lock().unwrap();
+        let counted = calculation.as_mut().is_some_and(|calculation| {
+            let waited_for = calculation.waiting.remove(&calc_msg.from);
+            if waited_for {
+                calculation.total += calc_msg.power;
+            }
+            waited_for
         });
-        friend_addresses.push(friend.to_string());
-        handles.push(handle);
+        if !counted {
+            println!("Late power from {}, it is my child but not counted upwards", calc_msg.from);
+            return;
+        }
     }
+    try_finish_power_calculation(node).await;
+}
 
-    // Wait for all threads to finish and collect results first
-    let mut results = Vec::with_capacity(handles.len());
-    for handle in handles {
-        results.push(handle.join());
+async fn try_finish_power_calculation(node: &Node) {
+    let done = node.power_calculation.lock().unwrap()
+        .as_ref()
+        .is_some_and(|calculation| calculation.waiting.is_empty());
+    if done {
+        finish_power_calculation(node).await;
     }
+}
 
-    // Now lock and update friends after all threads are done
-    let mut friends = node.friends.lock().unwrap();
-    for (i, result) in results.into_iter().enumerate() {
-        let address = &friend_addresses[i];
-        if let Some(friend) = friends.iter_mut().find(|f| f.address() == *address) {
-            match result {
-                Ok(power) => {
-                    if power > 0 {
-                        total_power += power;
-                        friend.friend_type = FriendType::Child;
-                        friend.power = power;
-                    }
-                }
-                Err(_) => {
-                    println!("Thread panicked while querying friend: {}", friend.address());
-                    node.remove_friend(&friend.address());
-                }
-            }
+async fn finish_power_calculation(node: &Node) {
+    // taking it out makes sure the result is reported only once
+    let Some(calculation) = node.power_calculation.lock().unwrap().take() else {
+        return;
+    };
+    match calculation.parent {
+        Some(parent) => {
+            let Some(wave) = node.wave.lock().unwrap().clone() else {
+                return;
+            };
+            let response = CalculateResponseMessage {
+                from: node.address.clone(),
+                to: parent,
+                power: calculation.total,
+                wave,
+            };
+            println!("Sending power {} to parent", calculation.total);
+            send_message(&response, node).await;
         }
+        None => println!("Total calculated power: {}", calculation.total),
+    }
+}
+
+// a wave that beats mine took me over, the leader of my old wave has to step down
+// (or it is me, then the winner is told I did)
+pub fn switch_wave(node: &Node, previous: &str, wave: &str) {
+    println!("Wave {} of {} beats my wave {}, switching to it", wave, wave_leader(wave), previous);
+    let to = if wave_leader(previous) == node.address {
+        println!("Stepping down, {} leads the cluster now", wave_leader(wave));
+        wave_leader(wave)
+    } else {
+        wave_leader(previous)
+    };
+    let node_clone = node.clone();
+    let (to, loser, winner) = (to.to_string(), previous.to_string(), wave.to_string());
+    tokio::spawn(async move {
+        send_wave_lost(&node_clone, &to, &loser, &winner).await;
+    });
+}
+
+pub async fn handle_wave_lost_message(node: &Node, _message: Box<dyn Message>) {
+    let lost = _message.as_any().downcast_ref::<WaveLostMessage>().unwrap();
+    let wave = node.wave.lock().unwrap().clone();
+    if wave.as_ref() == Some(&lost.loser) {
+        lose_wave(node, &lost.loser, &lost.winner).await;
+    } else if wave.as_ref() == Some(&lost.winner) && node.is_leader() {
+        println!("Leader {} of wave {} stepped down, my wave takes over its nodes", lost.from, lost.loser);
+    }
+}
+
+// only the leader of the losing wave steps down, its nodes are taken over by the winning wave
+async fn lose_wave(node: &Node, loser: &str, winner: &str) {
+    if !node.is_leader() || node.wave.lock().unwrap().as_deref() != Some(loser) {
+        return;
+    }
+    if !node.jobs.lock().unwrap().is_empty() {
+        println!("My wave {} lost to wave {} of {}, but my tree already runs jobs, keeping it", loser, winner, wave_leader(winner));
+        return;
+    }
+    println!("My wave {} lost to wave {} of {}, stepping down", loser, winner, wave_leader(winner));
+    node.forget_tree();
+    send_wave_lost(node, wave_leader(winner), loser, winner).await;
+}
+
+async fn send_wave_lost(node: &Node, to: &str, loser: &str, winner: &str) {
+    if to == node.address {
+        return;
+    }
+    let message = WaveLostMessage {
+        from: node.address.clone(),
+        to: to.to_string(),
+        loser: loser.to_string(),
+        winner: winner.to_string(),
+    };
+    send_message(&message, node).await;
+}
+
+// friends outside my subtree - a role or power left over from an earlier tree would be counted otherwise
+fn mark_not_children(node: &Node, addresses: &[String]) {
+    let mut friends = node.friends.lock().unwrap();
+    for friend in friends.iter_mut().filter(|f| addresses.contains(&f.address) && f.is_child()) {
+        friend.friend_type = FriendType::NotSpecified;
+        friend.power = 0;
     }
-    total_power
-}
\ No newline at end of file
+}
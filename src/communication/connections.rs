@@ -0,0 +1,457 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::net::TcpStream;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::sync::{oneshot, OwnedRwLockWriteGuard, RwLock, Semaphore};
+use tokio::time::timeout;
+use tokio_util::sync::CancellationToken;
+
+use crate::messages::{parse_message, AckMessage, Message};
+use crate::utils::Node;
+use super::{handle_request, record_incoming, record_response};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(3);
+// how many handled message ids are remembered for deduplication
+const SEEN_MESSAGES_CAPACITY: usize = 4096;
+
+// Wire format (one line per frame):
+//   HELLO|<address>                       - first line from the connecting side, its listening address
+//   REQ|<request id>|<message id>|<msg>   - request, answered by exactly one RES with the same request id
+//   RES|<request id>|<msg>                - response to a request
+// The request id is per attempt, the message id stays the same when a message is retried.
+
+#[derive(Debug)]
+pub enum RequestError {
+    // could not connect or write - the friend looks dead
+    Unreachable(String),
+    // request was written but no response arrived in time
+    NoResponse,
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RequestError::Unreachable(e) => write!(f, "unreachable: {}", e),
+            RequestError::NoResponse => write!(f, "no response received"),
+        }
+    }
+}
+
+// message ids already handled, with the response once it is known
+#[derive(Default)]
+struct SeenMessages {
+    order: VecDeque<String>,
+    responses: HashMap<String, Option<String>>,
+}
+
+impl SeenMessages {
+    // returns None for a new message, Some(cached response) for a duplicate
+    fn check_and_insert(&mut self, message_id: &str) -> Option<Option<String>> {
+        if let Some(response) = self.responses.get(message_id) {
+            return Some(response.clone());
+        }
+        if self.order.len() >= SEEN_MESSAGES_CAPACITY
+            && let Some(oldest) = self.order.pop_front() {
+            self.responses.remove(&oldest);
+        }
+        self.order.push_back(message_id.to_string());
+        self.responses.insert(message_id.to_string(), None);
+        None
+    }
+
+    fn set_response(&mut self, message_id: &str, response: String) {
+        if let Some(entry) = self.responses.get_mut(message_id) {
+            *entry = Some(response);
+        }
+    }
+}
+
+// a request waiting for its response, with whether the request was a background one
+type Waiting = (oneshot::Sender<Box<dyn Message>>, bool);
+
+// what a duplicate is answered with - still being handled, only idempotent messages are retried and those are answered with ACK
+fn duplicate_response(cached: Option<String>, me: &str, sender: &str) -> String {
+    cached.unwrap_or_else(|| AckMessage {
+        from: me.to_string(),
+        to: sender.to_string(),
+    }.serialize())
+}
+
+/// One long-lived stream to a friend, shared by all requests in both directions.
+struct Connection {
+    writer: tokio::sync::Mutex<OwnedWriteHalf>,
+    pending: Mutex<HashMap<u64, Waiting>>,
+    alive: AtomicBool,
+    closed: CancellationToken,
+}
+
+impl Connection {
+    fn new(writer: OwnedWriteHalf) -> Self {
+        Connection {
+            writer: tokio::sync::Mutex::new(writer),
+            pending: Mutex::new(HashMap::new()),
+            alive: AtomicBool::new(true),
+            closed: CancellationToken::new(),
+        }
+    }
+
+    async fn write_line(&self, line: &str) -> std::io::Result<()> {
+        let mut writer = self.writer.lock().await;
+        writer.write_all(format!("{}\n", line).as_bytes()).await
+    }
+
+    async fn close(&self) {
+        self.alive.store(false, Ordering::SeqCst);
+        self.closed.cancel();
+        // dropping the senders wakes up everyone still waiting for a response
+        self.pending.lock().unwrap().clear();
+        let _ = self.writer.lock().await.shutdown().await;
+    }
+}
+
+/// A request written on its connection, `ConnectionManager::wait_response` waits for the response.
+pub struct SentRequest {
+    connection: Arc<Connection>,
+    id: u64,
+    response: oneshot::Receiver<Box<dyn Message>>,
+}
+
+/// Keeps one connection per friend address and multiplexes requests over it.
+/// Outbound requests and inbound handlers are limited to `max_in_flight` each.
+#[derive(Clone)]
+pub struct ConnectionManager {
+    connections: Arc<Mutex<HashMap<String, Arc<Connection>>>>,
+    next_request_id: Arc<AtomicU64>,
+    next_message_id: Arc<AtomicU64>,
+    seen: Arc<Mutex<SeenMessages>>,
+    outbound: Arc<Semaphore>,
+    inbound: Arc<Semaphore>,
+    max_in_flight: u32,
+    // every request and response is written under the read lock, a snapshot takes the write lock for its markers
+    sends: Arc<RwLock<()>>,
+    // handlers running right now, a snapshot records the state once they are done
+    active_handlers: Arc<AtomicUsize>,
+}
+
+impl fmt::Debug for ConnectionManager {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let connections = self.connections.lock().unwrap();
+        f.debug_list().entries(connections.keys()).finish()
+    }
+}
+
+impl ConnectionManager {
+    pub fn new(max_in_flight: usize) -> Self {
+        ConnectionManager {
+            connections: Arc::new(Mutex::new(HashMap::new())),
+            next_request_id: Arc::new(AtomicU64::new(0)),
+            next_message_id: Arc::new(AtomicU64::new(0)),
+            seen: Arc::new(Mutex::new(SeenMessages::default())),
+            outbound: Arc::new(Semaphore::new(max_in_flight)),
+            inbound: Arc::new(Semaphore::new(max_in_flight)),
+            max_in_flight: max_in_flight as u32,
+            sends: Arc::new(RwLock::new(())),
+            active_handlers: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// On shutdown - waits until my requests in flight are answered (or time out) and my handlers are done,
+    /// then closes every connection.
+    pub async fn close_all(&self) {
+        let drained = async {
+            let _outbound = self.outbound.acquire_many(self.max_in_flight).await;
+            let _inbound = self.inbound.acquire_many(self.max_in_flight).await;
+        };
+        if timeout(RESPONSE_TIMEOUT * 2, drained).await.is_err() {
+            println!("Some requests are still in flight, closing anyway");
+        }
+        let connections: Vec<Arc<Connection>> = self.connections.lock().unwrap().drain().map(|(_, connection)| connection).collect();
+        for connection in connections {
+            connection.close().await;
+        }
+    }
+
+    /// New globally unique message id, kept by all retries of one message.
+    /// The incarnation keeps the ids of a restarted node apart from the ones its peers remember from the last run.
+    pub fn new_message_id(&self, node: &Node) -> String {
+        format!("{}/{}/{}", node.address, node.incarnation, self.next_message_id.fetch_add(1, Ordering::SeqCst))
+    }
+
+    /// Sends a request to the given address and waits for its response.
+    /// Reconnects once if the cached connection turns out to be dead.
+    pub async fn request(&self, node: &Node, address: &str, message_id: &str, message: &dyn Message) -> Result<Box<dyn Message>, RequestError> {
+        let _permit = self.outbound.acquire().await.map_err(|_| RequestError::Unreachable("connection manager closed".to_string()))?;
+        let sent = {
+            let _sends = self.sends.read().await;
+            self.write_request(node, address, message_id, message).await?
+        };
+        self.wait_response(sent).await
+    }
+
+    /// Holds every other request and response until the guard is dropped - a snapshot writes its markers meanwhile
+    /// with `request_holding_sends`, so nothing I send after my state is recorded gets ahead of them.
+    pub async fn hold_sends(&self) -> OwnedRwLockWriteGuard<()> {
+        self.sends.clone().write_owned().await
+    }
+
+    /// Writes a request while the sends are held by `hold_sends`, the response is waited for with `wait_response`.
+    pub async fn request_holding_sends(&self, node: &Node, address: &str, message_id: &str, message: &dyn Message) -> Result<SentRequest, RequestError> {
+        self.write_request(node, address, message_id, message).await
+    }
+
+    pub async fn wait_response(&self, sent: SentRequest) -> Result<Box<dyn Message>, RequestError> {
+        match timeout(RESPONSE_TIMEOUT, sent.response).await {
+            Ok(Ok(response)) => Ok(response),
+            _ => {
+                sent.connection.pending.lock().unwrap().remove(&sent.id);
+                Err(RequestError::NoResponse)
+            }
+        }
+    }
+
+    /// Handlers of incoming requests that are running right now.
+    pub fn active_handlers(&self) -> usize {
+        self.active_handlers.load(Ordering::SeqCst)
+    }
+
+    async fn write_request(&self, node: &Node, address: &str, message_id: &str, message: &dyn Message) -> Result<SentRequest, RequestError> {
+        let id = self.next_request_id.fetch_add(1, Ordering::SeqCst);
+        let line = format!("REQ|{}|{}|{}", id, message_id, message.serialize());
+        let (tx, rx) = oneshot::channel();
+
+        let connection = self.get_or_connect(node, address).await.map_err(|e| RequestError::Unreachable(format!("failed to connect: {}", e)))?;
+        connection.pending.lock().unwrap().insert(id, (tx, message.is_background()));
+        let connection = match connection.write_line(&line).await {
+            Ok(()) => connection,
+            Err(e) => {
+                println!("Connection to {} dropped ({}), reconnecting...", address, e);
+                let tx = connection.pending.lock().unwrap().remove(&id);
+                self.drop_connection(address, &connection).await;
+                let connection = self.get_or_connect(node, address).await.map_err(|e| RequestError::Unreachable(format!("failed to reconnect: {}", e)))?;
+                if let Some(tx) = tx {
+                    connection.pending.lock().unwrap().insert(id, tx);
+                }
+                connection.write_line(&line).await.map_err(|e| RequestError::Unreachable(format!("failed to write: {}", e)))?;
+                connection
+            }
+        };
+        Ok(SentRequest { connection, id, response: rx })
+    }
+
+    /// Serves a connection accepted by the listener.
+    pub async fn accept(&self, node: &Node, stream: TcpStream) {
+        let (read_half, write_half) = stream.into_split();
+        let mut lines = BufReader::new(read_half).lines();
+        let address = match lines.next_line().await {
+            Ok(Some(hello)) => match hello.split_once('|') {
+                Some(("HELLO", address)) => address.to_string(),
+                _ => {
+                    eprintln!("Unexpected first line on connection: {:?}", hello);
+                    return;
+                }
+            },
+            _ => {
+                eprintln!("Failed to read message from stream");
+                return;
+            }
+        };
+        println!("Accepted connection from {}", address);
+
+        let connection = Arc::new(Connection::new(write_half));
+        // reuse the incoming stream for our own requests unless we already have one
+        {
+            let mut connections = self.connections.lock().unwrap();
+            let reusable = connections.get(&address).is_some_and(|c| c.alive.load(Ordering::SeqCst));
+            if !reusable {
+                connections.insert(address.clone(), connection.clone());
+            }
+        }
+        self.serve(node, address, connection, lines).await;
+    }
+
+    async fn get_or_connect(&self, node: &Node, address: &str) -> std::io::Result<Arc<Connection>> {
+        if let Some(connection) = self.connections.lock().unwrap().get(address)
+            && connection.alive.load(Ordering::SeqCst) {
+            return Ok(connection.clone());
+        }
+
+        let stream = timeout(CONNECT_TIMEOUT, TcpStream::connect(address)).await
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "connect timed out"))??;
+        let (read_half, mut write_half) = stream.into_split();
+        write_half.write_all(format!("HELLO|{}\n", node.address).as_bytes()).await?;
+        let connection = Arc::new(Connection::new(write_half));
+        println!("Opened connection to {}", address);
+
+        self.connections.lock().unwrap().insert(address.to_string(), connection.clone());
+        let manager = self.clone();
+        let node_clone = node.clone();
+        let address = address.to_string();
+        let connection_clone = connection.clone();
+        tokio::spawn(async move {
+            let lines = BufReader::new(read_half).lines();
+            manager.serve(&node_clone, address, connection_clone, lines).await;
+        });
+        Ok(connection)
+    }
+
+    async fn drop_connection(&self, address: &str, connection: &Arc<Connection>) {
+        {
+            let mut connections = self.connections.lock().unwrap();
+            if connections.get(address).is_some_and(|c| Arc::ptr_eq(c, connection)) {
+                connections.remove(address);
+            }
+        }
+        connection.close().await;
+    }
+
+    // reads frames until the stream closes, every request is handled in its own task
+    async fn serve(&self, node: &Node, address: String, connection: Arc<Connection>, mut lines: Lines<BufReader<OwnedReadHalf>>) {
+        loop {
+            // kept open during shutdown, responses to my last requests still come on it
+            let line = tokio::select! {
+                _ = connection.closed.cancelled() => break,
+                line = lines.next_line() => match line {
+                    Ok(Some(l)) => l,
+                    _ => break,
+                },
+            };
+            let mut frame = line.splitn(3, '|');
+            let (kind, id, payload) = match (frame.next(), frame.next().and_then(|id| id.parse::<u64>().ok()), frame.next()) {
+                (Some(kind), Some(id), Some(payload)) => (kind, id, payload),
+                _ => {
+                    eprintln!("Malformed frame from {}: {}", address, line);
+                    continue;
+                }
+            };
+            match kind {
+                "REQ" => {
+                    if !node.is_communicating() {
+                        println!("Communication is off, ignoring request from {}", address);
+                        continue;
+                    }
+                    if node.shutdown.is_cancelled() {
+                        println!("Shutting down, ignoring request from {}", address);
+                        continue;
+                    }
+                    let Some((message_id, payload)) = payload.split_once('|') else {
+                        eprintln!("Request without message id from {}: {}", address, line);
+                        continue;
+                    };
+                    let Some(message) = parse_message(payload) else {
+                        eprintln!("Failed to parse incoming message: {}", payload);
+                        continue;
+                    };
+                    if !message.is_background() {
+                        println!("Received message {}: {:?}", message_id, payload);
+                    }
+                    // a retried message is answered but not processed again
+                    let duplicate = self.seen.lock().unwrap().check_and_insert(message_id);
+                    if let Some(cached) = duplicate {
+                        println!("Duplicate message {} from {}, not processing again", message_id, address);
+                        let response = duplicate_response(cached, &node.address, message.from());
+                        let _sends = self.sends.read().await;
+                        let _ = connection.write_line(&format!("RES|{}|{}", id, response)).await;
+                        continue;
+                    }
+                    // in the order of the connection, a snapshot needs to know what came before its marker -
+                    // what comes while it records my state is handled after that
+                    let held = record_incoming(node, message_id, message.as_ref());
+                    if held.is_none() {
+                        self.active_handlers.fetch_add(1, Ordering::SeqCst);
+                    }
+                    let node_clone = node.clone();
+                    let connection_clone = connection.clone();
+                    let inbound = self.inbound.clone();
+                    let seen = self.seen.clone();
+                    let sends = self.sends.clone();
+                    let active_handlers = self.active_handlers.clone();
+                    let message_id = message_id.to_string();
+                    tokio::spawn(async move {
+                        if let Some(held) = held {
+                            held.cancelled().await;
+                            active_handlers.fetch_add(1, Ordering::SeqCst);
+                        }
+                        let Ok(_permit) = inbound.acquire_owned().await else {
+                            active_handlers.fetch_sub(1, Ordering::SeqCst);
+                            return;
+                        };
+                        let response = handle_request(node_clone, message).await.serialize();
+                        active_handlers.fetch_sub(1, Ordering::SeqCst);
+                        seen.lock().unwrap().set_response(&message_id, response.clone());
+                        let _sends = sends.read().await;
+                        let _ = connection_clone.write_line(&format!("RES|{}|{}", id, response)).await;
+                    });
+                }
+                "RES" => {
+                    let waiting = connection.pending.lock().unwrap().remove(&id);
+                    match (waiting, parse_message(payload)) {
+                        (Some((tx, background)), Some(response)) => {
+                            if !background {
+                                record_response(node, &address, id, response.as_ref());
+                            }
+                            let _ = tx.send(response);
+                        }
+                        (Some(_), None) => eprintln!("Failed to parse response from {}: {}", address, payload),
+                        (None, _) => println!("Late response {} from {} dropped", id, address),
+                    }
+                }
+                _ => eprintln!("Unknown frame kind from {}: {}", address, kind),
+            }
+        }
+        println!("Connection to {} closed", address);
+        self.drop_connection(&address, &connection).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn new_message_is_handled() {
+        let mut seen = SeenMessages::default();
+        assert_eq!(seen.check_and_insert("a/1/0"), None);
+        assert_eq!(seen.check_and_insert("a/1/1"), None);
+    }
+
+    #[test]
+    fn duplicate_in_progress_gets_an_ack() {
+        let mut seen = SeenMessages::default();
+        seen.check_and_insert("a/1/0");
+        let cached = seen.check_and_insert("a/1/0").expect("duplicate");
+        assert_eq!(cached, None);
+        assert_eq!(duplicate_response(cached, "b", "a"), "ACK|b|a");
+    }
+
+    #[test]
+    fn duplicate_of_a_handled_message_gets_the_same_response() {
+        let mut seen = SeenMessages::default();
+        seen.check_and_insert("a/1/0");
+        seen.set_response("a/1/0", "CALC_RESPONSE|b|a|3|w".to_string());
+        let cached = seen.check_and_insert("a/1/0").expect("duplicate");
+        assert_eq!(duplicate_response(cached, "b", "a"), "CALC_RESPONSE|b|a|3|w");
+        // an id that was never seen gets no response stored
+        seen.set_response("a/1/1", "ACK|b|a".to_string());
+        assert_eq!(seen.check_and_insert("a/1/1"), None);
+    }
+
+    #[test]
+    fn oldest_ids_are_forgotten_at_capacity() {
+        let mut seen = SeenMessages::default();
+        for i in 0..SEEN_MESSAGES_CAPACITY {
+            assert_eq!(seen.check_and_insert(&format!("a/1/{}", i)), None);
+        }
+        assert!(seen.check_and_insert("a/1/1").is_some());
+        // one more pushes out the oldest, everything after it is still known
+        assert_eq!(seen.check_and_insert("a/1/new"), None);
+        assert_eq!(seen.responses.len(), SEEN_MESSAGES_CAPACITY);
+        assert!(seen.check_and_insert(&format!("a/1/{}", SEEN_MESSAGES_CAPACITY - 1)).is_some());
+        assert_eq!(seen.check_and_insert("a/1/0"), None);
+    }
+}
@@ -0,0 +1,158 @@
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::time::Duration;
+
+use sha2::{Digest, Sha256};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::UdpSocket;
+use tokio::time::MissedTickBehavior;
+
+use crate::utils::Node;
+
+// Datagrams of the discovery, plain text like the messages:
+//   DISCOVER|cluster|address|tag         multicast by every node every discovery interval
+//   DISCOVER_REPLY|cluster|address|tag   sent back to the announcing node
+// tag is sha256 of key|cluster|address, "-" without a key
+
+/// LAN discovery. I announce myself on the multicast group, nodes of the same cluster (with the same key)
+/// answer and both sides add each other as friends. Gossip then spreads the rest of the cluster.
+pub async fn run_discovery(node: Node) {
+    let Some(group) = node.config.discovery_group else {
+        return;
+    };
+    let socket = match multicast_socket(group, node.config.discovery_interface) {
+        Ok(socket) => socket,
+        Err(e) => {
+            eprintln!("Discovery on {} failed: {}", group, e);
+            return;
+        }
+    };
+    println!("Discovering cluster {} on {}", node.config.cluster_name, group);
+    let mut interval = tokio::time::interval(Duration::from_millis(node.config.discovery_interval_ms));
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut buffer = [0u8; 1024];
+    loop {
+        tokio::select! {
+            _ = node.shutdown.cancelled() => break,
+            _ = interval.tick() => {
+                let announce = datagram(&node, "DISCOVER");
+                if let Err(e) = socket.send_to(announce.as_bytes(), SocketAddr::V4(group)).await {
+                    eprintln!("Discovery announce failed: {}", e);
+                }
+            }
+            received = socket.recv_from(&mut buffer) => {
+                let Ok((length, source)) = received else {
+                    continue;
+                };
+                let text = String::from_utf8_lossy(&buffer[..length]).to_string();
+                let Some((kind, address)) = parse_datagram(&node, &text) else {
+                    continue;
+                };
+                if !node.is_friend(&address) && node.friends.lock().unwrap().len() < node.config.max_friends {
+                    println!("Discovered {}", address);
+                    node.add_friend(address);
+                }
+                if kind == "DISCOVER" {
+                    let reply = datagram(&node, "DISCOVER_REPLY");
+                    let _ = socket.send_to(reply.as_bytes(), source).await;
+                }
+            }
+        }
+    }
+}
+
+// several nodes on one machine share the port, the group is joined on the given interface
+fn multicast_socket(group: SocketAddrV4, interface: Ipv4Addr) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, group.port())).into())?;
+    socket.join_multicast_v4(group.ip(), &interface)?;
+    socket.set_multicast_if_v4(&interface)?;
+    socket.set_multicast_loop_v4(true)?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into())
+}
+
+fn datagram(node: &Node, kind: &str) -> String {
+    format!("{}|{}|{}|{}", kind, node.config.cluster_name, node.address, tag(node, &node.address))
+}
+
+// (kind, address) of another node of my cluster, None for anything else
+fn parse_datagram(node: &Node, text: &str) -> Option<(String, String)> {
+    let parts: Vec<&str> = text.trim().split('|').collect();
+    let [kind, cluster, address, tag_received] = parts[..] else {
+        return None;
+    };
+    if !matches!(kind, "DISCOVER" | "DISCOVER_REPLY") || cluster != node.config.cluster_name || address == node.address {
+        return None;
+    }
+    // wrong or missing key, it comes again with every announce so it isn't logged
+    if tag_received != tag(node, address) {
+        return None;
+    }
+    Some((kind.to_string(), address.to_string()))
+}
+
+fn tag(node: &Node, address: &str) -> String {
+    let Some(key) = node.config.cluster_key.as_ref() else {
+        return "-".to_string();
+    };
+    let mut hasher = Sha256::new();
+    hasher.update(format!("{}|{}|{}", key, node.config.cluster_name, address).as_bytes());
+    format!("{:x}", hasher.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+    use crate::utils::NodeConfig;
+
+    fn discovering_node(port: u16, group: SocketAddrV4, key: Option<&str>) -> Node {
+        let config = NodeConfig {
+            discovery_group: Some(group),
+            discovery_interface: Ipv4Addr::LOCALHOST,
+            discovery_interval_ms: 100,
+            cluster_key: key.map(|key| key.to_string()),
+            ..Default::default()
+        };
+        Node::new(format!("127.0.0.1:{}", port), Vec::new(), config)
+    }
+
+    // over loopback multicast, no --friends
+    #[tokio::test(flavor = "multi_thread")]
+    async fn nodes_of_one_cluster_find_each_other() {
+        let group = SocketAddrV4::new(Ipv4Addr::new(239, 255, 77, 78), 47781);
+        let a = discovering_node(47001, group, Some("secret"));
+        let b = discovering_node(47002, group, Some("secret"));
+        let stranger = discovering_node(47003, group, Some("other"));
+        for node in [&a, &b, &stranger] {
+            tokio::spawn(run_discovery(node.clone()));
+        }
+        let started = Instant::now();
+        while !(a.is_friend(&b.address) && b.is_friend(&a.address)) && started.elapsed() < Duration::from_secs(5) {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        assert!(a.is_friend(&b.address) && b.is_friend(&a.address));
+        // a wrong key is ignored on both sides
+        assert!(!a.is_friend(&stranger.address) && !stranger.is_friend(&a.address));
+        for node in [&a, &b, &stranger] {
+            node.shutdown.cancel();
+        }
+    }
+
+    #[tokio::test]
+    async fn announcements_of_other_clusters_are_ignored() {
+        let group = SocketAddrV4::new(Ipv4Addr::new(239, 255, 77, 78), 47782);
+        let node = discovering_node(47004, group, None);
+        assert_eq!(
+            parse_datagram(&node, "DISCOVER|dsva|127.0.0.1:47005|-"),
+            Some(("DISCOVER".to_string(), "127.0.0.1:47005".to_string()))
+        );
+        assert_eq!(parse_datagram(&node, "DISCOVER|other|127.0.0.1:47005|-"), None);
+        // my own announcement comes back by the multicast loop
+        assert_eq!(parse_datagram(&node, "DISCOVER|dsva|127.0.0.1:47004|-"), None);
+        assert_eq!(parse_datagram(&node, "HELLO|dsva|127.0.0.1:47005|-"), None);
+    }
+}
@@ -0,0 +1,255 @@
+use std::collections::HashSet;
+use std::sync::atomic::Ordering;
+
+use crate::messages::{parse_message, send_message, AckMessage, ElectEchoMessage, ElectMessage, Message};
+use crate::problem::{Job, PartOfAProblem, PartOfAProblemState, update_state_of_parts};
+use crate::utils::{Election, FriendHealth, FriendType, Node};
+use super::{fan_out, handle_request, request_work, submit_job};
+use super::termination::{adopt_holder, check_all, engage_to, forget_all};
+
+/// Starts a leader election after the leader died (echo algorithm with extinction).
+/// Every node floods the strongest wave it has seen - later round first, then higher candidate address - and
+/// weaker waves die out. The echoes of the winning wave build the new tree and bring the candidate what
+/// every node searched and still holds, so the new leader can continue the job.
+pub async fn start_election(node: &Node) {
+    let round = node.election.lock().unwrap().as_ref().map_or(0, |election| election.round) + 1;
+    println!("Starting leader election, round {}", round);
+    let wave = node.new_wave_id();
+    let neighbours = adopt_wave(node, round, node.address.clone(), &wave, None);
+    flood_wave(node, round, node.address.clone(), wave, neighbours).await;
+}
+
+// answers right away: ACK = I joined the wave and will echo later, ELECT_ECHO not joined = don't wait for me
+pub async fn handle_elect_message(node: &Node, _message: Box<dyn Message>) -> Box<dyn Message> {
+    let elect = _message.as_any().downcast_ref::<ElectMessage>().unwrap();
+    let stronger = is_stronger(elect.round, &elect.candidate, node.election.lock().unwrap().as_ref());
+    // a living leader keeps its tree
+    if !stronger || node.is_leader() {
+        return Box::new(ElectEchoMessage {
+            from: node.address.clone(),
+            to: elect.from.clone(),
+            round: elect.round,
+            candidate: elect.candidate.clone(),
+            joined: false,
+            power: 0,
+            parts: Vec::new(),
+        });
+    }
+    println!("Joining election of {} (round {}) through {}", elect.candidate, elect.round, elect.from);
+    // adopted before answering, so a second copy of the wave is already recognized
+    let neighbours = adopt_wave(node, elect.round, elect.candidate.clone(), &elect.wave, Some(elect.from.clone()));
+    let node_clone = node.clone();
+    let (round, candidate, wave) = (elect.round, elect.candidate.clone(), elect.wave.clone());
+    tokio::spawn(async move {
+        flood_wave(&node_clone, round, candidate, wave, neighbours).await;
+    });
+    Box::new(AckMessage {
+        from: node.address.clone(),
+        to: elect.from.clone(),
+    })
+}
+
+pub async fn handle_elect_echo(node: &Node, _message: Box<dyn Message>) {
+    let echo = _message.as_any().downcast_ref::<ElectEchoMessage>().unwrap();
+    {
+        let mut election = node.election.lock().unwrap();
+        let Some(election) = election.as_mut()
+            .filter(|election| election.round == echo.round && election.candidate == echo.candidate) else {
+            println!("Echo from {} for an old wave, ignoring", echo.from);
+            return;
+        };
+        if !election.waiting.remove(&echo.from) {
+            return;
+        }
+        println!("{} joined with power {}", echo.from, echo.power);
+        election.power += echo.power;
+        election.parts.extend(echo.parts.iter().cloned());
+    }
+    // the child keeps searching what it holds, if it dies the ranges are given back as usual
+    {
+        let mut friends = node.friends.lock().unwrap();
+        if let Some(friend) = friends.iter_mut().find(|f| f.address() == echo.from) {
+            friend.friend_type = FriendType::Child;
+            friend.power = echo.power;
+            friend.assigned_parts = echo.parts.iter()
+                .filter(|part| part.state == PartOfAProblemState::Distributed)
+                .cloned()
+                .collect();
+        }
+    }
+    adopt_holder(node, &echo.from, &echo.parts);
+    try_finish_election(node).await;
+}
+
+/// A friend died or left during the election, it won't echo.
+pub async fn forget_in_election(node: &Node, address: &str) {
+    let removed = node.election.lock().unwrap()
+        .as_mut()
+        .is_some_and(|election| !election.finished && election.waiting.remove(address));
+    if removed {
+        try_finish_election(node).await;
+    }
+}
+
+fn is_stronger(round: u64, candidate: &str, than: Option<&Election>) -> bool {
+    match than {
+        Some(election) => (round, candidate) > (election.round, election.candidate.as_str()),
+        None => true,
+    }
+}
+
+// the old tree is gone, it is rebuilt by the echoes of this wave
+fn adopt_wave(node: &Node, round: u64, candidate: String, wave: &str, parent: Option<String>) -> Vec<String> {
+    let neighbours: Vec<String> = {
+        let mut friends = node.friends.lock().unwrap();
+        for friend in friends.iter_mut() {
+            friend.friend_type = if Some(&friend.address) == parent.as_ref() { FriendType::Parent } else { FriendType::NotSpecified };
+            friend.power = 0;
+            friend.assigned_parts.clear();
+        }
+        friends.iter()
+            .filter(|f| Some(&f.address) != parent.as_ref() && f.health != FriendHealth::Dead)
+            .map(|f| f.address.clone())
+            .collect()
+    };
+    if node.is_idle() {
+        node.set_state_worker();
+    }
+    // the termination detection starts over on the new tree, the echoes count what everyone holds
+    forget_all(node);
+    *node.wave.lock().unwrap() = Some(wave.to_string());
+    *node.leader.lock().unwrap() = Some(candidate.clone());
+    *node.election.lock().unwrap() = Some(Election {
+        round,
+        candidate,
+        parent,
+        waiting: neighbours.iter().cloned().collect::<HashSet<_>>(),
+        power: node.power,
+        parts: Vec::new(),
+        finished: false,
+    });
+    neighbours
+}
+
+async fn flood_wave(node: &Node, round: u64, candidate: String, wave: String, neighbours: Vec<String>) {
+    let messages = neighbours.into_iter()
+        .map(|address| ElectMessage {
+            from: node.address.clone(),
+            to: address,
+            round,
+            candidate: candidate.clone(),
+            wave: wave.clone(),
+        })
+        .collect();
+    let results = fan_out(node, messages).await;
+    {
+        let mut election = node.election.lock().unwrap();
+        // a stronger wave took over meanwhile
+        let Some(election) = election.as_mut()
+            .filter(|election| election.round == round && election.candidate == candidate) else {
+            return;
+        };
+        for (address, response) in results {
+            match response {
+                Some(response) if response.as_any().is::<AckMessage>() => {}
+                _ => {
+                    election.waiting.remove(&address);
+                }
+            }
+        }
+    }
+    try_finish_election(node).await;
+}
+
+async fn try_finish_election(node: &Node) {
+    let finished = {
+        let mut election = node.election.lock().unwrap();
+        match election.as_mut() {
+            Some(election) if !election.finished && election.waiting.is_empty() => {
+                election.finished = true;
+                let mut parts = election.parts.clone();
+                parts.extend(own_parts(node));
+                Some((election.round, election.candidate.clone(), election.parent.clone(), election.power, parts))
+            }
+            _ => None,
+        }
+    };
+    let Some((round, candidate, parent, power, parts)) = finished else {
+        return;
+    };
+    let held = parts.clone();
+    match parent.clone() {
+        Some(parent) => {
+            println!("Election of {} done in my subtree, power {}", candidate, power);
+            let echo = ElectEchoMessage {
+                from: node.address.clone(),
+                to: parent,
+                round,
+                candidate,
+                joined: true,
+                power,
+                parts,
+            };
+            send_message(&echo, node).await;
+        }
+        None => become_leader(node, round, power, parts).await,
+    }
+    // the parent knows what I hold only after the echo, my signals can't come before it
+    engage_to(node, parent.as_deref(), &held);
+    // messages from the time without a parent can go up now, handled as if they just arrived
+    let undelivered: Vec<_> = node.undelivered.lock().unwrap().drain(..).collect();
+    for message in undelivered.iter().filter_map(|message| parse_message(message)) {
+        handle_request(node.clone(), message).await;
+    }
+    // whatever was finished during the election is signaled now, after its results went up
+    check_all(node).await;
+    // nodes left without work ask the new leader
+    if !node.solver_running.load(Ordering::SeqCst) && !node.jobs.lock().unwrap().is_empty() {
+        request_work(node).await;
+    }
+}
+
+// what I searched and what I still hold, the current part is split at the solver's position
+fn own_parts(node: &Node) -> Vec<PartOfAProblem> {
+    let mut parts = Vec::new();
+    if let Some(part) = node.solving_part_of_a_problem.lock().unwrap().clone() {
+        if part.state == PartOfAProblemState::SearchedAndNotFound {
+            parts.push(part);
+        } else {
+            let position = node.current_position.load(Ordering::SeqCst).clamp(part.start, part.end);
+            if position > part.start {
+                parts.push(PartOfAProblem { end: position - 1, state: PartOfAProblemState::SearchedAndNotFound, ..part.clone() });
+            }
+            parts.push(PartOfAProblem { start: position, state: PartOfAProblemState::Distributed, ..part });
+        }
+    }
+    for part in node.pending_parts.lock().unwrap().iter() {
+        parts.push(PartOfAProblem { state: PartOfAProblemState::Distributed, ..part.clone() });
+    }
+    // a standby also knows what the whole cluster searched before the leader died
+    if let Some(replica) = &*node.replica.lock().unwrap() {
+        parts.extend(replica.parts.iter().filter(|part| part.state == PartOfAProblemState::SearchedAndNotFound).cloned());
+    }
+    parts
+}
+
+async fn become_leader(node: &Node, round: u64, power: u32, reported: Vec<PartOfAProblem>) {
+    println!("I am the new leader (round {}), total power: {}", round, power);
+    node.set_state_leader();
+    let mut jobs: Vec<Job> = node.jobs.lock().unwrap().values().cloned().collect();
+    if jobs.is_empty() {
+        println!("No job to continue");
+        return;
+    }
+    jobs.sort_by_key(|job| std::cmp::Reverse(job.priority));
+    for job in jobs {
+        // anything nobody reported is searched again
+        let mut parts = vec![job.whole_part()];
+        for part in reported.iter().filter(|part| part.job_id == job.id) {
+            update_state_of_parts(&mut parts, part);
+        }
+        println!("Recovered parts of job {}: {:?}", job.id, parts);
+        // idle nodes ask for chunks after the election, held ones are finished by their holders
+        submit_job(node, job, parts).await;
+    }
+}
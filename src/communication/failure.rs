@@ -0,0 +1,47 @@
+use crate::messages::SolveResponseMessage;
+use crate::utils::{FriendType, Node};
+use super::{forget_in_election, handle_solve_response_message, start_election};
+use super::join::{rejoin_tree, report_power};
+use super::termination::forget_holder;
+
+/// Recovery path for a friend declared dead by heartbeats.
+/// The friend is taken from my friends first, so a second call for it (heartbeats and a LEAVE at once) does nothing.
+pub async fn handle_dead_friend(node: &Node, address: &str) {
+    let Some(friend) = node.take_friend(address) else {
+        return;
+    };
+    match friend.friend_type {
+        FriendType::Child => {
+            // everything the child's subtree did not report yet goes back up as not distributed,
+            // the leader splits it again among the nodes that are left
+            for part in friend.assigned_parts {
+                println!("Dead child {} was searching {:?}, giving it back", address, part);
+                let lost = SolveResponseMessage {
+                    from: node.address.clone(),
+                    to: node.address.clone(),
+                    job_id: part.job_id.clone(),
+                    start: part.start,
+                    end: part.end,
+                    solution: None,
+                    space_searched: false,
+                };
+                handle_solve_response_message(node, Box::new(lost)).await;
+            }
+            // its subtree is gone, or joins again somewhere else and counts there
+            report_power(node, -(friend.power as i32)).await;
+        }
+        FriendType::Parent => {
+            println!("My parent {} is dead", address);
+            let leader = node.leader.lock().unwrap().clone();
+            match leader.filter(|leader| leader != address) {
+                // the rest of the tree is fine, my subtree goes back into it under the leader
+                Some(leader) if rejoin_tree(node, &leader, address).await => {}
+                _ => start_election(node).await,
+            }
+        }
+        FriendType::NotSpecified => {}
+    }
+    // after what it held was given back, so the leader doesn't see the job done before that
+    forget_holder(node, address).await;
+    forget_in_election(node, address).await;
+}
@@ -0,0 +1,28 @@
+use tokio::task::JoinSet;
+
+use crate::messages::{send_message, Message};
+use crate::utils::Node;
+
+/// Sends all messages concurrently and collects `(recipient, response)` pairs.
+/// Concurrency is bounded by the connection manager, pending sends are dropped when the returned future itself is dropped.
+/// On shutdown they are not retried, the ones in flight finish before the connections are closed.
+pub async fn fan_out<M: Message + 'static>(node: &Node, messages: Vec<M>) -> Vec<(String, Option<Box<dyn Message>>)> {
+    let mut tasks = JoinSet::new();
+    for message in messages {
+        let node_clone = node.clone();
+        tasks.spawn(async move {
+            let to = message.to().to_string();
+            let response = send_message(&message, &node_clone).await;
+            (to, response)
+        });
+    }
+
+    let mut results = Vec::new();
+    while let Some(result) = tasks.join_next().await {
+        match result {
+            Ok(result) => results.push(result),
+            Err(e) => eprintln!("Send task failed: {}", e),
+        }
+    }
+    results
+}
@@ -0,0 +1,162 @@
+use std::time::{Duration, Instant};
+
+use tokio::time::MissedTickBehavior;
+
+use crate::messages::{GossipMessage, Message};
+use crate::utils::{Friend, Member, Node};
+use super::fan_out;
+
+// a member whose heartbeat didn't go up for this many rounds is forgotten
+const GOSSIP_FORGET_AFTER_ROUNDS: u32 = 10;
+// and its tombstone is dropped after this many, older gossip about it is gone from the cluster by then
+const GOSSIP_TOMBSTONE_ROUNDS: u32 = 60;
+
+/// Membership by gossip. Every round my heartbeat goes up and my member list goes to `gossip_fanout` nodes,
+/// they answer with theirs. Newer entries win, so the whole cluster is learned from a single seed friend.
+/// New members become friends until there are `max_friends` of them (the receiver of a gossip takes the sender
+/// as a friend as well while it has room, like with PING), members that stop gossiping are forgotten
+/// (and dropped from friends unless they are in the tree - that is up to heartbeats).
+pub async fn run_gossip(node: Node) {
+    if node.config.gossip_interval_ms == 0 {
+        return;
+    }
+    let round_time = Duration::from_millis(node.config.gossip_interval_ms);
+    let mut interval = tokio::time::interval(round_time);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut round: u64 = 0;
+    loop {
+        tokio::select! {
+            _ = node.shutdown.cancelled() => break,
+            _ = interval.tick() => {}
+        }
+        if !node.is_communicating() {
+            continue;
+        }
+        round += 1;
+        node.members.lock().unwrap().insert(node.address.clone(), Member {
+            incarnation: node.incarnation,
+            heartbeat: round,
+            updated: Instant::now(),
+            gone: false,
+        });
+        forget_silent_members(&node, round_time);
+
+        let targets = pick_targets(&node, round as usize);
+        let members = alive_members(&node);
+        let messages = targets.into_iter()
+            .map(|address| GossipMessage {
+                from: node.address.clone(),
+                to: address,
+                members: members.clone(),
+            })
+            .collect();
+        for (_, response) in fan_out(&node, messages).await {
+            if let Some(gossip) = response.as_ref().and_then(|response| response.as_any().downcast_ref::<GossipMessage>()) {
+                merge_members(&node, &gossip.members);
+            }
+        }
+    }
+}
+
+pub async fn handle_gossip_message(node: &Node, _message: Box<dyn Message>) -> Box<dyn Message> {
+    let gossip = _message.as_any().downcast_ref::<GossipMessage>().unwrap();
+    // like a PING, friendship goes both ways - otherwise CALC may never reach the sender.
+    // A node that is full answers anyway, the sender just stays a member
+    if !node.is_friend(&gossip.from) && !befriend(node, &gossip.from) {
+        println!("Gossip: already have {} friends, not adding {}", node.config.max_friends, gossip.from);
+    }
+    merge_members(node, &gossip.members);
+    Box::new(GossipMessage {
+        from: node.address.clone(),
+        to: gossip.from.clone(),
+        members: alive_members(node),
+    })
+}
+
+fn merge_members(node: &Node, members: &[(String, u64, u64)]) {
+    let mut learned = Vec::new();
+    {
+        let mut known = node.members.lock().unwrap();
+        for (address, incarnation, heartbeat) in members {
+            if *address == node.address {
+                continue;
+            }
+            let newer = known.get(address)
+                .is_none_or(|member| (*incarnation, *heartbeat) > (member.incarnation, member.heartbeat));
+            if !newer {
+                continue;
+            }
+            let previous = known.insert(address.clone(), Member {
+                incarnation: *incarnation,
+                heartbeat: *heartbeat,
+                updated: Instant::now(),
+                gone: false,
+            });
+            if previous.is_none_or(|member| member.gone) {
+                learned.push(address.clone());
+            }
+        }
+    }
+    for address in learned {
+        println!("Gossip: learned about {}", address);
+        befriend(node, &address);
+    }
+}
+
+// adds a new friend unless there are `max_friends` already, true if it was added
+fn befriend(node: &Node, address: &str) -> bool {
+    let mut friends = node.friends.lock().unwrap();
+    if friends.len() >= node.config.max_friends || friends.iter().any(|f| f.address() == address) {
+        return false;
+    }
+    friends.push(Friend::new(address.to_string()));
+    println!("Added friend: {}", address);
+    true
+}
+
+fn forget_silent_members(node: &Node, round_time: Duration) {
+    let forget_after = round_time * GOSSIP_FORGET_AFTER_ROUNDS;
+    let drop_after = round_time * GOSSIP_TOMBSTONE_ROUNDS;
+    let mut forgotten = Vec::new();
+    {
+        let mut members = node.members.lock().unwrap();
+        members.retain(|_, member| !member.gone || member.updated.elapsed() < drop_after);
+        for (address, member) in members.iter_mut() {
+            if !member.gone && *address != node.address && member.updated.elapsed() >= forget_after {
+                member.gone = true;
+                forgotten.push(address.clone());
+            }
+        }
+    }
+    for address in forgotten {
+        println!("Gossip: {} is silent, forgetting it", address);
+        let in_tree = node.is_tree_friend(&address);
+        if !in_tree && node.is_friend(&address) {
+            node.remove_friend(&address);
+        }
+    }
+}
+
+// (address, incarnation, heartbeat) of everyone not forgotten, me included
+fn alive_members(node: &Node) -> Vec<(String, u64, u64)> {
+    let members = node.members.lock().unwrap();
+    members.iter()
+        .filter(|(_, member)| !member.gone)
+        .map(|(address, member)| (address.clone(), member.incarnation, member.heartbeat))
+        .collect()
+}
+
+// friends take turns, a few each round - gossiping to anyone else would make it my friend
+fn pick_targets(node: &Node, round: usize) -> Vec<String> {
+    let mut candidates: Vec<String> = {
+        let friends = node.friends.lock().unwrap();
+        friends.iter().map(|f| f.address.clone()).collect()
+    };
+    candidates.sort();
+    if candidates.is_empty() {
+        return candidates;
+    }
+    let fanout = node.config.gossip_fanout.min(candidates.len());
+    let first = round * fanout % candidates.len();
+    (0..fanout).map(|i| candidates[(first + i) % candidates.len()].clone()).collect()
+}
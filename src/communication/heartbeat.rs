@@ -0,0 +1,53 @@
+use std::time::Duration;
+
+use tokio::time::MissedTickBehavior;
+
+use crate::messages::HeartbeatMessage;
+use crate::utils::{FriendHealth, Node};
+use super::{fan_out, handle_dead_friend};
+
+/// Periodically sends heartbeats to the parent and children. A friend that misses `suspect_after` heartbeats
+/// in a row is suspected, after `dead_after` it is declared dead and goes through `handle_dead_friend` in its own task.
+pub async fn run_heartbeats(node: Node) {
+    let mut interval = tokio::time::interval(Duration::from_millis(node.config.heartbeat_interval_ms));
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            _ = node.shutdown.cancelled() => break,
+            _ = interval.tick() => {}
+        }
+        // with communication off nobody answers, that's not the friends' fault
+        if !node.is_communicating() {
+            continue;
+        }
+
+        let tree_friends: Vec<String> = {
+            let friends = node.friends.lock().unwrap();
+            friends.iter()
+                .filter(|f| f.is_in_tree() && f.health != FriendHealth::Dead)
+                .map(|f| f.address.clone())
+                .collect()
+        };
+        let messages = tree_friends.into_iter()
+            .map(|address| HeartbeatMessage {
+                from: node.address.clone(),
+                to: address,
+            })
+            .collect();
+        for (address, response) in fan_out(&node, messages).await {
+            if response.is_some() {
+                node.mark_friend_alive(&address);
+                continue;
+            }
+            if node.mark_friend_missed_heartbeat(&address) == Some(FriendHealth::Dead) {
+                println!("Friend {} is dead", address);
+                // recovery may take a whole election, the other friends keep getting heartbeats meanwhile.
+                // A dead friend gets no more of them, so it is handled once
+                let node_clone = node.clone();
+                tokio::spawn(async move {
+                    handle_dead_friend(&node_clone, &address).await;
+                });
+            }
+        }
+    }
+}
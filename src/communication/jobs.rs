@@ -0,0 +1,226 @@
+use std::sync::atomic::Ordering;
+
+use crate::checkpoint::{remove_checkpoints, report_checkpointed_progress};
+use crate::messages::{JobMessage, Message, StopJobMessage};
+use crate::problem::{Job, PartOfAProblem, PartOfAProblemState, Problem};
+use crate::utils::{JobStatus, LeaderJob, Node, NodeState};
+use super::{distribute_part, fan_out, release_and_propagate, request_work, start_leasing};
+use super::leases::{serve_waiting, stop_leasing};
+use super::stealing::steal_for;
+use super::termination::forget_job;
+
+/// Registers the job on this node and on the whole subtree below it.
+/// Returns once every child answered, so parts of the job can be sent right after.
+pub async fn register_job(node: &Node, job: Job) {
+    println!("Registering job {}: {:?}", job.id, job);
+    node.jobs.lock().unwrap().insert(job.id.clone(), job.clone());
+    let child_addresses: Vec<String> = {
+        let friends = node.friends.lock().unwrap();
+        friends.iter().filter(|f| f.is_child()).map(|f| f.address.clone()).collect()
+    };
+    let messages = child_addresses.into_iter()
+        .map(|address| JobMessage::new(node.address.clone(), address, &job))
+        .collect();
+    fan_out(node, messages).await;
+}
+
+pub async fn handle_job_message(node: &Node, _message: Box<dyn Message>) {
+    let job_message = _message.as_any().downcast_ref::<JobMessage>().unwrap();
+    let job = job_message.to_job();
+    let known = node.jobs.lock().unwrap().contains_key(&job.id);
+    register_job(node, job.clone()).await;
+    if !known {
+        report_checkpointed_progress(node, &job).await;
+    }
+    // with leases nobody sends parts, workers ask for chunks themselves
+    if job.chunk_size > 0 && !known {
+        request_work(node).await;
+    }
+}
+
+/// Puts a job into the leader's queue. Parts may be partly searched already - after an election or from a checkpoint,
+/// distributed ranges are left to their holders. The job starts once fewer than `max_jobs` jobs run.
+pub async fn submit_job(node: &Node, job: Job, parts: Vec<PartOfAProblem>) {
+    {
+        let mut state = node.state.lock().unwrap();
+        let NodeState::LEADER { jobs } = &mut *state else {
+            println!("Only leader can run jobs");
+            return;
+        };
+        if jobs.iter().any(|known| known.problem.job.id == job.id) {
+            println!("Job {} is already known", job.id);
+            return;
+        }
+        println!("Job {} queued with priority {}", job.id, job.priority);
+        jobs.push(LeaderJob {
+            problem: Problem::new(job),
+            parts,
+            status: JobStatus::Queued,
+        });
+    }
+    node.leader_parts_changed();
+    schedule_jobs(node).await;
+}
+
+/// Starts queued jobs while there is room, the highest priority first.
+pub async fn schedule_jobs(node: &Node) {
+    loop {
+        let next = {
+            let mut state = node.state.lock().unwrap();
+            let NodeState::LEADER { jobs } = &mut *state else {
+                return;
+            };
+            let running = jobs.iter().filter(|job| job.status == JobStatus::Running).count();
+            // max_by_key takes the last of equal ones, the older job should win
+            let next = jobs.iter_mut().rev()
+                .filter(|job| job.status == JobStatus::Queued)
+                .max_by_key(|job| job.problem.job.priority)
+                .filter(|_| running < node.config.max_jobs);
+            next.map(|job| {
+                job.status = JobStatus::Running;
+                (job.problem.job.clone(), job.parts.clone())
+            })
+        };
+        let Some((job, parts)) = next else {
+            break;
+        };
+        if parts.len() == 1 && parts[0].state == PartOfAProblemState::SearchedAndNotFound {
+            println!("All parts of job {} searched and no solution found. Problem is unsolvable.", job.id);
+            finish_job(node, &job.id).await;
+            continue;
+        }
+        start_job(node, &job, &parts).await;
+    }
+    // the new jobs may have something for nodes that asked before
+    serve_waiting(node).await;
+}
+
+async fn start_job(node: &Node, job: &Job, parts: &[PartOfAProblem]) {
+    println!("Starting job {}", job.id);
+    let free: Vec<PartOfAProblem> = parts.iter()
+        .filter(|part| part.state == PartOfAProblemState::NotDistributed)
+        .cloned()
+        .collect();
+    node.leader_parts_changed();
+    if job.chunk_size > 0 {
+        start_leasing(node, job, free.clone());
+    }
+    // nodes that were not in the tree before don't know the job yet
+    register_job(node, job.clone()).await;
+    if job.chunk_size > 0 {
+        // the others ask for chunks once they know the job, me too
+        if !node.solver_running.load(Ordering::SeqCst) {
+            steal_for(node, Vec::new()).await;
+        }
+        return;
+    }
+    for part in free {
+        println!("Distributing {:?}", part);
+        distribute_part(node, job, &part).await;
+    }
+}
+
+/// The job is over (solved, unsolvable or cancelled) - it is forgotten by the whole tree and the next one starts.
+/// With `release_on_finish` the tree is released after the last one.
+pub async fn end_job(node: &Node, job_id: &str) {
+    finish_job(node, job_id).await;
+    schedule_jobs(node).await;
+    let no_jobs = matches!(&*node.state.lock().unwrap(), NodeState::LEADER { jobs } if jobs.is_empty());
+    if node.config.release_on_finish && no_jobs {
+        println!("Last job is over, releasing the tree");
+        release_and_propagate(node).await;
+    }
+}
+
+async fn finish_job(node: &Node, job_id: &str) {
+    {
+        let mut state = node.state.lock().unwrap();
+        if let NodeState::LEADER { jobs } = &mut *state {
+            jobs.retain(|job| job.problem.job.id != job_id);
+        }
+    }
+    node.leader_parts_changed();
+    stop_leasing(node, job_id);
+    node.worker_progress.lock().unwrap().retain(|_, progress| progress.job_id != job_id);
+    stop_job_and_propagate(node, job_id, true).await;
+}
+
+/// Nobody searches the job until it is resumed, what was searched stays searched.
+pub async fn pause_job(node: &Node, job_id: &str) {
+    {
+        let mut state = node.state.lock().unwrap();
+        let Some(job) = state.leader_job_mut(job_id).filter(|job| job.status != JobStatus::Paused) else {
+            println!("No job {} to pause", job_id);
+            return;
+        };
+        println!("Pausing job {}", job_id);
+        job.status = JobStatus::Paused;
+        // the holders report how far they got when they stop
+        for part in job.parts.iter_mut().filter(|part| part.state != PartOfAProblemState::SearchedAndNotFound) {
+            part.state = PartOfAProblemState::NotDistributed;
+        }
+    }
+    node.leader_parts_changed();
+    stop_leasing(node, job_id);
+    node.worker_progress.lock().unwrap().retain(|_, progress| progress.job_id != job_id);
+    stop_job_and_propagate(node, job_id, false).await;
+    // its place can be taken by a queued job
+    schedule_jobs(node).await;
+}
+
+/// A paused job goes back to the queue.
+pub async fn resume_job(node: &Node, job_id: &str) {
+    {
+        let mut state = node.state.lock().unwrap();
+        let Some(job) = state.leader_job_mut(job_id).filter(|job| job.status == JobStatus::Paused) else {
+            println!("No paused job {}", job_id);
+            return;
+        };
+        println!("Resuming job {}", job_id);
+        job.status = JobStatus::Queued;
+    }
+    schedule_jobs(node).await;
+}
+
+pub async fn handle_stop_job_message(node: &Node, _message: Box<dyn Message>) {
+    let stop = _message.as_any().downcast_ref::<StopJobMessage>().unwrap();
+    println!("Received STOP_JOB for {} from {}", stop.job_id, stop.from);
+    stop_job_and_propagate(node, &stop.job_id, stop.forget).await;
+}
+
+// like STOP_CALC, but only for parts of one job
+async fn stop_job_and_propagate(node: &Node, job_id: &str, forget: bool) {
+    {
+        let mut pending = node.pending_parts.lock().unwrap();
+        pending.retain(|part| part.job_id != job_id);
+        let current = node.solving_part_of_a_problem.lock().unwrap();
+        if current.as_ref().is_some_and(|part| part.job_id == job_id && part.state == PartOfAProblemState::Solving) {
+            node.stop_flag.store(true, Ordering::SeqCst);
+        }
+    }
+    let child_addresses: Vec<String> = {
+        let mut friends = node.friends.lock().unwrap();
+        friends.iter_mut()
+            .filter(|friend| friend.is_child())
+            .map(|friend| {
+                friend.assigned_parts.retain(|part| part.job_id != job_id);
+                friend.address.clone()
+            })
+            .collect()
+    };
+    // nothing of it is signaled anymore, a resumed job starts over
+    forget_job(node, job_id);
+    if forget {
+        node.jobs.lock().unwrap().remove(job_id);
+        remove_checkpoints(node, Some(job_id));
+    }
+    let messages = child_addresses.into_iter()
+        .map(|address| StopJobMessage {
+            from: node.address.clone(),
+            to: address,
+            job_id: job_id.to_string(),
+            forget,
+        })
+        .collect();
+    fan_out(node, messages).await;
+}
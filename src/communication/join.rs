@@ -0,0 +1,136 @@
+use crate::messages::{send_message, JobMessage, JoinMessage, JoinResponseMessage, Message, PowerUpdateMessage};
+use crate::utils::{FriendType, Node};
+use super::request_work;
+use super::termination::change_parent;
+
+/// Asks a friend that is already in a tree to take me as its child. Sent after `connect` while I am idle,
+/// so a node started in the middle of a job gets part of it - the jobs are registered before the answer comes,
+/// then I ask the leader for work like any idle worker.
+pub async fn join_tree(node: &Node, address: &str) {
+    if !node.is_idle() {
+        return;
+    }
+    println!("Asking {} to join its tree", address);
+    let join = JoinMessage {
+        from: node.address.clone(),
+        to: address.to_string(),
+        power: node.power,
+    };
+    let Some(response) = send_message(&join, node).await else {
+        println!("No answer to join from {}", address);
+        return;
+    };
+    let Some((leader, wave)) = response.as_any().downcast_ref::<JoinResponseMessage>()
+        .and_then(|response| response.leader.clone().map(|leader| (leader, response.wave.clone()))) else {
+        println!("{} is not in a tree, nothing to join", address);
+        return;
+    };
+    // something else may have taken me meanwhile (a CALC)
+    if node.try_join_wave(&wave, &leader).is_err() {
+        return;
+    }
+    node.set_parent(address);
+    println!("Joined the tree of {} under {}", leader, address);
+    if !node.jobs.lock().unwrap().is_empty() {
+        request_work(node).await;
+    }
+}
+
+/// My parent died but the leader lives - my subtree joins again under the leader, with what it holds and its power.
+/// What we held was given back by the parent of the dead one and may be searched twice, nothing is lost.
+/// Returns false if the leader didn't take me, the tree has to be rebuilt then.
+pub async fn rejoin_tree(node: &Node, leader: &str, dead_parent: &str) -> bool {
+    println!("Joining the tree again under leader {}", leader);
+    let power = node.power + node.friends.lock().unwrap().iter().filter(|f| f.is_child()).map(|f| f.power).sum::<u32>();
+    let join = JoinMessage {
+        from: node.address.clone(),
+        to: leader.to_string(),
+        power,
+    };
+    let wave = node.wave.lock().unwrap().clone();
+    let joined = send_message(&join, node).await
+        .and_then(|response| response.as_any().downcast_ref::<JoinResponseMessage>().cloned())
+        .is_some_and(|response| response.leader.as_deref() == Some(leader) && Some(&response.wave) == wave.as_ref());
+    if !joined {
+        println!("Leader {} didn't take me back", leader);
+        return false;
+    }
+    node.set_parent(leader);
+    // my results and signals go to the leader now
+    change_parent(node, dead_parent, leader);
+    println!("Joined the tree of {} again", leader);
+    true
+}
+
+pub async fn handle_join_message(node: &Node, _message: Box<dyn Message>) -> Box<dyn Message> {
+    let join = _message.as_any().downcast_ref::<JoinMessage>().unwrap();
+    let leader = node.leader.lock().unwrap().clone();
+    let wave = node.wave.lock().unwrap().clone();
+    let refuse = Box::new(JoinResponseMessage {
+        from: node.address.clone(),
+        to: join.from.clone(),
+        leader: None,
+        wave: String::new(),
+    });
+    // only a settled tree takes new nodes
+    let (Some(leader), Some(wave)) = (leader.filter(|_| !node.is_idle() && !node.is_electing()), wave) else {
+        return refuse;
+    };
+    println!("Node {} joins my subtree with power {}", join.from, join.power);
+    node.add_friend(join.from.clone());
+    {
+        let mut friends = node.friends.lock().unwrap();
+        let Some(friend) = friends.iter_mut().find(|f| f.address() == join.from) else {
+            return refuse;
+        };
+        friend.set_type(FriendType::Child);
+        friend.power = join.power;
+        friend.assigned_parts.clear();
+    }
+    // the child must know the jobs before it asks for a part of them
+    let jobs: Vec<_> = node.jobs.lock().unwrap().values().cloned().collect();
+    for job in jobs {
+        let message = JobMessage::new(node.address.clone(), join.from.clone(), &job);
+        send_message(&message, node).await;
+    }
+    let node_clone = node.clone();
+    let delta = join.power as i32;
+    tokio::spawn(async move {
+        report_power(&node_clone, delta).await;
+    });
+    Box::new(JoinResponseMessage {
+        from: node.address.clone(),
+        to: join.from.clone(),
+        leader: Some(leader),
+        wave,
+    })
+}
+
+pub async fn handle_power_update_message(node: &Node, _message: Box<dyn Message>) {
+    let update = _message.as_any().downcast_ref::<PowerUpdateMessage>().unwrap();
+    println!("Power of the subtree of {} changed by {}", update.from, update.delta);
+    {
+        let mut friends = node.friends.lock().unwrap();
+        if let Some(friend) = friends.iter_mut().find(|f| f.address() == update.from && f.is_child()) {
+            friend.power = friend.power.saturating_add_signed(update.delta);
+        }
+    }
+    report_power(node, update.delta).await;
+}
+
+// goes up to the leader, every node on the way counts it for the child it came from
+pub async fn report_power(node: &Node, delta: i32) {
+    let Some(parent) = node.parent_address() else {
+        if node.is_leader() {
+            let total = node.power + node.friends.lock().unwrap().iter().filter(|f| f.is_child()).map(|f| f.power).sum::<u32>();
+            println!("Total power is now {}", total);
+        }
+        return;
+    };
+    let update = PowerUpdateMessage {
+        from: node.address.clone(),
+        to: parent,
+        delta,
+    };
+    send_message(&update, node).await;
+}
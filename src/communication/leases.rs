@@ -0,0 +1,302 @@
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+use tokio::time::MissedTickBehavior;
+
+use crate::messages::{send_message, Message, RevokeMessage};
+use crate::problem::{Job, PartOfAProblem, PartOfAProblemState};
+use crate::utils::{Lease, LeaseQueue, Node};
+use super::stealing::{deliver, steal_for};
+use super::termination::check_passive;
+
+/// Lease scheduler, used instead of the one-shot split when the job has a chunk size.
+/// Idle workers ask the leader for work (the same WORK_REQUEST as for stealing), the leader cuts the next chunk
+/// from the free ranges and leases it to them. Progress reports keep the lease alive, the solve response ends it.
+/// Chunks of workers that stopped reporting go back to the free ranges and are handed out again, the worker
+/// is told to stop searching them in case it is only slow.
+pub fn start_leasing(node: &Node, job: &Job, free: Vec<PartOfAProblem>) {
+    println!("Leasing job {} in chunks of {}", job.id, job.chunk_size);
+    node.leases.lock().unwrap().insert(job.id.clone(), LeaseQueue {
+        job_id: job.id.clone(),
+        chunk_size: job.chunk_size,
+        free: free.into_iter()
+            .map(|part| PartOfAProblem { state: PartOfAProblemState::NotDistributed, ..part })
+            .collect(),
+        leases: Vec::new(),
+        next_id: 1,
+    });
+}
+
+pub fn stop_leasing(node: &Node, job_id: &str) {
+    node.leases.lock().unwrap().remove(job_id);
+}
+
+pub fn is_leasing(node: &Node, job_id: &str) -> bool {
+    node.leases.lock().unwrap().contains_key(job_id)
+}
+
+// path goes from the idle node up to my child, empty if I am the idle one. False if no chunk of the job is free.
+pub async fn grant_lease(node: &Node, job_id: &str, path: Vec<String>) -> bool {
+    let holder = path.first().cloned().unwrap_or_else(|| node.address.clone());
+    let chunk = {
+        let mut leases = node.leases.lock().unwrap();
+        let Some(queue) = leases.get_mut(job_id) else {
+            return false;
+        };
+        let Some(mut chunk) = next_chunk(queue) else {
+            return false;
+        };
+        let id = queue.next_id;
+        queue.next_id += 1;
+        chunk.lease = Some(id);
+        queue.leases.push(Lease {
+            id,
+            holder: holder.clone(),
+            part: chunk.clone(),
+            position: chunk.start,
+            expires: Instant::now() + Duration::from_millis(node.config.lease_ms),
+        });
+        chunk
+    };
+    println!("Leasing {:?} to {}", chunk, holder);
+    node.update_leader_parts(&PartOfAProblem { state: PartOfAProblemState::Distributed, ..chunk.clone() });
+    let route = path.into_iter().rev().collect();
+    deliver(node, chunk, route).await;
+    true
+}
+
+fn next_chunk(queue: &mut LeaseQueue) -> Option<PartOfAProblem> {
+    let first = queue.free.front_mut()?;
+    let end = first.end.min(first.start.saturating_add(queue.chunk_size - 1));
+    let chunk = PartOfAProblem::new(&first.job_id, first.start, end);
+    if end == first.end {
+        queue.free.pop_front();
+    } else {
+        first.start = end + 1;
+    }
+    Some(chunk)
+}
+
+/// A progress report of the holder, the lease lives on. An expired lease isn't brought back.
+pub fn renew_lease(node: &Node, holder: &str, job_id: &str, id: u64, position: usize) {
+    let mut leases = node.leases.lock().unwrap();
+    let Some(queue) = leases.get_mut(job_id) else {
+        return;
+    };
+    if let Some(lease) = queue.leases.iter_mut().find(|lease| lease.id == id && lease.holder == holder) {
+        lease.position = position;
+        lease.expires = Instant::now() + Duration::from_millis(node.config.lease_ms);
+    }
+}
+
+/// The range was searched or given back - leases inside it are over.
+pub fn end_leases(node: &Node, part: &PartOfAProblem) {
+    let mut leases = node.leases.lock().unwrap();
+    if let Some(queue) = leases.get_mut(&part.job_id) {
+        queue.leases.retain(|lease| lease.part.start < part.start || lease.part.end > part.end);
+    }
+}
+
+/// An unsearched range came back (its holder died or left), it is leased again.
+pub async fn give_back(node: &Node, part: &PartOfAProblem) {
+    {
+        let mut leases = node.leases.lock().unwrap();
+        let Some(queue) = leases.get_mut(&part.job_id) else {
+            return;
+        };
+        // the end of a lease came back - its beginning was reported as searched, so the lease is over
+        queue.leases.retain(|lease| lease.part.end < part.start || lease.part.end > part.end);
+        println!("{:?} is free again", part);
+        queue.free.push_front(PartOfAProblem { state: PartOfAProblemState::NotDistributed, ..part.clone() });
+    }
+    serve_waiting(node).await;
+}
+
+/// Something to give appeared (a chunk came back, a job started), idle nodes that asked before get it.
+pub async fn serve_waiting(node: &Node) {
+    let waiting = std::mem::take(&mut *node.work_waiting.lock().unwrap());
+    // the ones that don't get anything wait again
+    for path in waiting {
+        steal_for(node, path).await;
+    }
+}
+
+/// Takes back chunks whose holder stopped reporting progress. What it searched stays searched,
+/// the rest is leased again.
+pub async fn run_lease_reaper(node: Node) {
+    let mut interval = tokio::time::interval(Duration::from_millis((node.config.lease_ms / 4).max(100)));
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            _ = node.shutdown.cancelled() => break,
+            _ = interval.tick() => {}
+        }
+        let expired: Vec<Lease> = {
+            let mut leases = node.leases.lock().unwrap();
+            let now = Instant::now();
+            leases.values_mut().flat_map(|queue| take_expired(queue, now)).collect()
+        };
+        if expired.is_empty() {
+            continue;
+        }
+        for lease in expired {
+            println!("Lease of {:?} by {} expired at {}", lease.part, lease.holder, lease.position);
+            if lease.position <= lease.part.end {
+                node.update_leader_parts(&PartOfAProblem::new(&lease.part.job_id, lease.position, lease.part.end));
+            }
+            revoke(&node, lease);
+        }
+        serve_waiting(&node).await;
+    }
+}
+
+// the leases expired by now, what their holders didn't search is free again
+fn take_expired(queue: &mut LeaseQueue, now: Instant) -> Vec<Lease> {
+    let (gone, alive): (Vec<Lease>, Vec<Lease>) = std::mem::take(&mut queue.leases).into_iter()
+        .partition(|lease| lease.expires <= now);
+    queue.leases = alive;
+    for lease in gone.iter().filter(|lease| lease.position <= lease.part.end) {
+        queue.free.push_front(PartOfAProblem::new(&lease.part.job_id, lease.position, lease.part.end));
+    }
+    gone
+}
+
+// a holder that is only slow would go on searching what somebody else gets now
+fn revoke(node: &Node, lease: Lease) {
+    if lease.holder == node.address {
+        let node = node.clone();
+        tokio::spawn(async move {
+            drop_lease(&node, &lease.part.job_id, lease.id).await;
+        });
+        return;
+    }
+    let message = RevokeMessage {
+        from: node.address.clone(),
+        to: lease.holder.clone(),
+        job_id: lease.part.job_id.clone(),
+        lease: lease.id,
+    };
+    let node = node.clone();
+    tokio::spawn(async move {
+        if send_message(&message, &node).await.is_none() {
+            println!("{} didn't get the revoke of lease {}, it is probably gone", message.to, message.lease);
+        }
+    });
+}
+
+pub async fn handle_revoke_message(node: &Node, _message: Box<dyn Message>) {
+    let revoke = _message.as_any().downcast_ref::<RevokeMessage>().unwrap();
+    println!("Lease {} of job {} was revoked by {}", revoke.lease, revoke.job_id, revoke.from);
+    drop_lease(node, &revoke.job_id, revoke.lease).await;
+}
+
+// Holder side - the queued pieces of the lease are dropped, a running search of it ends where it is
+// (like when a part is split, the solver reports what it searched and asks for more work).
+async fn drop_lease(node: &Node, job_id: &str, id: u64) {
+    {
+        let mut pending = node.pending_parts.lock().unwrap();
+        pending.retain(|part| !(part.job_id == job_id && part.lease == Some(id)));
+        let mut current = node.solving_part_of_a_problem.lock().unwrap();
+        if let Some(part) = current.as_mut()
+            .filter(|part| part.job_id == job_id && part.lease == Some(id) && part.state == PartOfAProblemState::Solving) {
+            let position = node.current_position.load(Ordering::SeqCst);
+            let end = position.max(part.start).min(node.current_end.load(Ordering::SeqCst));
+            node.current_end.store(end, Ordering::SeqCst);
+            part.end = end;
+        }
+    }
+    check_passive(node, job_id).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use super::*;
+    use crate::utils::NodeConfig;
+
+    const JOB: &str = "127.0.0.1:47200#1";
+
+    fn lease_queue(chunk_size: usize, free: &[(usize, usize)]) -> LeaseQueue {
+        LeaseQueue {
+            job_id: JOB.to_string(),
+            chunk_size,
+            free: free.iter().map(|(start, end)| PartOfAProblem::new(JOB, *start, *end)).collect::<VecDeque<_>>(),
+            leases: Vec::new(),
+            next_id: 1,
+        }
+    }
+
+    fn lease(id: u64, holder: &str, start: usize, end: usize, position: usize, expires: Instant) -> Lease {
+        Lease {
+            id,
+            holder: holder.to_string(),
+            part: PartOfAProblem::new(JOB, start, end),
+            position,
+            expires,
+        }
+    }
+
+    fn free(queue: &LeaseQueue) -> Vec<(usize, usize)> {
+        queue.free.iter().map(|part| (part.start, part.end)).collect()
+    }
+
+    #[test]
+    fn chunks_are_cut_from_the_front() {
+        let mut queue = lease_queue(4, &[(0, 9), (20, 23)]);
+        let mut chunks = Vec::new();
+        while let Some(chunk) = next_chunk(&mut queue) {
+            chunks.push((chunk.start, chunk.end));
+        }
+        // the rest of a range is a smaller chunk, a range of exactly the chunk size is one
+        assert_eq!(chunks, vec![(0, 3), (4, 7), (8, 9), (20, 23)]);
+        assert!(queue.free.is_empty());
+    }
+
+    #[test]
+    fn chunks_at_the_edges() {
+        let mut queue = lease_queue(1, &[(5, 6)]);
+        assert_eq!(next_chunk(&mut queue).map(|chunk| (chunk.start, chunk.end)), Some((5, 5)));
+        assert_eq!(next_chunk(&mut queue).map(|chunk| (chunk.start, chunk.end)), Some((6, 6)));
+        assert!(next_chunk(&mut queue).is_none());
+        let mut queue = lease_queue(10, &[(usize::MAX - 3, usize::MAX)]);
+        assert_eq!(next_chunk(&mut queue).map(|chunk| (chunk.start, chunk.end)), Some((usize::MAX - 3, usize::MAX)));
+        assert!(queue.free.is_empty());
+    }
+
+    #[test]
+    fn expired_lease_is_free_again_from_its_position() {
+        let now = Instant::now();
+        let mut queue = lease_queue(10, &[(30, 39)]);
+        queue.leases.push(lease(1, "127.0.0.1:47201", 0, 9, 4, now));
+        queue.leases.push(lease(2, "127.0.0.1:47202", 10, 19, 10, now + Duration::from_secs(5)));
+        // searched to the end, nothing to give again
+        queue.leases.push(lease(3, "127.0.0.1:47203", 20, 29, 30, now - Duration::from_secs(1)));
+        let expired: Vec<u64> = take_expired(&mut queue, now).iter().map(|lease| lease.id).collect();
+        assert_eq!(expired, vec![1, 3]);
+        assert_eq!(queue.leases.iter().map(|lease| lease.id).collect::<Vec<_>>(), vec![2]);
+        assert_eq!(free(&queue), vec![(4, 9), (30, 39)]);
+        // and is leased again first
+        assert_eq!(next_chunk(&mut queue).map(|chunk| (chunk.start, chunk.end)), Some((4, 9)));
+    }
+
+    #[tokio::test]
+    async fn only_the_holder_renews_its_lease() {
+        let node = Node::new("127.0.0.1:47200".to_string(), Vec::new(), NodeConfig { lease_ms: 60_000, ..Default::default() });
+        let now = Instant::now();
+        let mut leases = lease_queue(10, &[]);
+        leases.leases.push(lease(1, "127.0.0.1:47201", 0, 9, 0, now));
+        leases.leases.push(lease(2, "127.0.0.1:47202", 10, 19, 10, now));
+        node.leases.lock().unwrap().insert(JOB.to_string(), leases);
+
+        renew_lease(&node, "127.0.0.1:47201", JOB, 1, 5);
+        // somebody else's lease, or a lease id of the holder it doesn't have anymore
+        renew_lease(&node, "127.0.0.1:47201", JOB, 2, 15);
+        let mut leases = node.leases.lock().unwrap();
+        let queue = leases.get_mut(JOB).unwrap();
+        assert_eq!(queue.leases.iter().map(|lease| lease.position).collect::<Vec<_>>(), vec![5, 10]);
+        let expired: Vec<u64> = take_expired(queue, now).iter().map(|lease| lease.id).collect();
+        assert_eq!(expired, vec![2]);
+        assert_eq!(free(queue), vec![(10, 19)]);
+    }
+}
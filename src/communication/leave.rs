@@ -0,0 +1,150 @@
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use crate::messages::{send_message, AdoptMessage, LeaveMessage, Message, NewParentMessage, SolveResponseMessage};
+use crate::problem::{remove_range, PartOfAProblem, PartOfAProblemState};
+use crate::utils::{FriendType, Node};
+use super::{handle_dead_friend, handle_solve_response_message};
+use super::termination::{adopt_holder, change_parent, check_all, forget_all};
+
+/// Leaves the tree without losing anything: my children go to my parent together with the ranges they hold,
+/// my search stops and reports how far it got, the unsearched rest goes back up and only then the parent lets me go.
+/// Returns true if the node can exit now.
+pub async fn leave_tree(node: &Node) -> bool {
+    if node.is_idle() {
+        return true;
+    }
+    if node.is_leader() {
+        println!("Leader can't leave, stop the search first (or die and let the tree elect a new leader)");
+        return false;
+    }
+    if node.is_electing() {
+        println!("Can't leave during an election, try again later");
+        return false;
+    }
+    let Some(parent) = node.parent_address() else {
+        println!("No parent to hand my work over to");
+        return false;
+    };
+    println!("Leaving the tree, handing everything over to {}", parent);
+    // no signal goes up from here on - the stopped solver would report me passive before the rest is given back,
+    // the parent stops counting me when I leave
+    node.leaving.store(true, Ordering::SeqCst);
+
+    let children: Vec<AdoptMessage> = {
+        let friends = node.friends.lock().unwrap();
+        friends.iter()
+            .filter(|f| f.is_child())
+            .map(|f| AdoptMessage {
+                from: node.address.clone(),
+                to: parent.clone(),
+                child: f.address.clone(),
+                power: f.power,
+                parts: f.assigned_parts.clone(),
+            })
+            .collect()
+    };
+    // the parent knows the children and their ranges before they start talking to it
+    for adopt in children.iter() {
+        println!("Handing child {} over to {} with {:?}", adopt.child, parent, adopt.parts);
+        if send_message(adopt, node).await.is_none() {
+            println!("{} doesn't answer, not leaving", parent);
+            node.leaving.store(false, Ordering::SeqCst);
+            check_all(node).await;
+            return false;
+        }
+    }
+    for adopt in children.iter() {
+        let message = NewParentMessage {
+            from: node.address.clone(),
+            to: adopt.child.clone(),
+            parent: parent.clone(),
+        };
+        send_message(&message, node).await;
+    }
+
+    // my own search - the solver reports the searched beginning when it stops
+    let (solving, pending) = {
+        let mut pending = node.pending_parts.lock().unwrap();
+        let taken: Vec<PartOfAProblem> = pending.drain(..).collect();
+        let solving = node.solving_part_of_a_problem.lock().unwrap().clone()
+            .filter(|part| part.state == PartOfAProblemState::Solving);
+        node.stop_flag.store(true, Ordering::SeqCst);
+        (solving, taken)
+    };
+    while node.solver_running.load(Ordering::SeqCst) {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    let mut rest = pending;
+    if let Some(part) = solving {
+        let end = node.current_end.load(Ordering::SeqCst);
+        let position = node.current_position.load(Ordering::SeqCst);
+        if position <= end {
+            rest.insert(0, PartOfAProblem::new(&part.job_id, position, end));
+        }
+    }
+    for part in rest {
+        println!("Giving back {:?}", part);
+        let unsearched = SolveResponseMessage {
+            from: node.address.clone(),
+            to: node.address.clone(),
+            job_id: part.job_id.clone(),
+            start: part.start,
+            end: part.end,
+            solution: None,
+            space_searched: false,
+        };
+        handle_solve_response_message(node, Box::new(unsearched)).await;
+    }
+
+    let leave = LeaveMessage {
+        from: node.address.clone(),
+        to: parent.clone(),
+    };
+    if send_message(&leave, node).await.is_none() {
+        println!("{} didn't confirm, it notices I am gone by heartbeats", parent);
+    }
+    // my parent stopped waiting for me, my children signal their new parent
+    forget_all(node);
+    true
+}
+
+pub async fn handle_adopt_message(node: &Node, _message: Box<dyn Message>) {
+    let adopt = _message.as_any().downcast_ref::<AdoptMessage>().unwrap();
+    println!("Adopting {} from {}, it holds {:?}", adopt.child, adopt.from, adopt.parts);
+    node.add_friend(adopt.child.clone());
+    let mut friends = node.friends.lock().unwrap();
+    if let Some(child) = friends.iter_mut().find(|f| f.address() == adopt.child) {
+        child.set_type(FriendType::Child);
+        child.power = adopt.power;
+        child.assigned_parts = adopt.parts.clone();
+    }
+    adopt_holder(node, &adopt.child, &adopt.parts);
+    // those ranges were counted for the leaving node
+    if let Some(leaving) = friends.iter_mut().find(|f| f.address() == adopt.from && f.is_child()) {
+        leaving.power = leaving.power.saturating_sub(adopt.power);
+        for part in adopt.parts.iter() {
+            remove_range(&mut leaving.assigned_parts, part);
+        }
+    }
+}
+
+pub async fn handle_new_parent_message(node: &Node, _message: Box<dyn Message>) {
+    let new_parent = _message.as_any().downcast_ref::<NewParentMessage>().unwrap();
+    println!("My parent {} is leaving, {} is my parent now", new_parent.from, new_parent.parent);
+    node.remove_friend(&new_parent.from);
+    node.add_friend(new_parent.parent.clone());
+    node.set_parent(&new_parent.parent);
+    change_parent(node, &new_parent.from, &new_parent.parent);
+}
+
+pub async fn handle_leave_message(node: &Node, _message: Box<dyn Message>) {
+    let leave = _message.as_any().downcast_ref::<LeaveMessage>().unwrap();
+    println!("Child {} left the tree", leave.from);
+    let is_child = node.friends.lock().unwrap().iter().any(|f| f.address() == leave.from && f.is_child());
+    if !is_child {
+        return;
+    }
+    // whatever it still had is given back and its power goes, like when a child dies
+    handle_dead_friend(node, &leave.from).await;
+}
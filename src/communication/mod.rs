@@ -1,128 +1,245 @@
-use std::net::{TcpListener};
-use std::io::{Read, Write};
-use std::thread;
 use crate::Node;
-use crate::messages::{AckMessage, CalculatePowerMessage, CalculateResponseMessage, Message, PingMessage, parse_message, SolveProblemMessage, SolveResponseMessage, send_message, StopCalculationMessage};
-use std::thread::sleep;
-use std::time::Duration;
-use crate::problem::{Combinable, Problem, merge_parts, update_state_of_parts};
+use crate::messages::{AckMessage, AdoptMessage, CalcRejectMessage, CalculatePowerMessage, CalculateResponseMessage, DonateMessage, ElectEchoMessage, ElectMessage, GossipMessage, JobMessage, JoinMessage, LeaveMessage, Message, NewParentMessage, PauseMessage, PingMessage, PowerUpdateMessage, ProgressMessage, ReleaseMessage, ReplicaMessage, ResumeMessage, RevokeMessage, SignalMessage, SnapshotStateMessage, SolveProblemMessage, SolveResponseMessage, SplitMessage, send_message, StopCalculationMessage, StopJobMessage, TreeMessage, TreeResponseMessage, WaveLostMessage, WorkRequestMessage};
+use std::future::Future;
+use std::pin::Pin;
+use tokio::net::TcpListener;
+use crate::problem::{Combinable, Job, PartOfAProblem, Problem, merge_parts, unsearched_gaps};
 use crate::problem::PartOfAProblemState;
-use crate::utils::{NodeState};
+use crate::utils::{JobStatus, NodeState};
+use leases::{end_leases, give_back, is_leasing};
+use crate::checkpoint::remove_checkpoints;
 
 mod calc_power;
+mod connections;
+mod discovery;
+mod election;
+mod failure;
+mod fan_out;
+mod gossip;
+mod heartbeat;
+mod replication;
+mod jobs;
+mod join;
+mod leases;
+mod leave;
+mod pause;
+mod progress;
+mod release;
 mod send_parts;
+mod snapshot;
+mod solver;
+mod stealing;
+mod termination;
+mod tree;
 
-pub use calc_power::calculate_total_power;
+pub use calc_power::{start_power_calculation, handle_calculate_response, handle_wave_lost_message};
+use calc_power::switch_wave;
+pub use connections::{ConnectionManager, RequestError};
+pub use discovery::run_discovery;
+pub use election::{start_election, handle_elect_message, handle_elect_echo, forget_in_election};
+pub use failure::handle_dead_friend;
+pub use fan_out::fan_out;
+pub use gossip::{run_gossip, handle_gossip_message};
+pub use heartbeat::run_heartbeats;
+pub use replication::{run_replication, handle_replica_message};
+pub use jobs::{handle_job_message, submit_job, end_job, pause_job, resume_job, handle_stop_job_message};
+pub use join::{join_tree, handle_join_message, handle_power_update_message};
+pub use leases::{start_leasing, run_lease_reaper, handle_revoke_message};
+pub use leave::{leave_tree, handle_adopt_message, handle_new_parent_message, handle_leave_message};
+pub use pause::{pause_and_propagate, resume_and_propagate, handle_pause_message, handle_resume_message};
+pub use progress::{run_progress_reports, handle_progress_message};
+pub use release::{release_and_propagate, handle_release_message};
 pub use send_parts::send_parts_to_friends;
+pub use snapshot::{take_snapshot, record_incoming, record_response, handle_snapshot_state_message};
+pub use solver::solve_own_part;
+pub use stealing::{request_work, handle_work_request, handle_split_message, handle_donate_message};
+pub use termination::handle_signal_message;
+use termination::{forget_all, work_handled, work_received};
+pub use tree::{collect_tree, handle_tree_message, handle_tree_response};
 
 
-fn read_message(_stream: &mut std::net::TcpStream) -> Option<String> {
-    let mut buffer = [0; 1024];
-    match _stream.read(&mut buffer) {
-        Ok(size) if size > 0 => {
-            let message = String::from_utf8_lossy(&buffer[..size]).to_string();
-            Some(message)
-        }
-        _ => None,
-    }
-}
-
-pub fn listen(node: Node) {
-    // listener on new messages from other nodes
-    let listener = TcpListener::bind(&node.address).expect("Failed to bind to port");
-    for stream in listener.incoming() {
+pub async fn listen(node: Node) {
+    // listener on new connections from other nodes, each connection is kept open and served in its own task
+    let bind_address = node.config.listen_address.map_or(node.address.clone(), |address| address.to_string());
+    let listener = TcpListener::bind(&bind_address).await.expect("Failed to bind to port");
+    loop {
+        let stream = tokio::select! {
+            _ = node.shutdown.cancelled() => break,
+            accepted = listener.accept() => accepted,
+        };
         // Check if communication is enabled before accepting connections
-        if !*node.communicating.lock().unwrap() {
+        if !node.is_communicating() {
             println!("Communication is off, not accepting connections");
-            sleep(Duration::from_millis(100));
             continue;
         }
 
         match stream {
-            Ok(mut stream) => {
-                // Read the incoming message
-                if let Some(message) = read_message(&mut stream) {
-                    println!("Received message: {:?}", message);
-                    let node_clone = node.clone();
-                    let mut stream_clone = stream.try_clone().unwrap();
-                    let message_string = message.clone();
-                    // Handle the connection in a new thread
-                    thread::spawn(move || {
-                        println!("Handling new connection...");
-                        if let Some(msg) = parse_message(&message_string) {
-                            handle_new_connection(&node_clone, msg, &mut stream_clone);
-                        } else {
-                            eprintln!("Failed to parse incoming message: {}", message_string);
-                        }
-                    });
-                } else {
-                    eprintln!("Failed to read message from stream");
-                }
+            Ok((stream, _)) => {
+                let node_clone = node.clone();
+                tokio::spawn(async move {
+                    node_clone.connections.accept(&node_clone, stream).await;
+                });
             }
             Err(e) => eprintln!("Connection failed: {}", e),
         }
     }
 }
 
-// every new connection in separate thread...
-fn handle_new_connection(_node: &Node, _message: Box<dyn Message>, stream: &mut std::net::TcpStream) {
-    // process new connection and return response message
-    
+// boxed so the connection manager doesn't depend on the handler's future type (the two call each other)
+pub fn handle_request(node: Node, message: Box<dyn Message>) -> Pin<Box<dyn Future<Output = Box<dyn Message>> + Send>> {
+    Box::pin(async move { handle_new_connection(&node, message).await })
+}
+
+// every request in separate task, returns the response message
+async fn handle_new_connection(_node: &Node, _message: Box<dyn Message>) -> Box<dyn Message> {
+    // hearing from a friend is as good as a heartbeat
+    _node.mark_friend_alive(_message.from());
+    if !_message.is_background() {
+        println!("Handling new message...");
+    }
+
     // calculate power message
     if _message.as_any().is::<CalculatePowerMessage>() {
-        handle_calculate_connection(_node, _message, stream);
-        return;
+        return handle_calculate_connection(_node, _message).await;
+    } else if _message.as_any().is::<ElectMessage>() {
+        return handle_elect_message(_node, _message).await;
+    } else if _message.as_any().is::<SplitMessage>() {
+        return handle_split_message(_node, _message).await;
+    } else if _message.as_any().is::<JoinMessage>() {
+        return handle_join_message(_node, _message).await;
+    } else if _message.as_any().is::<GossipMessage>() {
+        return handle_gossip_message(_node, _message).await;
+    } else if _message.as_any().is::<TreeMessage>() {
+        handle_tree_message(_node, _message.clone_box());
+    } else if _message.as_any().is::<TreeResponseMessage>() {
+        handle_tree_response(_node, _message.clone_box()).await;
+    } else if _message.as_any().is::<ElectEchoMessage>() {
+        handle_elect_echo(_node, _message.clone_box()).await;
+    } else if _message.as_any().is::<CalculateResponseMessage>() {
+        handle_calculate_response(_node, _message.clone_box()).await;
+    } else if _message.as_any().is::<ReleaseMessage>() {
+        handle_release_message(_node, _message.clone_box()).await;
+    } else if _message.as_any().is::<WaveLostMessage>() {
+        handle_wave_lost_message(_node, _message.clone_box()).await;
     } else if _message.as_any().is::<PingMessage>() {
         _node.add_friend(_message.from().to_string());
+    } else if _message.as_any().is::<JobMessage>() {
+        handle_job_message(_node, _message.clone_box()).await;
     } else if _message.as_any().is::<SolveProblemMessage>() {
-        handle_solve_message(_node, _message.clone_box());
+        handle_solve_message(_node, _message.clone_box()).await;
     } else if _message.as_any().is::<SolveResponseMessage>() {
-        handle_solve_response_message(_node, _message.clone_box());
+        handle_solve_response_message(_node, _message.clone_box()).await;
+    } else if _message.as_any().is::<WorkRequestMessage>() {
+        handle_work_request(_node, _message.clone_box());
+    } else if _message.as_any().is::<DonateMessage>() {
+        handle_donate_message(_node, _message.clone_box()).await;
+    } else if _message.as_any().is::<RevokeMessage>() {
+        handle_revoke_message(_node, _message.clone_box()).await;
+    } else if _message.as_any().is::<SignalMessage>() {
+        handle_signal_message(_node, _message.clone_box()).await;
+    } else if _message.as_any().is::<ProgressMessage>() {
+        handle_progress_message(_node, _message.clone_box()).await;
+    } else if _message.as_any().is::<ReplicaMessage>() {
+        handle_replica_message(_node, _message.clone_box());
     } else if _message.as_any().is::<StopCalculationMessage>() {
-        handle_stop_calculate_connection(_node, _message.clone_box());
+        handle_stop_calculate_connection(_node, _message.clone_box()).await;
+    } else if _message.as_any().is::<StopJobMessage>() {
+        handle_stop_job_message(_node, _message.clone_box()).await;
+    } else if _message.as_any().is::<PauseMessage>() {
+        handle_pause_message(_node, _message.clone_box()).await;
+    } else if _message.as_any().is::<ResumeMessage>() {
+        handle_resume_message(_node, _message.clone_box()).await;
+    } else if _message.as_any().is::<PowerUpdateMessage>() {
+        handle_power_update_message(_node, _message.clone_box()).await;
+    } else if _message.as_any().is::<AdoptMessage>() {
+        handle_adopt_message(_node, _message.clone_box()).await;
+    } else if _message.as_any().is::<NewParentMessage>() {
+        handle_new_parent_message(_node, _message.clone_box()).await;
+    } else if _message.as_any().is::<LeaveMessage>() {
+        handle_leave_message(_node, _message.clone_box()).await;
+    } else if _message.as_any().is::<SnapshotStateMessage>() {
+        handle_snapshot_state_message(_node, _message.clone_box()).await;
     }
     // always send ack at the end
-    send_acknowledgment(_node, _message, stream);
+    acknowledgment(_node, _message)
 }
 
-fn send_acknowledgment(_node: &Node, _message: Box<dyn Message>, stream: &mut std::net::TcpStream) {
+fn acknowledgment(_node: &Node, _message: Box<dyn Message>) -> Box<dyn Message> {
     let response = AckMessage {
         from: _node.address.clone(),
         to: _message.from().to_string(),
     };
-    let serialized = response.serialize();
-    println!("Sending acknowledgment: {}", serialized);
-    let _ = stream.write_all(serialized.as_bytes());
+    if !_message.is_background() {
+        println!("Sending acknowledgment: {}", response.serialize());
+    }
+    Box::new(response)
 }
 
 
-fn handle_calculate_connection(_node: &Node, _message: Box<dyn Message>, stream: &mut std::net::TcpStream) {
-    // if not idle -> will not work
-    if !_node.is_idle() {
-        send_acknowledgment(_node, _message, stream);
-        return;
+async fn handle_calculate_connection(_node: &Node, _message: Box<dyn Message>) -> Box<dyn Message> {
+    let calc_msg = _message.as_any().downcast_ref::<CalculatePowerMessage>().unwrap();
+    let current = _node.wave.lock().unwrap().clone();
+    // a retry of the CALC I already accepted
+    if current.as_ref() == Some(&calc_msg.wave) && _node.parent_address().as_deref() == Some(calc_msg.from()) {
+        return acknowledgment(_node, _message);
+    }
+    // a CALC of my own wave that came back through a cycle, even if I stepped down meanwhile
+    let own_wave = calc_msg.leader == _node.address;
+    // if not idle -> already in a tree, the sender doesn't get me as its child (unless its wave beats mine)
+    let joined = if own_wave { Err(current) } else { _node.try_join_wave(&calc_msg.wave, &calc_msg.leader) };
+    let previous = match joined {
+        Ok(previous) => previous,
+        Err(joined) => {
+            if joined.as_ref() == Some(&calc_msg.wave) {
+                println!("Wave {} reached me again through {}, not its child", calc_msg.wave, calc_msg.from);
+            } else {
+                println!("Rejecting wave {} from {}, I am in wave {:?}", calc_msg.wave, calc_msg.from, joined);
+            }
+            return Box::new(CalcRejectMessage {
+                from: _node.address.clone(),
+                to: calc_msg.from.clone(),
+                wave: calc_msg.wave.clone(),
+                joined,
+            });
+        }
+    };
+    if let Some(previous) = previous {
+        switch_wave(_node, &previous, &calc_msg.wave);
     }
-    _node.set_state_worker();
     // set parent
-    _node.set_parent(_message.from());
-
-    let power = calculate_total_power(_node);
+    _node.set_parent(calc_msg.from());
 
-    let response = CalculateResponseMessage {
-        from: _node.address.clone(),
-        to: _message.from().to_string(),
-        power,
-    };
-    let serialized = response.serialize();
-    println!("Sending response: {}", serialized);
-    let _ = stream.write_all(serialized.as_bytes());
+    // my subtree is queried in the background, the power is sent to the parent as a new message
+    let node_clone = _node.clone();
+    let parent = calc_msg.from().to_string();
+    let budget_ms = calc_msg.budget_ms;
+    tokio::spawn(async move {
+        start_power_calculation(&node_clone, Some(parent), budget_ms).await;
+    });
+    acknowledgment(_node, _message)
 }
 
 
-fn handle_solve_message(_node: &Node, _message: Box<dyn Message>) {
+async fn handle_solve_message(_node: &Node, _message: Box<dyn Message>) {
     let problem_message = _message.as_any().downcast_ref::<SolveProblemMessage>().unwrap();
     println!("Received solve problem message: {:?}", problem_message);
-    let problem = Problem::new_from_solve_message(problem_message);
+    work_received(_node, &problem_message.job_id, &problem_message.from);
+    let job = _node.jobs.lock().unwrap().get(&problem_message.job_id).cloned();
+    match job {
+        Some(job) => {
+            let part = PartOfAProblem::new(&job.id, problem_message.start, problem_message.end);
+            distribute_part(_node, &job, &part).await;
+        }
+        None => eprintln!("Unknown job {}, ignoring its part", problem_message.job_id),
+    }
+    work_handled(_node, &problem_message.job_id).await;
+}
+
+
+/// Splits a part between me and my children by power, sends the children their shares and queues my own.
+/// Used for the whole job on the leader, for received parts and for parts given back after a failure.
+pub async fn distribute_part(_node: &Node, job: &Job, part: &PartOfAProblem) {
+    let problem = Problem::new_from_part(job, part);
     let mut available_power = _node.friends.lock().unwrap().iter().filter(|friend| friend.is_child()).map(|friend| friend.power).sum::<u32>();
     available_power += _node.power;
     let parts = problem.divide_into_n(available_power as usize);
@@ -130,164 +247,170 @@ fn handle_solve_message(_node: &Node, _message: Box<dyn Message>) {
     for (i, part) in parts.iter().enumerate() {
         println!("Part {}: {:?}, combinations: {}", i, part, part.total_combinations());
     }
-    assign_parts_to_self_and_friends(_node, parts);
-    send_parts_to_friends(_node);
-    // (Solving own part happens in this thread)
-    println!("WORKER started solving problem...");
-    // need thread to send ack...
-    let problem_part = _node.solving_part_of_a_problem.lock().unwrap().as_ref().unwrap().clone();
-    _node.stop_flag.store(false, std::sync::atomic::Ordering::SeqCst);
-    let stop_flag = _node.stop_flag.clone();
-    let my_address = _node.address.to_string();
-    let parent_address = _node.get_parent_address();
-    let node_clone = _node.clone();
-    std::thread::spawn(move || {
-        let mut problem = Problem::new_from_part(&problem_part);
-        match problem.brute_force(&stop_flag) {
-            Some(solution) => {
-                println!("Solution found: {}", solution);
-                let response = SolveResponseMessage {
-                    from: my_address,
-                    to: parent_address,
-                    start: problem_part.start.clone(),
-                    end: problem_part.end.clone(),
-                    solution: Some(solution),
-                    space_searched: true,
-                };
-                send_message(&response, &node_clone);
-            }
-            None => {
-                println!("No solution found in my part.");
-                let space_searched = !stop_flag.load(std::sync::atomic::Ordering::SeqCst);
-                let response = SolveResponseMessage {
-                    from: my_address,
-                    to: parent_address,
-                    start: problem_part.start.clone(),
-                    end: problem_part.end.clone(),
-                    solution: None,
-                    space_searched,
-                };
-                // only send if fully searched - if not - received stop signal and parent already knows
-                if space_searched {
-                    send_message(&response, &node_clone);
-                    node_clone.solving_part_of_a_problem.lock().unwrap().as_mut().unwrap().state = PartOfAProblemState::SearchedAndNotFound;
-                } else {
-                    *node_clone.solving_part_of_a_problem.lock().unwrap() = None;
-                }
-                stop_flag.store(true, std::sync::atomic::Ordering::SeqCst);
-            }
-        }
-        stop_flag.store(true, std::sync::atomic::Ordering::SeqCst);
-    });
+    let my_part = assign_parts_to_self_and_friends(_node, parts);
+    send_parts_to_friends(_node).await;
+    // own part is solved on a worker thread, this task only sends the ack
+    if let Some(my_part) = my_part {
+        println!("Started solving problem...");
+        solve_own_part(_node, my_part);
+    }
 }
 
 
-// Assign parts to self and friends, shared for both commands and communication
-pub fn assign_parts_to_self_and_friends(_node: &Node, parts: Vec<crate::problem::PartOfAProblem>) {
-    // Assign my part
-    if let Some(my_part) = parts.get(0) {
-        _node.solving_part_of_a_problem.lock().unwrap().replace(my_part.clone());
-    }
+// Assign parts to friends, returns my own part. Shared for both commands and communication
+pub fn assign_parts_to_self_and_friends(_node: &Node, parts: Vec<PartOfAProblem>) -> Option<PartOfAProblem> {
     // Assign parts to friends
     let mut part_index = 1; // 0 is for myself
     let mut friends = _node.friends.lock().unwrap();
     for friend in friends.iter_mut() {
         if friend.is_child() && friend.power > 0 {
-            let take_n = friend.power as usize;
-            if part_index + take_n > parts.len() + 1 {
+            // less parts than power when the range is small
+            let take_n = (friend.power as usize).min(parts.len().saturating_sub(part_index));
+            if take_n == 0 {
                 break;
             }
-            let merged = merge_parts(&parts[part_index..part_index+take_n].to_vec());
-            println!("Assigning to friend {:?} part: {:?}, total {:?}", friend, merged, merged.total_combinations());
-            friend.solving_part_of_a_problem.replace(merged);
+            let merged = merge_parts(&parts[part_index..part_index+take_n]);
+            println!("Assigning to friend {:?} part: {:?}, total {:?}", friend.address, merged, merged.total_combinations());
+            friend.assigned_parts.push(merged);
             part_index += take_n;
         }
     }
+    parts.into_iter().next()
 }
 
-pub fn handle_solve_response_message(node: &Node, _message: Box<dyn Message>) {
+pub async fn handle_solve_response_message(node: &Node, _message: Box<dyn Message>) {
     let solve_response = _message.as_any().downcast_ref::<SolveResponseMessage>().unwrap();
+    // searched or given back, either way the child doesn't hold the range anymore
+    let reported_part = PartOfAProblem::new(&solve_response.job_id, solve_response.start, solve_response.end);
+    node.release_child_part(solve_response.from(), &reported_part);
     if !node.is_leader() {
         // Forward the message to the parent (who will forward to leader)
-        let parent_address = node.get_parent_address();
+        let Some(parent_address) = node.parent_address() else {
+            if node.is_electing() {
+                println!("No parent until the election ends, keeping {:?}", solve_response);
+                node.undelivered.lock().unwrap().push(solve_response.serialize());
+            } else {
+                eprintln!("No parent to forward {:?} to, dropping it", solve_response);
+            }
+            return;
+        };
         let mut forward_message = solve_response.clone();
         // Set 'from' to this node, 'to' to parent
         forward_message.from = node.address.clone();
         forward_message.to = parent_address.clone();
-        send_message(&forward_message, node);
+        send_message(&forward_message, node).await;
         return;
     }
 
-    print!("Leader handling solve response message...\n");
+    println!("Leader handling solve response message...");
     println!("Received solve response: {:?}", solve_response);
 
-    if solve_response.solution.is_some() {
-        println!("!!!!! Solution found - it is {} !!!!!", solve_response.solution.as_ref().unwrap());
-        stop_cal_and_propagate(node);
+    // response for a job that was already stopped (solution found elsewhere)
+    let active = node.state.lock().unwrap().is_active_job(&solve_response.job_id);
+    if !active {
+        println!("Job {} is not active anymore, ignoring response", solve_response.job_id);
+        return;
+    }
+
+    if let Some(solution) = &solve_response.solution {
+        println!("!!!!! Solution found - it is {} !!!!!", solution);
+        println!("Job {} is done", solve_response.job_id);
+        end_job(node, &solve_response.job_id).await;
         return;
     }
     
-    let updated_part = crate::problem::PartOfAProblem {
-        alphabet: String::new(), // not needed here
-        start: solve_response.start.clone(),
-        end: solve_response.end.clone(),
-        hash: String::new(), // not needed here
-        state: if solve_response.space_searched {
-            PartOfAProblemState::SearchedAndNotFound
-        } else {
-            PartOfAProblemState::NotDistributed
-        },
+    let reported = PartOfAProblem::new(&solve_response.job_id, solve_response.start, solve_response.end);
+    let updated_parts = if solve_response.space_searched {
+        let searched = PartOfAProblem { state: PartOfAProblemState::SearchedAndNotFound, ..reported };
+        end_leases(node, &searched);
+        vec![searched]
+    } else {
+        // what of it was reported searched already stays searched
+        let state = node.state.lock().unwrap();
+        let Some(leader_job) = state.leader_job(&solve_response.job_id) else {
+            return;
+        };
+        unsearched_gaps(&leader_job.parts, &reported)
     };
 
-    {
-        let mut state = node.state.lock().unwrap();
-        if let NodeState::LEADER { problem: _, parts: leader_parts } = &mut *state {
-            println!("Updating leader's parts with response...");
-            println!("Before update: {:?}", leader_parts);
-            update_state_of_parts(leader_parts, &updated_part);
-            println!("After update: {:?}", leader_parts);
-        }
+    println!("Updating leader's parts with response...");
+    for part in updated_parts.iter() {
+        node.update_leader_parts(part);
     }
-    // if searched entire space
-    {
+    let (job, status) = {
         let state = node.state.lock().unwrap();
-        if let NodeState::LEADER { parts, .. } = &*state {
-            if parts.len() == 1 && matches!(parts[0].state, PartOfAProblemState::SearchedAndNotFound) {
-                println!("All parts searched and no solution found. Problem is unsolvable.");
-                thread::spawn({
-                    let node = node.clone();
-                    move || {
-                        stop_cal_and_propagate(&node);
-                    }
-                });
+        let Some(leader_job) = state.leader_job(&solve_response.job_id) else {
+            return;
+        };
+        println!("After update: {:?}", leader_job.parts);
+        (leader_job.problem.job.clone(), leader_job.status)
+    };
+    // a range came back unsearched (its holder died), its gaps are split again among who is left
+    if !solve_response.space_searched {
+        let leasing = is_leasing(node, &job.id);
+        for part in updated_parts {
+            if leasing {
+                give_back(node, &part).await;
+            } else if status == JobStatus::Running {
+                // a paused job keeps it until it is resumed
+                println!("Redistributing lost range {:?}", part);
+                distribute_part(node, &job, &part).await;
             }
         }
     }
+    // whether the whole space is searched is decided by the termination detection, once all work is done
 }
 
 
-pub fn handle_stop_calculate_connection(_node: &Node, _message: Box<dyn Message>) {
+pub async fn handle_stop_calculate_connection(_node: &Node, _message: Box<dyn Message>) {
     println!("Received STOP_CALC message from {}", _message.from());
-    stop_cal_and_propagate(_node);
+    stop_cal_and_propagate(_node).await;
 }
 
-pub fn stop_cal_and_propagate(_node: &Node) {
+pub async fn stop_cal_and_propagate(_node: &Node) {
+    let child_addresses = stop_cal(_node);
+    let stop_messages = child_addresses.into_iter()
+        .map(|address| StopCalculationMessage {
+            from: _node.address.clone(),
+            to: address,
+        })
+        .collect();
+    fan_out(_node, stop_messages).await;
+}
+
+// stops everything of the jobs on this node, returns my children
+fn stop_cal(_node: &Node) -> Vec<String> {
     if _node.is_leader() {
         let mut state = _node.state.lock().unwrap();
-        if let NodeState::LEADER { problem, parts } = &mut *state {
-            *problem = None;
-            *parts = Vec::new();
+        if let NodeState::LEADER { jobs } = &mut *state {
+            // the jobs are over, nothing to resume
+            for job in jobs.drain(..) {
+                remove_checkpoints(_node, Some(&job.problem.job.id));
+            }
         }
     }
+    remove_checkpoints(_node, None);
+    forget_all(_node);
+    *_node.replica.lock().unwrap() = None;
+    _node.leases.lock().unwrap().clear();
+    _node.work_waiting.lock().unwrap().clear();
+    _node.worker_progress.lock().unwrap().clear();
+    // nothing left to resume
+    _node.paused.store(false, std::sync::atomic::Ordering::SeqCst);
 
-    _node.stop_flag.store(true, std::sync::atomic::Ordering::SeqCst);
-    // Set solving_part_of_a_problem to None for each child and collect their addresses
+    // queued parts are dropped, the running one is interrupted
+    {
+        let mut pending = _node.pending_parts.lock().unwrap();
+        pending.clear();
+        _node.stop_flag.store(true, std::sync::atomic::Ordering::SeqCst);
+    }
+    // Forget parts assigned to each child and collect their addresses
     let child_addresses: Vec<String> = {
         let mut friends = _node.friends.lock().unwrap();
         friends.iter_mut()
             .filter_map(|friend| {
                 if friend.is_child() {
-                    friend.solving_part_of_a_problem = None;
+                    friend.assigned_parts.clear();
                     Some(friend.address.clone())
                 } else {
                     None
@@ -295,16 +418,7 @@ pub fn stop_cal_and_propagate(_node: &Node) {
             })
             .collect()
     };
-    for address in child_addresses {
-        let from = _node.address.clone();
-        let node_clone = _node.clone();
-        std::thread::spawn(move || {
-            let stop_message = StopCalculationMessage {
-                from,
-                to: address,
-            };
-            send_message(&stop_message, &node_clone);
-        });
-    }
     *_node.solving_part_of_a_problem.lock().unwrap() = None;
+    _node.jobs.lock().unwrap().clear();
+    child_addresses
 }
\ No newline at end of file
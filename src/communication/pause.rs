@@ -0,0 +1,57 @@
+use std::sync::atomic::Ordering;
+
+use crate::messages::{Message, PauseMessage, ResumeMessage};
+use crate::utils::Node;
+use super::fan_out;
+use super::leases::serve_waiting;
+
+/// Pauses the search in my whole subtree. Solvers stop where they are and keep their parts,
+/// the leader keeps its parts and leases as they are - nothing is given back or redistributed.
+pub async fn pause_and_propagate(node: &Node) {
+    if node.paused.swap(true, Ordering::SeqCst) {
+        println!("Already paused");
+        return;
+    }
+    println!("Pausing the search");
+    let messages = child_addresses(node).into_iter()
+        .map(|address| PauseMessage {
+            from: node.address.clone(),
+            to: address,
+        })
+        .collect();
+    fan_out(node, messages).await;
+}
+
+/// Solvers continue from the position they paused at, nodes that asked for work meanwhile get some.
+pub async fn resume_and_propagate(node: &Node) {
+    if !node.paused.swap(false, Ordering::SeqCst) {
+        println!("Not paused");
+        return;
+    }
+    println!("Resuming the search");
+    let messages = child_addresses(node).into_iter()
+        .map(|address| ResumeMessage {
+            from: node.address.clone(),
+            to: address,
+        })
+        .collect();
+    fan_out(node, messages).await;
+    if node.is_leader() {
+        serve_waiting(node).await;
+    }
+}
+
+pub async fn handle_pause_message(node: &Node, _message: Box<dyn Message>) {
+    println!("Received PAUSE from {}", _message.from());
+    pause_and_propagate(node).await;
+}
+
+pub async fn handle_resume_message(node: &Node, _message: Box<dyn Message>) {
+    println!("Received RESUME from {}", _message.from());
+    resume_and_propagate(node).await;
+}
+
+fn child_addresses(node: &Node) -> Vec<String> {
+    let friends = node.friends.lock().unwrap();
+    friends.iter().filter(|f| f.is_child()).map(|f| f.address.clone()).collect()
+}
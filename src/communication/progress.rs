@@ -0,0 +1,87 @@
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+use tokio::time::MissedTickBehavior;
+
+use crate::messages::{send_message, Message, ProgressMessage};
+use crate::problem::{PartOfAProblem, PartOfAProblemState};
+use crate::utils::{Node, WorkerProgress};
+use super::leases::renew_lease;
+
+/// Periodically reports how far my search got. Reports go up the tree like solve responses,
+/// the leader marks everything before the position as searched and keeps the rate for `info`.
+pub async fn run_progress_reports(node: Node) {
+    let mut interval = tokio::time::interval(Duration::from_millis(node.config.progress_interval_ms));
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    // (candidates searched, time) at the last report, for the rate - counted over all parts, they may take turns
+    let mut last: Option<(usize, Instant)> = None;
+    loop {
+        tokio::select! {
+            _ = node.shutdown.cancelled() => break,
+            _ = interval.tick() => {}
+        }
+        let Some(part) = node.solving_part_of_a_problem.lock().unwrap().clone()
+            .filter(|part| part.state == PartOfAProblemState::Solving) else {
+            last = None;
+            continue;
+        };
+        let position = node.current_position.load(Ordering::SeqCst);
+        let end = node.current_end.load(Ordering::SeqCst);
+        let now = Instant::now();
+        let searched = node.searched.load(Ordering::SeqCst);
+        let rate = match last {
+            Some((last_searched, time)) => {
+                let elapsed = now.duration_since(time).as_secs_f64();
+                if elapsed > 0.0 { (searched.saturating_sub(last_searched) as f64 / elapsed) as u64 } else { 0 }
+            }
+            // first report after being idle, nothing to compare with
+            None => 0,
+        };
+        last = Some((searched, now));
+        let report = ProgressMessage {
+            from: node.address.clone(),
+            to: node.address.clone(),
+            origin: node.address.clone(),
+            job_id: part.job_id.clone(),
+            start: part.start,
+            position,
+            end,
+            rate,
+            lease: part.lease,
+        };
+        handle_progress_message(&node, Box::new(report)).await;
+    }
+}
+
+pub async fn handle_progress_message(node: &Node, _message: Box<dyn Message>) {
+    let progress = _message.as_any().downcast_ref::<ProgressMessage>().unwrap();
+    if !node.is_leader() {
+        let Some(parent) = node.parent_address() else {
+            return;
+        };
+        let mut forward = progress.clone();
+        forward.from = node.address.clone();
+        forward.to = parent;
+        send_message(&forward, node).await;
+        return;
+    }
+    node.worker_progress.lock().unwrap().insert(progress.origin.clone(), WorkerProgress {
+        job_id: progress.job_id.clone(),
+        position: progress.position,
+        end: progress.end,
+        rate: progress.rate,
+        updated: Instant::now(),
+    });
+    if let Some(lease) = progress.lease {
+        renew_lease(node, &progress.origin, &progress.job_id, lease, progress.position);
+    }
+    // the searched beginning of the part, the rest keeps its state
+    if progress.position > progress.start {
+        let mut searched = PartOfAProblem::new(&progress.job_id, progress.start, progress.position - 1);
+        searched.state = PartOfAProblemState::SearchedAndNotFound;
+        let active = node.state.lock().unwrap().is_active_job(&progress.job_id);
+        if active {
+            node.update_leader_parts(&searched);
+        }
+    }
+}
@@ -0,0 +1,45 @@
+use crate::messages::{Message, ReleaseMessage};
+use crate::utils::Node;
+use super::{fan_out, stop_cal};
+
+/// Takes the whole tree apart: jobs are stopped, every node of my wave goes back to idle
+/// without a parent, children, powers or parts, so the next `cal` can build a new tree.
+/// Spreads like the wave that built the tree - to all friends, the ones of another wave (or already released) ignore it.
+pub async fn release_and_propagate(node: &Node) {
+    let Some(wave) = node.wave.lock().unwrap().clone() else {
+        println!("Not in a tree, nothing to release");
+        return;
+    };
+    release(node, &wave, None).await;
+}
+
+pub async fn handle_release_message(node: &Node, _message: Box<dyn Message>) {
+    let release_msg = _message.as_any().downcast_ref::<ReleaseMessage>().unwrap();
+    if node.wave.lock().unwrap().as_ref() != Some(&release_msg.wave) {
+        return;
+    }
+    println!("Received RELEASE of wave {} from {}", release_msg.wave, release_msg.from);
+    release(node, &release_msg.wave, Some(&release_msg.from)).await;
+}
+
+async fn release(node: &Node, wave: &str, from: Option<&str>) {
+    println!("Releasing the tree of wave {}", wave);
+    stop_cal(node);
+    node.undelivered.lock().unwrap().clear();
+    *node.election.lock().unwrap() = None;
+    // forgotten before passing it on, the RELEASE coming back through a cycle is ignored
+    node.forget_tree();
+    let messages = {
+        let friends = node.friends.lock().unwrap();
+        friends.iter()
+            .filter(|f| Some(f.address.as_str()) != from)
+            .map(|f| ReleaseMessage {
+                from: node.address.clone(),
+                to: f.address.clone(),
+                wave: wave.to_string(),
+            })
+            .collect()
+    };
+    fan_out(node, messages).await;
+    println!("Released, node is idle");
+}
@@ -0,0 +1,58 @@
+use std::sync::atomic::Ordering;
+
+use crate::messages::{Message, ReplicaMessage};
+use crate::problem::PartOfAProblem;
+use crate::utils::{LeaderReplica, Node, NodeState};
+use super::fan_out;
+
+/// Sends the leader's interval maps (of all its jobs) to its standbys (the first `standbys` children) whenever it changes.
+/// Changes made while a copy is being sent are coalesced into the next one. If the leader dies, standbys add
+/// the searched ranges to their election report, so the new leader doesn't search them again.
+pub async fn run_replication(node: Node) {
+    loop {
+        tokio::select! {
+            _ = node.shutdown.cancelled() => break,
+            _ = node.leader_changed.notified() => {}
+        }
+        // read before the snapshot, a newer change wakes the loop again
+        let version = node.leader_version.load(Ordering::SeqCst);
+        let parts: Vec<PartOfAProblem> = match &*node.state.lock().unwrap() {
+            NodeState::LEADER { jobs } => jobs.iter().flat_map(|job| job.parts.iter().cloned()).collect(),
+            _ => continue,
+        };
+        let mut standbys: Vec<String> = {
+            let friends = node.friends.lock().unwrap();
+            friends.iter().filter(|f| f.is_child()).map(|f| f.address.clone()).collect()
+        };
+        standbys.sort();
+        standbys.truncate(node.config.standbys);
+        let messages = standbys.into_iter()
+            .map(|address| ReplicaMessage {
+                from: node.address.clone(),
+                to: address,
+                leader: node.address.clone(),
+                version,
+                parts: parts.clone(),
+            })
+            .collect();
+        fan_out(&node, messages).await;
+    }
+}
+
+pub fn handle_replica_message(node: &Node, _message: Box<dyn Message>) {
+    let replica_message = _message.as_any().downcast_ref::<ReplicaMessage>().unwrap();
+    let mut replica = node.replica.lock().unwrap();
+    // retried or reordered copies must not overwrite a newer one
+    let outdated = replica.as_ref().is_some_and(|replica| {
+        replica.leader == replica_message.leader && replica.version >= replica_message.version
+    });
+    if outdated {
+        return;
+    }
+    println!("Standby copy of jobs from {}, version {}", replica_message.leader, replica_message.version);
+    *replica = Some(LeaderReplica {
+        leader: replica_message.leader.clone(),
+        version: replica_message.version,
+        parts: replica_message.parts.clone(),
+    });
+}
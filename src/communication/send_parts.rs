@@ -1,54 +1,45 @@
 use crate::utils::{Node, FriendType};
-use crate::messages::{send_message, SolveProblemMessage};
-use crate::problem::{PartOfAProblemState, update_state_of_parts};
-use std::thread;
+use crate::messages::SolveProblemMessage;
+use crate::problem::PartOfAProblemState;
+use super::fan_out;
+use super::termination::work_sent;
 
-/// Sends parts of a problem to friends: for each friend of type Child with a not distributed part, send it.
-pub fn send_parts_to_friends(node: &Node) {
+/// Sends parts of a problem to friends: for each friend of type Child, send its not distributed parts.
+pub async fn send_parts_to_friends(node: &Node) {
     // Collect work to do while holding the lock
     let mut to_send = Vec::new();
     {
         let mut friends = node.friends.lock().unwrap();
         for friend in friends.iter_mut() {
-            if friend.friend_type == FriendType::Child {
-                if let Some(part) = &mut friend.solving_part_of_a_problem {
-                    if matches!(part.state, PartOfAProblemState::NotDistributed) {
-                        let node_clone = node.clone();
-                        let friend_address = friend.address.clone();
-                        part.state = PartOfAProblemState::Distributed;
-                        to_send.push((node_clone, friend_address, part.clone()));
-                        // update leader node state parts...
-                        if node.is_leader() {
-                            let mut state_guard = node.state.lock().unwrap();
-                            if let crate::utils::NodeState::LEADER { problem: _, parts } = &mut *state_guard {
-                                println!("Parts of leader before update: {:?}", parts);
-                                update_state_of_parts(parts, &part);
-                                println!("Parts of leader after update: {:?}", parts);
-                            }
-                        }
-                    }
+            if friend.friend_type != FriendType::Child {
+                continue;
+            }
+            for part in friend.assigned_parts.iter_mut() {
+                if !matches!(part.state, PartOfAProblemState::NotDistributed) {
+                    continue;
                 }
+                part.state = PartOfAProblemState::Distributed;
+                to_send.push((friend.address.clone(), part.clone()));
+                // update leader node state parts...
+                node.update_leader_parts(part);
             }
         }
     }
 
-    // Now send messages outside the lock
-    let mut handles = Vec::new();
-    for (node_clone, friend_address, part) in to_send {
-        let handle = thread::spawn(move || {
-            let message = SolveProblemMessage {
-                from: node_clone.address.clone(),
-                to: friend_address,
-                alphabet: part.alphabet.clone(),
-                start: part.start.clone(),
-                end: part.end.clone(),
-                hash: part.hash.clone(),
-            };
-            let _ = send_message(&message, &node_clone);
-        });
-        handles.push(handle);
-    }
-    for handle in handles {
-        let _ = handle.join();
+    // counted before they go, a child that doesn't get its part is given up on by heartbeats
+    for (friend_address, part) in to_send.iter() {
+        work_sent(node, &part.job_id, friend_address);
     }
+
+    // Now send messages outside the lock
+    let messages = to_send.into_iter()
+        .map(|(friend_address, part)| SolveProblemMessage {
+            from: node.address.clone(),
+            to: friend_address,
+            job_id: part.job_id.clone(),
+            start: part.start,
+            end: part.end,
+        })
+        .collect();
+    fan_out(node, messages).await;
 }
@@ -0,0 +1,278 @@
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use tokio::task::JoinSet;
+use tokio::time::timeout;
+use tokio_util::sync::CancellationToken;
+
+use crate::messages::{send_message, MarkerMessage, Message, SnapshotStateMessage};
+use crate::utils::{LocalSnapshot, Node, SnapshotReports};
+
+// a channel whose marker doesn't come by then (the friend is dead or doesn't have me as a friend) is left open
+const SNAPSHOT_TIMEOUT: Duration = Duration::from_secs(10);
+// handlers of what came before the cut that run longer than this (waiting for a node that records its own state) are not waited for
+const HANDLERS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Consistent snapshot of the whole cluster (Chandy-Lamport over the friend channels). I record my state
+/// and send a marker to every friend, each node does the same when the first marker reaches it and records
+/// what comes on every other channel until the marker of that channel arrives - those messages were in flight.
+/// Every node sends its part to me, the result is the text of all of them. Returns (snapshot id, text).
+pub async fn take_snapshot(node: &Node) -> (String, String) {
+    let id = node.new_wave_id();
+    println!("Taking snapshot {}", id);
+    *node.snapshot_reports.lock().unwrap() = Some(SnapshotReports {
+        id: id.clone(),
+        reports: HashMap::new(),
+    });
+    record_state(node, &id, &node.address, None);
+
+    // everyone answers within the timeouts after its marker came, so twice that is enough for the whole cluster
+    let deadline = Instant::now() + (SNAPSHOT_TIMEOUT + HANDLERS_TIMEOUT) * 2;
+    while !all_reported(node) && Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    }
+    let reports = node.snapshot_reports.lock().unwrap().take().map(|collected| collected.reports).unwrap_or_default();
+    (id.clone(), assemble(node, &id, reports))
+}
+
+/// Called for every incoming request in the order it came on the connection, before it is handled.
+/// Markers are processed here, other messages are recorded if their channel is being recorded.
+/// While my state is being recorded the handler has to wait for the returned token, so what came
+/// after the cut doesn't get into the state.
+pub fn record_incoming(node: &Node, message_id: &str, message: &dyn Message) -> Option<CancellationToken> {
+    if let Some(marker) = message.as_any().downcast_ref::<MarkerMessage>() {
+        record_state(node, &marker.id, &marker.initiator, Some(&marker.from));
+        let (complete, held) = {
+            let mut snapshot = node.snapshot.lock().unwrap();
+            let snapshot = snapshot.as_mut().filter(|snapshot| snapshot.id == marker.id && !snapshot.done)?;
+            snapshot.closed.insert(marker.from.clone());
+            // before the state is recorded the recording task finishes it
+            let captured = snapshot.captured.is_cancelled();
+            (captured && channels_closed(node, snapshot), (!captured).then(|| snapshot.captured.clone()))
+        };
+        if complete {
+            let node_clone = node.clone();
+            let id = marker.id.clone();
+            tokio::spawn(async move {
+                finish_snapshot(&node_clone, &id).await;
+            });
+        }
+        return held;
+    }
+    let mut snapshot = node.snapshot.lock().unwrap();
+    let snapshot = snapshot.as_mut().filter(|snapshot| !snapshot.done)?;
+    // heartbeats, gossip and progress reports would only hide the interesting ones, the states are the snapshot itself
+    let recorded = !message.is_background() && !message.as_any().is::<SnapshotStateMessage>();
+    if recorded && !snapshot.closed.contains(message.from()) && snapshot.recorded.insert(message_id.to_string()) {
+        snapshot.channels.entry(message.from().to_string()).or_default().push(message.serialize());
+    }
+    (!snapshot.captured.is_cancelled()).then(|| snapshot.captured.clone())
+}
+
+/// Called for every response to one of my requests in the order it came on the connection. Responses that
+/// come after my state is recorded and before the marker of their channel were in flight at the cut.
+pub fn record_response(node: &Node, from: &str, request_id: u64, response: &dyn Message) {
+    let mut snapshot = node.snapshot.lock().unwrap();
+    let Some(snapshot) = snapshot.as_mut().filter(|snapshot| !snapshot.done && snapshot.captured.is_cancelled()) else {
+        return;
+    };
+    if !snapshot.closed.contains(from) && snapshot.recorded.insert(format!("RES {}", request_id)) {
+        snapshot.channels.entry(from.to_string()).or_default().push(format!("response {}", response.serialize()));
+    }
+}
+
+pub async fn handle_snapshot_state_message(node: &Node, _message: Box<dyn Message>) {
+    let state = _message.as_any().downcast_ref::<SnapshotStateMessage>().unwrap();
+    println!("Snapshot {}: got the state of {}", state.id, state.from);
+    add_report(node, &state.id, &state.from, state.friends.clone(), state.report.clone());
+}
+
+// my state is recorded once per snapshot, when the snapshot starts here or its first marker comes
+fn record_state(node: &Node, id: &str, initiator: &str, from: Option<&str>) {
+    {
+        let mut snapshot = node.snapshot.lock().unwrap();
+        if let Some(snapshot) = snapshot.as_ref() {
+            if snapshot.id == id {
+                return;
+            }
+            if !snapshot.done {
+                println!("Snapshot {} is still running, ignoring snapshot {}", snapshot.id, id);
+                return;
+            }
+        }
+        println!("Snapshot {}: recording my state", id);
+        *snapshot = Some(LocalSnapshot {
+            id: id.to_string(),
+            initiator: initiator.to_string(),
+            state: String::new(),
+            captured: CancellationToken::new(),
+            channels: HashMap::new(),
+            recorded: HashSet::new(),
+            closed: from.map(|from| from.to_string()).into_iter().collect(),
+            done: false,
+        });
+    }
+
+    let node_clone = node.clone();
+    let id = id.to_string();
+    let initiator = initiator.to_string();
+    tokio::spawn(async move {
+        capture_state(&node_clone, &id, &initiator).await;
+        // my only friend may have sent its marker already
+        let complete = node_clone.snapshot.lock().unwrap().as_ref()
+            .is_some_and(|snapshot| snapshot.id == id && channels_closed(&node_clone, snapshot));
+        if !complete {
+            tokio::time::sleep(SNAPSHOT_TIMEOUT).await;
+        }
+        finish_snapshot(&node_clone, &id).await;
+    });
+}
+
+// The cut: the handlers of what came before it finish first (what comes now waits for the token), then
+// my state is recorded and my markers are written while nothing else can be sent, so on every channel
+// whatever I send after the state comes after the marker.
+async fn capture_state(node: &Node, id: &str, initiator: &str) {
+    let drained = async {
+        while node.connections.active_handlers() > 0 {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    };
+    if timeout(HANDLERS_TIMEOUT, drained).await.is_err() {
+        println!("Snapshot {}: some handlers are still running, recording my state anyway", id);
+    }
+
+    let sends = node.connections.hold_sends().await;
+    let state = node.info();
+    let captured = {
+        let mut snapshot = node.snapshot.lock().unwrap();
+        let Some(snapshot) = snapshot.as_mut().filter(|snapshot| snapshot.id == id) else {
+            return;
+        };
+        snapshot.state = state;
+        snapshot.captured.clone()
+    };
+    let mut writes = JoinSet::new();
+    for address in friend_addresses(node) {
+        let node_clone = node.clone();
+        let marker = MarkerMessage {
+            from: node.address.clone(),
+            to: address,
+            id: id.to_string(),
+            initiator: initiator.to_string(),
+        };
+        writes.spawn(async move {
+            let message_id = node_clone.connections.new_message_id(&node_clone);
+            let sent = node_clone.connections.request_holding_sends(&node_clone, &marker.to, &message_id, &marker).await;
+            (marker.to, sent)
+        });
+    }
+    let mut sent = Vec::new();
+    while let Some(result) = writes.join_next().await {
+        match result {
+            Ok((_, Ok(request))) => sent.push(request),
+            Ok((address, Err(e))) => println!("Snapshot {}: marker to {} not sent: {}", id, address, e),
+            Err(e) => eprintln!("Send task failed: {}", e),
+        }
+    }
+    drop(sends);
+    captured.cancel();
+
+    for request in sent {
+        let _ = node.connections.wait_response(request).await;
+    }
+}
+
+fn channels_closed(node: &Node, snapshot: &LocalSnapshot) -> bool {
+    friend_addresses(node).iter().all(|address| snapshot.closed.contains(address))
+}
+
+// sends my part to the initiator, only once
+async fn finish_snapshot(node: &Node, id: &str) {
+    let (initiator, report) = {
+        let mut snapshot = node.snapshot.lock().unwrap();
+        let Some(snapshot) = snapshot.as_mut().filter(|snapshot| snapshot.id == id && !snapshot.done) else {
+            return;
+        };
+        snapshot.done = true;
+        (snapshot.initiator.clone(), report(node, snapshot))
+    };
+    println!("Snapshot {}: my part is done", id);
+    let friends = friend_addresses(node);
+    if initiator == node.address {
+        add_report(node, id, &node.address, friends, report);
+        return;
+    }
+    let message = SnapshotStateMessage {
+        from: node.address.clone(),
+        to: initiator.clone(),
+        id: id.to_string(),
+        friends,
+        report,
+    };
+    if send_message(&message, node).await.is_none() {
+        println!("Snapshot {}: initiator {} didn't get my state", id, initiator);
+    }
+}
+
+fn add_report(node: &Node, id: &str, address: &str, friends: Vec<String>, report: String) {
+    let mut collected = node.snapshot_reports.lock().unwrap();
+    if let Some(collected) = collected.as_mut().filter(|collected| collected.id == id) {
+        collected.reports.insert(address.to_string(), (friends, report));
+    }
+}
+
+// everyone I know of from the reports has reported
+fn all_reported(node: &Node) -> bool {
+    let collected = node.snapshot_reports.lock().unwrap();
+    let Some(collected) = collected.as_ref() else {
+        return true;
+    };
+    collected.reports.contains_key(&node.address)
+        && collected.reports.values().flat_map(|(friends, _)| friends).all(|address| collected.reports.contains_key(address))
+}
+
+fn report(node: &Node, snapshot: &LocalSnapshot) -> String {
+    let mut senders: HashSet<&String> = snapshot.channels.keys().chain(snapshot.closed.iter()).collect();
+    let friends = friend_addresses(node);
+    senders.extend(friends.iter());
+    let mut senders: Vec<&String> = senders.into_iter().collect();
+    senders.sort();
+
+    let mut output = format!("--- Node {} ---\n{}", node.address, snapshot.state);
+    for sender in senders {
+        let messages = snapshot.channels.get(sender).map_or(&[][..], |messages| messages.as_slice());
+        let closed = if snapshot.closed.contains(sender) { "" } else { " (no marker came, recorded until the timeout)" };
+        output.push_str(&format!("Channel from {}: {} messages in flight{}\n", sender, messages.len(), closed));
+        for message in messages {
+            output.push_str(&format!("  {}\n", message));
+        }
+    }
+    output
+}
+
+fn assemble(node: &Node, id: &str, reports: HashMap<String, (Vec<String>, String)>) -> String {
+    let mut addresses: Vec<&String> = reports.keys().collect();
+    addresses.sort();
+    let mut missing: Vec<&String> = reports.values()
+        .flat_map(|(friends, _)| friends)
+        .filter(|address| !reports.contains_key(*address))
+        .collect();
+    missing.sort();
+    missing.dedup();
+
+    let mut output = format!("=== Snapshot {} taken by {} ===\n", id, node.address);
+    output.push_str(&format!("Nodes: {:?}\n", addresses));
+    if !missing.is_empty() {
+        output.push_str(&format!("Missing (no state came): {:?}\n", missing));
+    }
+    for address in addresses {
+        output.push('\n');
+        output.push_str(&reports[address].1);
+    }
+    output
+}
+
+fn friend_addresses(node: &Node) -> Vec<String> {
+    let friends = node.friends.lock().unwrap();
+    friends.iter().map(|f| f.address.clone()).collect()
+}
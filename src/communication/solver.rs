@@ -0,0 +1,173 @@
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::Duration;
+
+use crate::messages::SolveResponseMessage;
+use crate::problem::{PartOfAProblem, PartOfAProblemState, Problem};
+use crate::utils::Node;
+use super::{handle_solve_response_message, request_work};
+use super::termination::check_passive;
+
+/// Queues a part for this node and makes sure the solver thread is running.
+/// Parts are brute forced one after another on a dedicated OS thread so the async runtime is never blocked.
+pub fn solve_own_part(node: &Node, mut part: PartOfAProblem) {
+    // updating leader parts state, it is mine now
+    part.state = PartOfAProblemState::Distributed;
+    node.update_leader_parts(&part);
+    let mut pending = node.pending_parts.lock().unwrap();
+    pending.push_back(part);
+    if node.solver_running.swap(true, Ordering::SeqCst) {
+        // the running solver picks it up when done with its current part
+        return;
+    }
+    drop(pending);
+    let node_clone = node.clone();
+    thread::spawn(move || run_solver(&node_clone));
+}
+
+fn run_solver(node: &Node) {
+    let mut finished = false;
+    loop {
+        let next = {
+            let mut pending = node.pending_parts.lock().unwrap();
+            match pending.pop_front() {
+                // stop clears the queue, so anything queued came after the last stop
+                Some(mut part) => {
+                    node.stop_flag.store(false, Ordering::SeqCst);
+                    // it is my current part before the queue is unlocked, the node is never seen without work in between
+                    part.state = PartOfAProblemState::Solving;
+                    let mut current = node.solving_part_of_a_problem.lock().unwrap();
+                    node.current_position.store(part.start, Ordering::SeqCst);
+                    node.current_end.store(part.end, Ordering::SeqCst);
+                    current.replace(part.clone());
+                    part
+                }
+                None => {
+                    node.solver_running.store(false, Ordering::SeqCst);
+                    node.stop_flag.store(true, Ordering::SeqCst);
+                    break;
+                }
+            }
+        };
+        let job_id = next.job_id.clone();
+        finished = solve_part(node, next);
+        // the last part of the job here, my parent (or the leader itself) may be waiting for it
+        node.runtime.block_on(check_passive(node, &job_id));
+    }
+    // out of work in the middle of a job, take some from the others
+    if finished && !node.jobs.lock().unwrap().is_empty() {
+        let node_clone = node.clone();
+        node.runtime.spawn(async move {
+            request_work(&node_clone).await;
+        });
+    }
+}
+
+/// The result goes through `handle_solve_response_message` - the leader processes it, a worker forwards it to its parent.
+/// The part is searched in slices of `share * slice_size` candidates. When a part of another job waits after a slice,
+/// the searched beginning is reported and the rest goes to the back of the queue.
+/// Returns false if the search was stopped.
+fn solve_part(node: &Node, mut problem_part: PartOfAProblem) -> bool {
+    let Some(job) = node.jobs.lock().unwrap().get(&problem_part.job_id).cloned() else {
+        eprintln!("Unknown job {}, can't solve my part", problem_part.job_id);
+        *node.solving_part_of_a_problem.lock().unwrap() = None;
+        return false;
+    };
+    // updating leader parts state
+    node.update_leader_parts(&problem_part);
+    println!("Started solving part {:?}", problem_part);
+
+    let mut problem = Problem::new_from_part(&job, &problem_part);
+    let slice = (job.share.max(1) as usize).saturating_mul(node.config.slice_size.max(1));
+    let solution = loop {
+        let from = problem.current;
+        let solution = problem.brute_force(&node.stop_flag, &node.paused, &node.current_position, &node.current_end, slice);
+        node.searched.fetch_add(problem.current - from, Ordering::SeqCst);
+        let done = solution.is_some()
+            || node.stop_flag.load(Ordering::SeqCst)
+            || node.current_position.load(Ordering::SeqCst) > node.current_end.load(Ordering::SeqCst);
+        if done {
+            break solution;
+        }
+        if node.paused.load(Ordering::SeqCst) {
+            // the part stays mine, the search goes on from problem.current after resume
+            println!("Paused at {} in part {:?}", problem.current, problem_part);
+            wait_while_paused(node);
+            continue;
+        }
+        if other_job_waiting(node, &job.id) {
+            break solution;
+        }
+    };
+    let stopped = solution.is_none() && node.stop_flag.load(Ordering::SeqCst);
+    let is_leader = node.is_leader();
+    // what is left of the part when the solver gives way to another job
+    let mut rest = None;
+    let searched_end = {
+        // the end might have moved when a part of the range was given away,
+        // it is read under the lock the split holds, and after this the part can't be split anymore.
+        // The queue is locked first, the rest is in it before the part stops being my current one
+        let mut pending = node.pending_parts.lock().unwrap();
+        let mut current = node.solving_part_of_a_problem.lock().unwrap();
+        let end = node.current_end.load(Ordering::SeqCst);
+        let position = node.current_position.load(Ordering::SeqCst).min(end + 1);
+        if solution.is_none() && position <= end && !stopped {
+            // still under the lease of the part
+            rest = Some(PartOfAProblem { lease: problem_part.lease, ..PartOfAProblem::new(&problem_part.job_id, position, end) });
+        }
+        let searched_end = if solution.is_some() {
+            Some(end)
+        } else {
+            position.checked_sub(1).filter(|last| *last >= problem_part.start)
+        };
+        if !stopped {
+            match (current.as_mut(), searched_end) {
+                (Some(part), Some(searched_end)) => {
+                    part.end = searched_end;
+                    part.state = PartOfAProblemState::SearchedAndNotFound;
+                }
+                _ => *current = None,
+            }
+        } else if !is_leader {
+            // not needed anymore - found elsewhere or calculations stopped...
+            *current = None;
+        }
+        // a stop meanwhile drops it like the rest of the queue
+        if let Some(rest) = rest.as_ref().filter(|_| !node.stop_flag.load(Ordering::SeqCst)) {
+            pending.push_back(rest.clone());
+        }
+        searched_end
+    };
+    match &solution {
+        Some(solution) => println!("Solution found: {}", solution),
+        None if stopped => println!("Stopped in my part, searched until {:?}", searched_end),
+        None if rest.is_some() => println!("Giving way to another job, searched until {:?}", searched_end),
+        None => println!("No solution found in my part."),
+    }
+    // what was searched is reported even when stopped - a paused job keeps it
+    if let Some(searched_end) = searched_end {
+        problem_part.end = searched_end;
+        let response = SolveResponseMessage {
+            from: node.address.clone(),
+            to: node.address.clone(),
+            job_id: problem_part.job_id.clone(),
+            start: problem_part.start,
+            end: problem_part.end,
+            solution,
+            space_searched: true,
+        };
+        node.runtime.block_on(handle_solve_response_message(node, Box::new(response)));
+    }
+    !stopped
+}
+
+// a stop ends the pause too
+fn wait_while_paused(node: &Node) {
+    while node.paused.load(Ordering::SeqCst) && !node.stop_flag.load(Ordering::SeqCst) {
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+fn other_job_waiting(node: &Node, job_id: &str) -> bool {
+    node.pending_parts.lock().unwrap().iter().any(|part| part.job_id != job_id)
+}
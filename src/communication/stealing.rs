@@ -0,0 +1,269 @@
+use std::sync::atomic::Ordering;
+
+use crate::messages::{send_message, DonateMessage, Message, SolveResponseMessage, SplitMessage, SplitResponseMessage, WorkRequestMessage};
+use crate::problem::{Combinable, PartOfAProblem, PartOfAProblemState};
+use crate::utils::Node;
+use super::{handle_request, solve_own_part};
+use super::leases::{grant_lease, is_leasing};
+use super::termination::{work_handled, work_not_delivered, work_received, work_sent};
+
+// where the largest piece of a range is searched
+enum Holder {
+    Me,
+    Pending(usize),
+    Child(String),
+}
+
+/// Called when my solver ran out of parts in the middle of a job. The request goes up to the leader,
+/// which takes half of the largest range still being searched and sends it back down the same path.
+pub async fn request_work(node: &Node) {
+    if node.is_leader() {
+        steal_for(node, Vec::new()).await;
+        return;
+    }
+    let Some(parent) = node.parent_address() else {
+        return;
+    };
+    println!("Out of work, asking {} for more", parent);
+    let request = WorkRequestMessage {
+        from: node.address.clone(),
+        to: parent,
+        path: vec![node.address.clone()],
+    };
+    send_message(&request, node).await;
+}
+
+// acked right away, splitting may need a few round trips down the tree
+pub fn handle_work_request(node: &Node, _message: Box<dyn Message>) {
+    let request = _message.as_any().downcast_ref::<WorkRequestMessage>().unwrap().clone();
+    let node = node.clone();
+    tokio::spawn(async move {
+        forward_work_request(&node, request).await;
+    });
+}
+
+async fn forward_work_request(node: &Node, request: WorkRequestMessage) {
+    if node.is_leader() {
+        steal_for(node, request.path).await;
+        return;
+    }
+    let Some(parent) = node.parent_address() else {
+        if node.is_electing() {
+            println!("No parent until the election ends, keeping the work request of {:?}", request.path);
+            node.undelivered.lock().unwrap().push(request.serialize());
+        } else {
+            eprintln!("No parent to forward the work request of {:?} to", request.path);
+        }
+        return;
+    };
+    let mut forward = request;
+    forward.from = node.address.clone();
+    forward.to = parent;
+    forward.path.push(node.address.clone());
+    send_message(&forward, node).await;
+}
+
+// The leader's part - running jobs are tried by priority. Path goes from the idle node up to my child,
+// empty if I am the idle one. If no job has anything to give, the node waits until something comes up.
+pub async fn steal_for(node: &Node, path: Vec<String>) {
+    let running = node.state.lock().unwrap().running_jobs();
+    if running.is_empty() {
+        return;
+    }
+    let requester = path.first().cloned().unwrap_or_else(|| node.address.clone());
+    // nothing is handed out while the tree is paused, the node is served after resume
+    let paused = node.paused.load(Ordering::SeqCst);
+    for job in running.into_iter().filter(|_| !paused) {
+        // jobs with a chunk size are leased, nothing is split
+        if is_leasing(node, &job.id) {
+            if grant_lease(node, &job.id, path.clone()).await {
+                return;
+            }
+            continue;
+        }
+        let free = first_not_distributed(node, &job.id);
+        let donated = match free {
+            Some(free) => Some(free),
+            None => split_largest(node, &job.whole_part()).await,
+        };
+        if let Some(mut donated) = donated {
+            println!("Giving {:?} to {}", donated, requester);
+            donated.state = PartOfAProblemState::Distributed;
+            node.update_leader_parts(&donated);
+            let route = path.into_iter().rev().collect();
+            deliver(node, donated, route).await;
+            return;
+        }
+    }
+    println!("Nothing to give to {}, it waits", requester);
+    let mut waiting = node.work_waiting.lock().unwrap();
+    if !waiting.contains(&path) {
+        waiting.push(path);
+    }
+}
+
+// a range nobody searches (nobody could take it when it came back), given as it is
+fn first_not_distributed(node: &Node, job_id: &str) -> Option<PartOfAProblem> {
+    let state = node.state.lock().unwrap();
+    state.leader_job(job_id)?.parts.iter()
+        .find(|part| part.state == PartOfAProblemState::NotDistributed)
+        .cloned()
+}
+
+pub async fn handle_split_message(node: &Node, _message: Box<dyn Message>) -> Box<dyn Message> {
+    let split = _message.as_any().downcast_ref::<SplitMessage>().unwrap();
+    let within = PartOfAProblem::new(&split.job_id, split.start, split.end);
+    let donated = split_largest(node, &within).await;
+    Box::new(SplitResponseMessage {
+        from: node.address.clone(),
+        to: split.from.clone(),
+        donated,
+    })
+}
+
+pub async fn handle_donate_message(node: &Node, _message: Box<dyn Message>) {
+    let donate = _message.as_any().downcast_ref::<DonateMessage>().unwrap();
+    let part = PartOfAProblem { lease: donate.lease, ..PartOfAProblem::new(&donate.job_id, donate.start, donate.end) };
+    work_received(node, &donate.job_id, &donate.from);
+    deliver(node, part, donate.route.clone()).await;
+    work_handled(node, &donate.job_id).await;
+}
+
+// passes the stolen range to the next hop, the last one searches it
+pub async fn deliver(node: &Node, mut part: PartOfAProblem, mut route: Vec<String>) {
+    if route.is_empty() {
+        println!("Got stolen range {:?}", part);
+        solve_own_part(node, part);
+        return;
+    }
+    let next = route.remove(0);
+    // recorded like any other assigned range, so it is given back if the child dies
+    part.state = PartOfAProblemState::Distributed;
+    {
+        let mut friends = node.friends.lock().unwrap();
+        if let Some(friend) = friends.iter_mut().find(|f| f.address() == next && f.is_child()) {
+            friend.assigned_parts.push(part.clone());
+        }
+    }
+    let donate = DonateMessage {
+        from: node.address.clone(),
+        to: next.clone(),
+        job_id: part.job_id.clone(),
+        start: part.start,
+        end: part.end,
+        route,
+        lease: part.lease,
+    };
+    work_sent(node, &part.job_id, &next);
+    if send_message(&donate, node).await.is_none() {
+        // the node on the way is gone (left or died), the range must not be lost with it
+        println!("Could not pass {:?} to {}, giving it back", part, next);
+        work_not_delivered(node, &part.job_id, &next);
+        node.release_child_part(&next, &part);
+        let lost = SolveResponseMessage {
+            from: node.address.clone(),
+            to: node.address.clone(),
+            job_id: part.job_id.clone(),
+            start: part.start,
+            end: part.end,
+            solution: None,
+            space_searched: false,
+        };
+        // boxed, handling the response may deliver again
+        handle_request(node.clone(), Box::new(lost)).await;
+    }
+}
+
+// takes half of the largest piece of `within` searched by me or my subtree
+async fn split_largest(node: &Node, within: &PartOfAProblem) -> Option<PartOfAProblem> {
+    let mut best: Option<(usize, Holder, PartOfAProblem)> = None;
+    let mut consider = |size: usize, holder: Holder, piece: PartOfAProblem| {
+        if size >= node.config.steal_min_size && best.as_ref().is_none_or(|(best_size, _, _)| size > *best_size) {
+            best = Some((size, holder, piece));
+        }
+    };
+    if let Some(part) = node.solving_part_of_a_problem.lock().unwrap().as_ref()
+        && part.state == PartOfAProblemState::Solving && !node.stop_flag.load(Ordering::SeqCst) {
+        let position = node.current_position.load(Ordering::SeqCst);
+        let end = node.current_end.load(Ordering::SeqCst);
+        if let Some(piece) = intersect(&PartOfAProblem::new(&part.job_id, position, end), within) {
+            consider(piece.total_combinations(), Holder::Me, piece);
+        }
+    }
+    for (index, part) in node.pending_parts.lock().unwrap().iter().enumerate() {
+        if part.job_id == within.job_id && part.start >= within.start && part.end <= within.end {
+            consider(part.total_combinations(), Holder::Pending(index), part.clone());
+        }
+    }
+    for friend in node.friends.lock().unwrap().iter().filter(|f| f.is_child()) {
+        for part in friend.assigned_parts.iter() {
+            if let Some(piece) = intersect(part, within) {
+                consider(piece.total_combinations(), Holder::Child(friend.address.clone()), piece);
+            }
+        }
+    }
+
+    let (_, holder, piece) = best?;
+    match holder {
+        Holder::Me => split_own_part(node),
+        Holder::Pending(index) => split_pending_part(node, index, &piece),
+        Holder::Child(address) => {
+            let split = SplitMessage {
+                from: node.address.clone(),
+                to: address.clone(),
+                job_id: piece.job_id.clone(),
+                start: piece.start,
+                end: piece.end,
+            };
+            let response = send_message(&split, node).await?;
+            let donated = response.as_any().downcast_ref::<SplitResponseMessage>()?.donated.clone()?;
+            // the child's subtree doesn't search it anymore
+            node.release_child_part(&address, &donated);
+            Some(donated)
+        }
+    }
+}
+
+// Cuts the rest of my current search in half and gives away the upper half.
+// The solver publishes its position before checking the end, so after lowering the end
+// it never searches past max(position read afterwards, new end) - that is where the given away half starts.
+fn split_own_part(node: &Node) -> Option<PartOfAProblem> {
+    let mut current = node.solving_part_of_a_problem.lock().unwrap();
+    let part = current.as_mut().filter(|part| part.state == PartOfAProblemState::Solving)?;
+    let end = node.current_end.load(Ordering::SeqCst);
+    let position = node.current_position.load(Ordering::SeqCst);
+    if position >= end {
+        return None;
+    }
+    let middle = position + (end - position).div_ceil(2);
+    node.current_end.store(middle - 1, Ordering::SeqCst);
+    let donated_start = middle.max(node.current_position.load(Ordering::SeqCst));
+    if donated_start > end {
+        node.current_end.store(end, Ordering::SeqCst);
+        return None;
+    }
+    node.current_end.store(donated_start - 1, Ordering::SeqCst);
+    part.end = donated_start - 1;
+    println!("Giving away {}..={} of my part, keeping {:?}", donated_start, end, part);
+    Some(PartOfAProblem::new(&part.job_id, donated_start, end))
+}
+
+// a queued part wasn't started yet, its upper half can go as it is
+fn split_pending_part(node: &Node, index: usize, piece: &PartOfAProblem) -> Option<PartOfAProblem> {
+    let mut pending = node.pending_parts.lock().unwrap();
+    let part = pending.get_mut(index).filter(|part| part.start == piece.start && part.end == piece.end)?;
+    if part.start == part.end {
+        return pending.remove(index);
+    }
+    let middle = part.start + (part.end - part.start).div_ceil(2);
+    let donated = PartOfAProblem::new(&part.job_id, middle, part.end);
+    part.end = middle - 1;
+    Some(donated)
+}
+
+fn intersect(part: &PartOfAProblem, within: &PartOfAProblem) -> Option<PartOfAProblem> {
+    if part.job_id != within.job_id || part.end < within.start || part.start > within.end || part.start > part.end {
+        return None;
+    }
+    Some(PartOfAProblem::new(&part.job_id, part.start.max(within.start), part.end.min(within.end)))
+}
@@ -0,0 +1,407 @@
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use crate::messages::{send_message, Message, SignalMessage};
+use crate::problem::{PartOfAProblem, PartOfAProblemState};
+use crate::utils::{Engagement, JobStatus, Node};
+use super::{distribute_part, end_job};
+use super::leases::{give_back, is_leasing, serve_waiting};
+
+// Termination detection of a job, Dijkstra-Scholten over the work tree. Every range sent to another node
+// (SOLVE, DONATE) is counted in my deficit until the receiver signals it back. A node that gets work while it
+// is not engaged in the job is engaged by the sender, any other work is signaled back right away. Once the node
+// has nothing of the job to search and its deficit is zero, it signals the one that engaged it and is free again.
+// When that happens on the leader, everything sent out is done - and the results came before the signals.
+
+/// Called before a range of the job is sent to `to`.
+pub fn work_sent(node: &Node, job_id: &str, to: &str) {
+    // only the leader sends work without being engaged, anyone else passes on what engaged it
+    let parent = if node.is_leader() { None } else { node.parent_address() };
+    let mut engagements = node.engagements.lock().unwrap();
+    let engagement = engagements.entry(job_id.to_string()).or_insert_with(|| Engagement {
+        parent,
+        ..Default::default()
+    });
+    *engagement.deficit.entry(to.to_string()).or_default() += 1;
+}
+
+/// The range didn't get to `to` and was given back, nothing to wait for.
+pub fn work_not_delivered(node: &Node, job_id: &str, to: &str) {
+    let mut engagements = node.engagements.lock().unwrap();
+    if let Some(engagement) = engagements.get_mut(job_id) {
+        take_deficit(engagement, to);
+    }
+}
+
+/// Called when a range of the job comes from `from`, before it is handled. `work_handled` must follow.
+pub fn work_received(node: &Node, job_id: &str, from: &str) {
+    let leader = node.is_leader();
+    let engaged = {
+        let mut engagements = node.engagements.lock().unwrap();
+        let engaged = leader || engagements.contains_key(job_id);
+        let engagement = engagements.entry(job_id.to_string()).or_insert_with(|| Engagement {
+            parent: (!leader).then(|| from.to_string()),
+            ..Default::default()
+        });
+        // I am not passive until the work is queued or passed on
+        *engagement.deficit.entry(node.address.clone()).or_default() += 1;
+        engaged
+    };
+    if engaged {
+        let node = node.clone();
+        let job_id = job_id.to_string();
+        let from = from.to_string();
+        tokio::spawn(async move {
+            send_signal(&node, &job_id, &from).await;
+        });
+    } else {
+        println!("Engaged in job {} by {}", job_id, from);
+    }
+}
+
+pub async fn work_handled(node: &Node, job_id: &str) {
+    {
+        let mut engagements = node.engagements.lock().unwrap();
+        if let Some(engagement) = engagements.get_mut(job_id) {
+            take_deficit(engagement, &node.address);
+        }
+    }
+    check_passive(node, job_id).await;
+}
+
+pub async fn handle_signal_message(node: &Node, _message: Box<dyn Message>) {
+    let signal = _message.as_any().downcast_ref::<SignalMessage>().unwrap();
+    {
+        let mut engagements = node.engagements.lock().unwrap();
+        let Some(engagement) = engagements.get_mut(&signal.job_id) else {
+            // the job was stopped or the tree rebuilt meanwhile
+            return;
+        };
+        take_deficit(engagement, &signal.from);
+    }
+    println!("{} is done with its work of job {}", signal.from, signal.job_id);
+    check_passive(node, &signal.job_id).await;
+}
+
+/// A dead or departed child signals nothing anymore, what it held was given back already.
+pub async fn forget_holder(node: &Node, address: &str) {
+    let job_ids: Vec<String> = {
+        let mut engagements = node.engagements.lock().unwrap();
+        engagements.iter_mut()
+            .filter_map(|(job_id, engagement)| engagement.deficit.remove(address).map(|_| job_id.clone()))
+            .collect()
+    };
+    for job_id in job_ids {
+        check_passive(node, &job_id).await;
+    }
+}
+
+/// A node took over ranges of a job from somebody else (an election echo, adopting a child of a leaving node),
+/// it is counted as if I sent them.
+pub fn adopt_holder(node: &Node, holder: &str, parts: &[PartOfAProblem]) {
+    let mut engagements = node.engagements.lock().unwrap();
+    for part in parts.iter().filter(|part| part.state != PartOfAProblemState::SearchedAndNotFound) {
+        let engagement = engagements.entry(part.job_id.clone()).or_default();
+        engagement.deficit.insert(holder.to_string(), 1);
+    }
+}
+
+/// After an election my echo told the parent what my subtree holds, those jobs are engaged by it now.
+pub fn engage_to(node: &Node, parent: Option<&str>, parts: &[PartOfAProblem]) {
+    let mut engagements = node.engagements.lock().unwrap();
+    for part in parts.iter().filter(|part| part.state == PartOfAProblemState::Distributed) {
+        engagements.entry(part.job_id.clone()).or_default().parent = parent.map(|parent| parent.to_string());
+    }
+}
+
+/// My parent is leaving, the engagements go over to the new one (which adopts me with what I hold).
+pub fn change_parent(node: &Node, old: &str, new: &str) {
+    let mut engagements = node.engagements.lock().unwrap();
+    for engagement in engagements.values_mut().filter(|engagement| engagement.parent.as_deref() == Some(old)) {
+        engagement.parent = Some(new.to_string());
+    }
+}
+
+pub fn forget_job(node: &Node, job_id: &str) {
+    node.engagements.lock().unwrap().remove(job_id);
+}
+
+pub fn forget_all(node: &Node) {
+    node.engagements.lock().unwrap().clear();
+}
+
+/// Checks every job, e.g. after the solver was busy with one of them or signals were held back by an election.
+pub async fn check_all(node: &Node) {
+    let job_ids: Vec<String> = node.jobs.lock().unwrap().keys().cloned().collect();
+    for job_id in job_ids {
+        check_passive(node, &job_id).await;
+    }
+}
+
+/// Signals the parent (or finds out the job's work is over on the leader) if I have nothing of the job left.
+pub async fn check_passive(node: &Node, job_id: &str) {
+    // signals are held back until the new tree is built, it re-engages everyone with what they hold
+    if node.is_electing() {
+        return;
+    }
+    // a leaving node gives back what it holds, its parent forgets it then
+    if node.leaving.load(Ordering::SeqCst) {
+        return;
+    }
+    let leader = node.is_leader();
+    // a paused or queued job is not searched, a finished one is forgotten
+    if leader && !node.state.lock().unwrap().leader_job(job_id).is_some_and(|job| job.status == JobStatus::Running) {
+        return;
+    }
+    loop {
+        let parent = {
+            let mut engagements = node.engagements.lock().unwrap();
+            let waiting = engagements.get(job_id).is_some_and(|engagement| engagement.deficit.values().any(|count| *count > 0));
+            if waiting || has_own_work(node, job_id) {
+                return;
+            }
+            if leader {
+                // the ranges being handed out again count as sent to myself, a signal meanwhile doesn't end it twice
+                let engagement = engagements.entry(job_id.to_string()).or_default();
+                engagement.parent = None;
+                engagement.deficit.insert(node.address.clone(), 1);
+                None
+            } else {
+                match engagements.get(job_id).map(|engagement| engagement.parent.clone()) {
+                    // engaged by an echo before my own echo went up, the election finishes it
+                    Some(None) => return,
+                    Some(Some(parent)) => {
+                        engagements.remove(job_id);
+                        Some(parent)
+                    }
+                    None => return,
+                }
+            }
+        };
+        if let Some(parent) = parent {
+            println!("Done with my work of job {}, signaling {}", job_id, parent);
+            send_signal(node, job_id, &parent).await;
+            return;
+        }
+        let handed_out = work_done(node, job_id).await;
+        {
+            let mut engagements = node.engagements.lock().unwrap();
+            if let Some(engagement) = engagements.get_mut(job_id) {
+                take_deficit(engagement, &node.address);
+            }
+        }
+        // what was handed out may be done already, its signal found me busy
+        if !handed_out {
+            return;
+        }
+    }
+}
+
+// Leader only - nothing of the job is searched anywhere. A fully searched map means there is no solution,
+// anything else was lost on the way (a response that never came, a holder that vanished) and is handed out again.
+// Returns true if something was.
+async fn work_done(node: &Node, job_id: &str) -> bool {
+    let leasing = is_leasing(node, job_id);
+    let (job, unsearched) = {
+        let state = node.state.lock().unwrap();
+        let Some(leader_job) = state.leader_job(job_id).filter(|job| job.status == JobStatus::Running) else {
+            return false;
+        };
+        let unsearched: Vec<PartOfAProblem> = leader_job.parts.iter()
+            .filter(|part| part.state != PartOfAProblemState::SearchedAndNotFound)
+            .cloned()
+            .collect();
+        (leader_job.problem.job.clone(), unsearched)
+    };
+    let searched_all = unsearched.is_empty();
+    if searched_all {
+        println!("All work of job {} is done and no solution found. Problem is unsolvable.", job.id);
+        let node = node.clone();
+        node.runtime.clone().spawn(async move {
+            end_job(&node, &job.id).await;
+        });
+        return false;
+    }
+    // free chunks of a leased job wait for the next worker that asks
+    let lost: Vec<PartOfAProblem> = unsearched.into_iter()
+        .filter(|part| !leasing || part.state != PartOfAProblemState::NotDistributed)
+        .collect();
+    if lost.is_empty() {
+        // leased chunks are left, the idle nodes that asked get them
+        serve_waiting(node).await;
+        return false;
+    }
+    for part in lost {
+        println!("All work of job {} is done but {:?} was never reported, searching it again", job.id, part);
+        let part = PartOfAProblem { state: PartOfAProblemState::NotDistributed, ..part };
+        node.update_leader_parts(&part);
+        if leasing {
+            give_back(node, &part).await;
+        } else {
+            distribute_part(node, &job, &part).await;
+        }
+    }
+    true
+}
+
+// my own search of the job, queued or running - the queue is locked first like everywhere else
+fn has_own_work(node: &Node, job_id: &str) -> bool {
+    let pending = node.pending_parts.lock().unwrap();
+    if pending.iter().any(|part| part.job_id == job_id) {
+        return true;
+    }
+    let current = node.solving_part_of_a_problem.lock().unwrap();
+    node.solver_running.load(Ordering::SeqCst)
+        && current.as_ref().is_some_and(|part| part.job_id == job_id && part.state == PartOfAProblemState::Solving)
+}
+
+fn take_deficit(engagement: &mut Engagement, address: &str) {
+    if let Some(count) = engagement.deficit.get_mut(address) {
+        *count = count.saturating_sub(1);
+        if *count == 0 {
+            engagement.deficit.remove(address);
+        }
+    }
+}
+
+// A lost signal would keep the leader waiting for good, it is sent until it gets through or the receiver is gone
+// (it is then given up on by heartbeats, which ends its wait for me too). A signal that gets counted twice can only
+// end the wait early - the leader then hands out again what it has no result for, it never misses a range.
+async fn send_signal(node: &Node, job_id: &str, to: &str) {
+    let signal = SignalMessage {
+        from: node.address.clone(),
+        to: to.to_string(),
+        job_id: job_id.to_string(),
+    };
+    while send_message(&signal, node).await.is_none() {
+        if !node.is_friend(to) || node.shutdown.is_cancelled() {
+            println!("{} is gone, dropping my signal of job {}", to, job_id);
+            return;
+        }
+        println!("{} didn't get my signal of job {}, trying again", to, job_id);
+        tokio::time::sleep(Duration::from_millis(node.config.heartbeat_interval_ms)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::SignalMessage;
+    use crate::problem::{Job, Problem};
+    use crate::utils::{LeaderJob, NodeConfig, NodeState};
+    use super::super::leases::start_leasing;
+
+    const JOB: &str = "127.0.0.1:47100#1";
+    // nobody listens there, signals to it are dropped right away
+    const PARENT: &str = "127.0.0.1:9";
+
+    fn node() -> Node {
+        let config = NodeConfig { retries: 0, ..Default::default() };
+        Node::new("127.0.0.1:47101".to_string(), Vec::new(), config)
+    }
+
+    fn deficit(node: &Node, address: &str) -> u32 {
+        node.engagements.lock().unwrap().get(JOB).and_then(|engagement| engagement.deficit.get(address).copied()).unwrap_or(0)
+    }
+
+    fn signal(from: &str) -> Box<dyn Message> {
+        Box::new(SignalMessage {
+            from: from.to_string(),
+            to: "127.0.0.1:47101".to_string(),
+            job_id: JOB.to_string(),
+        })
+    }
+
+    #[tokio::test]
+    async fn deficit_counts_work_until_it_is_signaled_back() {
+        let node = node();
+        work_received(&node, JOB, PARENT);
+        assert_eq!(node.engagements.lock().unwrap()[JOB].parent.as_deref(), Some(PARENT));
+        work_sent(&node, JOB, "127.0.0.1:47102");
+        work_sent(&node, JOB, "127.0.0.1:47102");
+        work_sent(&node, JOB, "127.0.0.1:47103");
+        work_handled(&node, JOB).await;
+        assert_eq!((deficit(&node, &node.address), deficit(&node, "127.0.0.1:47102")), (0, 2));
+
+        handle_signal_message(&node, signal("127.0.0.1:47102")).await;
+        handle_signal_message(&node, signal("127.0.0.1:47103")).await;
+        assert_eq!(deficit(&node, "127.0.0.1:47102"), 1);
+        // the last signal makes me passive, I signal my parent and am free again
+        handle_signal_message(&node, signal("127.0.0.1:47102")).await;
+        assert!(!node.engagements.lock().unwrap().contains_key(JOB));
+    }
+
+    #[tokio::test]
+    async fn engaged_node_keeps_its_parent() {
+        let node = node();
+        work_received(&node, JOB, PARENT);
+        // signaled back right away, only the first sender is waited for
+        work_received(&node, JOB, "127.0.0.1:47104");
+        assert_eq!(node.engagements.lock().unwrap()[JOB].parent.as_deref(), Some(PARENT));
+        assert_eq!(deficit(&node, &node.address), 2);
+        work_handled(&node, JOB).await;
+        assert!(node.engagements.lock().unwrap().contains_key(JOB));
+        work_handled(&node, JOB).await;
+        assert!(!node.engagements.lock().unwrap().contains_key(JOB));
+    }
+
+    #[tokio::test]
+    async fn undelivered_work_and_gone_holders_are_not_waited_for() {
+        let node = node();
+        work_received(&node, JOB, PARENT);
+        work_sent(&node, JOB, "127.0.0.1:47102");
+        work_sent(&node, JOB, "127.0.0.1:47103");
+        work_handled(&node, JOB).await;
+        work_not_delivered(&node, JOB, "127.0.0.1:47102");
+        assert!(node.engagements.lock().unwrap().contains_key(JOB));
+        forget_holder(&node, "127.0.0.1:47103").await;
+        assert!(!node.engagements.lock().unwrap().contains_key(JOB));
+    }
+
+    #[tokio::test]
+    async fn leaving_node_holds_its_signal() {
+        let node = node();
+        work_received(&node, JOB, PARENT);
+        node.leaving.store(true, Ordering::SeqCst);
+        work_handled(&node, JOB).await;
+        assert!(node.engagements.lock().unwrap().contains_key(JOB));
+    }
+
+    #[tokio::test]
+    async fn leader_hands_out_ranges_never_reported() {
+        let node = node();
+        let job = Job {
+            id: JOB.to_string(),
+            algorithm: "sha256".to_string(),
+            alphabet: "ab".to_string(),
+            min_len: 1,
+            max_len: 4,
+            targets: Vec::new(),
+            chunk_size: 5,
+            priority: 0,
+            share: 1,
+        };
+        let mut parts = vec![
+            PartOfAProblem::new(JOB, 0, 9),
+            PartOfAProblem::new(JOB, 10, 19),
+            PartOfAProblem::new(JOB, 20, 29),
+        ];
+        parts[0].state = PartOfAProblemState::SearchedAndNotFound;
+        parts[1].state = PartOfAProblemState::Distributed;
+        parts[2].state = PartOfAProblemState::SearchedAndNotFound;
+        *node.state.lock().unwrap() = NodeState::LEADER {
+            jobs: vec![LeaderJob { problem: Problem::new(job.clone()), parts, status: JobStatus::Running }],
+        };
+        start_leasing(&node, &job, Vec::new());
+
+        // nothing is out there anymore, yet 10..=19 has no result
+        check_passive(&node, JOB).await;
+        let free: Vec<(usize, usize)> = node.leases.lock().unwrap()[JOB].free.iter().map(|part| (part.start, part.end)).collect();
+        assert_eq!(free, vec![(10, 19)]);
+        // and the job goes on until it is searched
+        let state = node.state.lock().unwrap();
+        let leader_job = state.leader_job(JOB).unwrap();
+        assert_eq!(leader_job.status, JobStatus::Running);
+        assert!(leader_job.parts.iter().any(|part| (part.start, part.end, &part.state) == (10, 19, &PartOfAProblemState::NotDistributed)));
+    }
+}
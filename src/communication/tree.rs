@@ -0,0 +1,182 @@
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+use crate::messages::{send_message, AckMessage, Message, TreeMessage, TreeResponseMessage};
+use crate::problem::PartOfAProblemState;
+use crate::utils::{Node, NodeState, TreeCollection, TreeEntry};
+use super::fan_out;
+
+// time the whole tree has to answer, every level gets less like with CALC
+const TREE_BUDGET_MS: u64 = 10000;
+const TREE_LEVEL_MARGIN_MS: u64 = 500;
+const TREE_MIN_BUDGET_MS: u64 = 500;
+
+/// Snapshot of the tree below me, an echo like the power calculation: every node asks its children,
+/// they answer with TREE_RESPONSE once their own subtree answered or their budget ran out.
+/// A child that doesn't answer in time is in it too, as UNREACHABLE, without its subtree.
+pub async fn collect_tree(node: &Node) -> Vec<TreeEntry> {
+    let id = node.new_wave_id();
+    let node_clone = node.clone();
+    let id_clone = id.clone();
+    // its sends may outlive the deadline, the result is ready by then anyway
+    tokio::spawn(async move {
+        start_tree_collection(&node_clone, &id_clone, None, TREE_BUDGET_MS).await;
+    });
+    let deadline = Instant::now() + Duration::from_millis(TREE_BUDGET_MS);
+    while Instant::now() < deadline {
+        {
+            let mut collection = node.tree_collection.lock().unwrap();
+            if collection.as_ref().is_some_and(|collection| collection.id == id && collection.done) {
+                return collection.take().map(|collection| collection.entries).unwrap_or_default();
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    // another collection took over mine
+    Vec::new()
+}
+
+// answered with an ACK right away, the subtree is collected meanwhile
+pub fn handle_tree_message(node: &Node, _message: Box<dyn Message>) {
+    let tree = _message.as_any().downcast_ref::<TreeMessage>().unwrap();
+    let known = node.tree_collection.lock().unwrap().as_ref().is_some_and(|collection| collection.id == tree.id);
+    if known {
+        return;
+    }
+    let node = node.clone();
+    let (id, parent, budget_ms) = (tree.id.clone(), tree.from.clone(), tree.budget_ms);
+    tokio::spawn(async move {
+        start_tree_collection(&node, &id, Some(parent), budget_ms).await;
+    });
+}
+
+pub async fn handle_tree_response(node: &Node, _message: Box<dyn Message>) {
+    let response = _message.as_any().downcast_ref::<TreeResponseMessage>().unwrap();
+    {
+        let mut collection = node.tree_collection.lock().unwrap();
+        let Some(collection) = collection.as_mut()
+            .filter(|collection| collection.id == response.id && !collection.done) else {
+            println!("Late tree of {}, ignoring it", response.from);
+            return;
+        };
+        if collection.waiting.remove(&response.from).is_none() {
+            return;
+        }
+        collection.entries.extend(response.entries.iter().cloned());
+    }
+    try_finish_tree_collection(node, &response.id).await;
+}
+
+async fn start_tree_collection(node: &Node, id: &str, parent: Option<String>, budget_ms: u64) {
+    let children_budget_ms = budget_ms.saturating_sub(TREE_LEVEL_MARGIN_MS).max(TREE_MIN_BUDGET_MS);
+    let deadline = Instant::now() + Duration::from_millis(children_budget_ms);
+    let children: HashMap<String, u32> = {
+        let friends = node.friends.lock().unwrap();
+        friends.iter().filter(|f| f.is_child()).map(|f| (f.address.clone(), f.power)).collect()
+    };
+    // everyone is waited for up front, a fast child may answer before the fan-out below returns
+    *node.tree_collection.lock().unwrap() = Some(TreeCollection {
+        id: id.to_string(),
+        parent,
+        waiting: children.clone(),
+        entries: vec![own_entry(node)],
+        done: false,
+    });
+    let messages = children.keys()
+        .map(|address| TreeMessage {
+            from: node.address.clone(),
+            to: address.clone(),
+            id: id.to_string(),
+            budget_ms: children_budget_ms,
+        })
+        .collect();
+    // the deadline runs from now - a child that doesn't even ACK keeps the sends below busy past it
+    let node_clone = node.clone();
+    let id_clone = id.to_string();
+    tokio::spawn(async move {
+        tokio::time::sleep_until(deadline.into()).await;
+        let waiting: Vec<String> = node_clone.tree_collection.lock().unwrap()
+            .as_ref()
+            .filter(|collection| collection.id == id_clone && !collection.done)
+            .map(|collection| collection.waiting.keys().cloned().collect())
+            .unwrap_or_default();
+        if !waiting.is_empty() {
+            println!("Tree deadline reached, {:?} didn't answer", waiting);
+            give_up_on(&node_clone, &id_clone, &waiting);
+        }
+        try_finish_tree_collection(&node_clone, &id_clone).await;
+    });
+    // ACK = the child collects its subtree and answers later
+    let unreachable: Vec<String> = fan_out(node, messages).await.into_iter()
+        .filter(|(_, response)| !response.as_ref().is_some_and(|response| response.as_any().is::<AckMessage>()))
+        .map(|(address, _)| address)
+        .collect();
+    give_up_on(node, id, &unreachable);
+    try_finish_tree_collection(node, id).await;
+}
+
+// the children are in the tree without their subtrees
+fn give_up_on(node: &Node, id: &str, addresses: &[String]) {
+    let mut collection = node.tree_collection.lock().unwrap();
+    let Some(collection) = collection.as_mut().filter(|collection| collection.id == id && !collection.done) else {
+        return;
+    };
+    for address in addresses {
+        let Some(power) = collection.waiting.remove(address) else {
+            continue;
+        };
+        collection.entries.push(TreeEntry {
+            address: address.clone(),
+            parent: Some(node.address.clone()),
+            state: "UNREACHABLE".to_string(),
+            power,
+            threads: 0,
+            part: None,
+            pending: 0,
+        });
+    }
+}
+
+async fn try_finish_tree_collection(node: &Node, id: &str) {
+    // marked done under the lock, the subtree goes up only once
+    let response = {
+        let mut collection = node.tree_collection.lock().unwrap();
+        let Some(collection) = collection.as_mut()
+            .filter(|collection| collection.id == id && !collection.done && collection.waiting.is_empty()) else {
+            return;
+        };
+        collection.done = true;
+        collection.parent.clone().map(|parent| TreeResponseMessage {
+            from: node.address.clone(),
+            to: parent,
+            id: id.to_string(),
+            entries: std::mem::take(&mut collection.entries),
+        })
+    };
+    if let Some(response) = response
+        && send_message(&response, node).await.is_none() {
+        println!("Could not send my subtree to {}", response.to);
+    }
+}
+
+fn own_entry(node: &Node) -> TreeEntry {
+    let state = match &*node.state.lock().unwrap() {
+        NodeState::IDLE => "IDLE",
+        NodeState::LEADER { .. } => "LEADER",
+        NodeState::WORKER => "WORKER",
+    };
+    // end and position of the running part are kept outside of it
+    let part = node.solving_part_of_a_problem.lock().unwrap().clone()
+        .filter(|part| part.state == PartOfAProblemState::Solving)
+        .map(|part| (part.job_id, part.start, node.current_end.load(Ordering::SeqCst), node.current_position.load(Ordering::SeqCst)));
+    TreeEntry {
+        address: node.address.clone(),
+        parent: node.parent_address(),
+        state: state.to_string(),
+        power: node.power,
+        threads: node.solver_running.load(Ordering::SeqCst) as u32,
+        part,
+        pending: node.pending_parts.lock().unwrap().len(),
+    }
+}
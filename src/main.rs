@@ -1,24 +1,44 @@
 mod args;
+mod checkpoint;
 mod commands;
 mod communication;
 mod utils;
 mod messages;
 mod problem;
 
+use checkpoint::run_checkpoints;
 use commands::process_commands;
 use communication::listen;
+use communication::{run_discovery, run_gossip, run_heartbeats, run_lease_reaper, run_progress_reports, run_replication};
 use args::Args;
 use utils::Node;
+use utils::NodeConfig;
 use utils::Friend;
 use utils::parse_address;
 
 use clap::Parser;
+use std::net::SocketAddrV4;
 use std::thread;
+use std::time::Duration;
 
+// how long the background tasks get to finish their round when the node shuts down
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
 
-fn main() {
+
+#[tokio::main]
+async fn main() {
     let args = Args::parse();
-    let my_address = parse_address(&format!("{}", args.port));
+    // the address I am known by, the listener may be bound to all interfaces
+    let listen_address = SocketAddrV4::new(args.host, args.port);
+    let my_address = match args.advertise {
+        Some(advertise) if advertise.contains(':') => advertise,
+        Some(advertise) => format!("{}:{}", advertise, args.port),
+        None if args.host.is_unspecified() => {
+            eprintln!("Listening on {} - nobody can reach me there, set --advertise", listen_address);
+            std::process::exit(2);
+        }
+        None => listen_address.to_string(),
+    };
     
     // loading friends
     let friends: Vec<Friend> = args.friends
@@ -30,16 +50,64 @@ fn main() {
         .collect();
 
     // create node
-    let node = Node::new(my_address, friends);
+    let config = NodeConfig {
+        max_in_flight: args.max_in_flight,
+        retries: args.retries,
+        retry_backoff_ms: args.retry_backoff_ms,
+        calc_timeout_ms: args.calc_timeout_ms,
+        heartbeat_interval_ms: args.heartbeat_interval_ms,
+        suspect_after: args.suspect_after,
+        dead_after: args.dead_after,
+        standbys: args.standbys,
+        steal_min_size: args.steal_min_size,
+        progress_interval_ms: args.progress_interval_ms,
+        chunk_size: if args.scheduler == "lease" { args.chunk_size.max(1) } else { 0 },
+        lease_ms: args.lease_ms,
+        state_dir: args.state_dir,
+        checkpoint_interval_ms: args.checkpoint_interval_ms,
+        max_jobs: args.max_jobs,
+        slice_size: args.slice_size,
+        release_on_finish: args.release_on_finish,
+        gossip_interval_ms: args.gossip_interval_ms,
+        gossip_fanout: args.gossip_fanout,
+        max_friends: args.max_friends,
+        listen_address: Some(listen_address),
+        discovery_group: args.discovery.then_some(args.discovery_group),
+        discovery_interface: args.discovery_interface,
+        discovery_interval_ms: args.discovery_interval_ms,
+        cluster_name: args.cluster_name,
+        cluster_key: args.cluster_key,
+    };
+    let node = Node::new(my_address, friends, config);
 
     // printing node info
     node.print_info();
 
-    // Start command processing thread
+    // Start command processing thread (stdin is blocking, so it stays a plain thread)
     let node_clone = node.clone();
     thread::spawn(move || {
         process_commands(&node_clone);
     });
 
-    listen(node);
+    let tasks = vec![
+        tokio::spawn(run_heartbeats(node.clone())),
+        tokio::spawn(run_replication(node.clone())),
+        tokio::spawn(run_progress_reports(node.clone())),
+        tokio::spawn(run_lease_reaper(node.clone())),
+        tokio::spawn(run_checkpoints(node.clone())),
+        tokio::spawn(run_gossip(node.clone())),
+        tokio::spawn(run_discovery(node.clone())),
+    ];
+
+    // returns once the node is shutting down (die, leave)
+    listen(node.clone()).await;
+    // my search stops, the solver thread doesn't keep the process busy
+    node.stop_flag.store(true, std::sync::atomic::Ordering::SeqCst);
+    for task in tasks {
+        if tokio::time::timeout(SHUTDOWN_TIMEOUT, task).await.is_err() {
+            println!("A background task didn't stop in time");
+        }
+    }
+    node.connections.close_all().await;
+    println!("Node stopped");
 }
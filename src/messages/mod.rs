@@ -4,62 +4,260 @@ pub use send_message::send_message;
 use core::str;
 use std::any::Any;
 
+use crate::problem::{Job, PartOfAProblem, PartOfAProblemState};
+use crate::utils::TreeEntry;
+
 pub fn parse_message(s: &str) -> Option<Box<dyn Message>> {
-    let parts: Vec<&str> = s.splitn(10, '|').collect();
+    let parts: Vec<&str> = s.splitn(12, '|').collect();
     match parts[0] {
         "PING" => Some(Box::new(PingMessage {
-            from: parts[1].to_string(),
-            to: parts[2].to_string(),
+            from: parts.get(1)?.to_string(),
+            to: parts.get(2)?.to_string(),
         })),
         "ACK" => Some(Box::new(AckMessage {
-            from: parts[1].to_string(),
-            to: parts[2].to_string(),
+            from: parts.get(1)?.to_string(),
+            to: parts.get(2)?.to_string(),
         })),
         "CALC" => Some(Box::new(CalculatePowerMessage {
-            from: parts[1].to_string(),
-            to: parts[2].to_string(),
+            from: parts.get(1)?.to_string(),
+            to: parts.get(2)?.to_string(),
+            budget_ms: parts.get(3)?.parse().unwrap_or(0),
+            leader: parts.get(4)?.to_string(),
+            wave: parts.get(5)?.to_string(),
         })),
         "CALC_RESPONSE" => Some(Box::new(CalculateResponseMessage {
-            from: parts[1].to_string(),
-            to: parts[2].to_string(),
-            power: parts[3].parse().unwrap_or(0),
+            from: parts.get(1)?.to_string(),
+            to: parts.get(2)?.to_string(),
+            power: parts.get(3)?.parse().unwrap_or(0),
+            wave: parts.get(4)?.to_string(),
+        })),
+        "CALC_REJECT" => Some(Box::new(CalcRejectMessage {
+            from: parts.get(1)?.to_string(),
+            to: parts.get(2)?.to_string(),
+            wave: parts.get(3)?.to_string(),
+            joined: parts.get(4).filter(|joined| **joined != "-").map(|joined| joined.to_string()),
+        })),
+        "MARKER" => Some(Box::new(MarkerMessage {
+            from: parts.get(1)?.to_string(),
+            to: parts.get(2)?.to_string(),
+            id: parts.get(3)?.to_string(),
+            initiator: parts.get(4)?.to_string(),
+        })),
+        "SNAPSHOT_STATE" => Some(Box::new(SnapshotStateMessage {
+            from: parts.get(1)?.to_string(),
+            to: parts.get(2)?.to_string(),
+            id: parts.get(3)?.to_string(),
+            friends: parts.get(4)?.split(',').filter(|f| !f.is_empty()).map(|f| f.to_string()).collect(),
+            report: unescape_text(parts.get(5)?),
+        })),
+        "TREE" => Some(Box::new(TreeMessage {
+            from: parts.get(1)?.to_string(),
+            to: parts.get(2)?.to_string(),
+            id: parts.get(3)?.to_string(),
+            budget_ms: parts.get(4)?.parse().ok()?,
+        })),
+        "TREE_RESPONSE" => Some(Box::new(TreeResponseMessage {
+            from: parts.get(1)?.to_string(),
+            to: parts.get(2)?.to_string(),
+            id: parts.get(3)?.to_string(),
+            entries: decode_tree(parts.get(4)?)?,
+        })),
+        "RELEASE" => Some(Box::new(ReleaseMessage {
+            from: parts.get(1)?.to_string(),
+            to: parts.get(2)?.to_string(),
+            wave: parts.get(3)?.to_string(),
+        })),
+        "WAVE_LOST" => Some(Box::new(WaveLostMessage {
+            from: parts.get(1)?.to_string(),
+            to: parts.get(2)?.to_string(),
+            loser: parts.get(3)?.to_string(),
+            winner: parts.get(4)?.to_string(),
+        })),
+        "JOB" => Some(Box::new(JobMessage {
+            from: parts.get(1)?.to_string(),
+            to: parts.get(2)?.to_string(),
+            job_id: parts.get(3)?.to_string(),
+            algorithm: parts.get(4)?.to_string(),
+            alphabet: unescape_text(parts.get(5)?),
+            min_len: parts.get(6)?.parse().ok()?,
+            max_len: parts.get(7)?.parse().ok()?,
+            targets: parts.get(8)?.split(',').map(unescape_text).collect(),
+            chunk_size: parts.get(9)?.parse().ok()?,
+            priority: parts.get(10)?.parse().ok()?,
+            share: parts.get(11)?.parse().ok()?,
         })),
         "SOLVE" => Some(Box::new(SolveProblemMessage {
-            from: parts[1].to_string(),
-            to: parts[2].to_string(),
-            alphabet: parts[3].to_string(),
-            start: parts[4].to_string(),
-            end: parts[5].to_string(),
-            hash: parts[6].to_string(),
+            from: parts.get(1)?.to_string(),
+            to: parts.get(2)?.to_string(),
+            job_id: parts.get(3)?.to_string(),
+            start: parts.get(4)?.parse().ok()?,
+            end: parts.get(5)?.parse().ok()?,
         })),
         "SOLVE_RESPONSE" => {
-            let solution = if parts[5] == "NONE" {
+            let solution = if *parts.get(6)? == "NONE" {
                 None
             } else {
-                Some(parts[5].to_string())
+                Some(unescape_text(parts.get(6)?))
             };
             Some(Box::new(SolveResponseMessage {
-                from: parts[1].to_string(),
-                to: parts[2].to_string(),
-                start: parts[3].to_string(),
-                end: parts[4].to_string(),
+                from: parts.get(1)?.to_string(),
+                to: parts.get(2)?.to_string(),
+                job_id: parts.get(3)?.to_string(),
+                start: parts.get(4)?.parse().ok()?,
+                end: parts.get(5)?.parse().ok()?,
                 solution,
-                space_searched: parts[6].parse().unwrap_or(false),
+                space_searched: parts.get(7)?.parse().unwrap_or(false),
             }))
         },
+        "HEARTBEAT" => Some(Box::new(HeartbeatMessage {
+            from: parts.get(1)?.to_string(),
+            to: parts.get(2)?.to_string(),
+        })),
+        "ELECT" => Some(Box::new(ElectMessage {
+            from: parts.get(1)?.to_string(),
+            to: parts.get(2)?.to_string(),
+            round: parts.get(3)?.parse().ok()?,
+            candidate: parts.get(4)?.to_string(),
+            wave: parts.get(5)?.to_string(),
+        })),
+        "ELECT_ECHO" => Some(Box::new(ElectEchoMessage {
+            from: parts.get(1)?.to_string(),
+            to: parts.get(2)?.to_string(),
+            round: parts.get(3)?.parse().ok()?,
+            candidate: parts.get(4)?.to_string(),
+            joined: parts.get(5)?.parse().ok()?,
+            power: parts.get(6)?.parse().ok()?,
+            parts: decode_parts(parts.get(7)?)?,
+        })),
+        "REPLICA" => Some(Box::new(ReplicaMessage {
+            from: parts.get(1)?.to_string(),
+            to: parts.get(2)?.to_string(),
+            leader: parts.get(3)?.to_string(),
+            version: parts.get(4)?.parse().ok()?,
+            parts: decode_parts(parts.get(5)?)?,
+        })),
+        "WORK_REQUEST" => Some(Box::new(WorkRequestMessage {
+            from: parts.get(1)?.to_string(),
+            to: parts.get(2)?.to_string(),
+            path: decode_addresses(parts.get(3)?),
+        })),
+        "SPLIT" => Some(Box::new(SplitMessage {
+            from: parts.get(1)?.to_string(),
+            to: parts.get(2)?.to_string(),
+            job_id: parts.get(3)?.to_string(),
+            start: parts.get(4)?.parse().ok()?,
+            end: parts.get(5)?.parse().ok()?,
+        })),
+        "SPLIT_RESPONSE" => Some(Box::new(SplitResponseMessage {
+            from: parts.get(1)?.to_string(),
+            to: parts.get(2)?.to_string(),
+            donated: decode_parts(parts.get(3)?)?.into_iter().next(),
+        })),
+        "DONATE" => Some(Box::new(DonateMessage {
+            from: parts.get(1)?.to_string(),
+            to: parts.get(2)?.to_string(),
+            job_id: parts.get(3)?.to_string(),
+            start: parts.get(4)?.parse().ok()?,
+            end: parts.get(5)?.parse().ok()?,
+            route: decode_addresses(parts.get(6)?),
+            lease: decode_lease(parts.get(7)?)?,
+        })),
+        "REVOKE" => Some(Box::new(RevokeMessage {
+            from: parts.get(1)?.to_string(),
+            to: parts.get(2)?.to_string(),
+            job_id: parts.get(3)?.to_string(),
+            lease: parts.get(4)?.parse().ok()?,
+        })),
+        "SIGNAL" => Some(Box::new(SignalMessage {
+            from: parts.get(1)?.to_string(),
+            to: parts.get(2)?.to_string(),
+            job_id: parts.get(3)?.to_string(),
+        })),
+        "PROGRESS" => Some(Box::new(ProgressMessage {
+            from: parts.get(1)?.to_string(),
+            to: parts.get(2)?.to_string(),
+            origin: parts.get(3)?.to_string(),
+            job_id: parts.get(4)?.to_string(),
+            start: parts.get(5)?.parse().ok()?,
+            position: parts.get(6)?.parse().ok()?,
+            end: parts.get(7)?.parse().ok()?,
+            rate: parts.get(8)?.parse().ok()?,
+            lease: decode_lease(parts.get(9)?)?,
+        })),
         "STOP_CALC" => Some(Box::new(StopCalculationMessage {
-            from: parts[1].to_string(),
-            to: parts[2].to_string(),
+            from: parts.get(1)?.to_string(),
+            to: parts.get(2)?.to_string(),
+        })),
+        "STOP_JOB" => Some(Box::new(StopJobMessage {
+            from: parts.get(1)?.to_string(),
+            to: parts.get(2)?.to_string(),
+            job_id: parts.get(3)?.to_string(),
+            forget: parts.get(4)?.parse().ok()?,
+        })),
+        "PAUSE" => Some(Box::new(PauseMessage {
+            from: parts.get(1)?.to_string(),
+            to: parts.get(2)?.to_string(),
+        })),
+        "RESUME" => Some(Box::new(ResumeMessage {
+            from: parts.get(1)?.to_string(),
+            to: parts.get(2)?.to_string(),
+        })),
+        "JOIN" => Some(Box::new(JoinMessage {
+            from: parts.get(1)?.to_string(),
+            to: parts.get(2)?.to_string(),
+            power: parts.get(3)?.parse().ok()?,
+        })),
+        "JOIN_RESPONSE" => Some(Box::new(JoinResponseMessage {
+            from: parts.get(1)?.to_string(),
+            to: parts.get(2)?.to_string(),
+            leader: Some(parts.get(3)?.to_string()).filter(|leader| !leader.is_empty()),
+            wave: parts.get(4).unwrap_or(&"").to_string(),
+        })),
+        "POWER_UPDATE" => Some(Box::new(PowerUpdateMessage {
+            from: parts.get(1)?.to_string(),
+            to: parts.get(2)?.to_string(),
+            delta: parts.get(3)?.parse().ok()?,
+        })),
+        "ADOPT" => Some(Box::new(AdoptMessage {
+            from: parts.get(1)?.to_string(),
+            to: parts.get(2)?.to_string(),
+            child: parts.get(3)?.to_string(),
+            power: parts.get(4)?.parse().ok()?,
+            parts: decode_parts(parts.get(5)?)?,
+        })),
+        "NEW_PARENT" => Some(Box::new(NewParentMessage {
+            from: parts.get(1)?.to_string(),
+            to: parts.get(2)?.to_string(),
+            parent: parts.get(3)?.to_string(),
+        })),
+        "GOSSIP" => Some(Box::new(GossipMessage {
+            from: parts.get(1)?.to_string(),
+            to: parts.get(2)?.to_string(),
+            members: decode_members(parts.get(3)?)?,
+        })),
+        "LEAVE" => Some(Box::new(LeaveMessage {
+            from: parts.get(1)?.to_string(),
+            to: parts.get(2)?.to_string(),
         })),
         _ => None,
     }
 }
 
-pub trait Message {
+pub trait Message: Send + Sync {
     fn from(&self) -> &str;
     fn to(&self) -> &str;
     fn serialize(&self) -> String;
 
+    // safe to send again when no response arrives (the receiver drops duplicates)
+    fn is_idempotent(&self) -> bool {
+        false
+    }
+
+    // periodic messages (heartbeats, progress...) that are not worth logging
+    fn is_background(&self) -> bool {
+        false
+    }
+
     fn as_any(&self) -> &dyn Any;
     fn clone_box(&self) -> Box<dyn Message>;
 }
@@ -83,6 +281,10 @@ impl Message for PingMessage {
         format!("PING|{}|{}", self.from, self.to)
     }
 
+    fn is_idempotent(&self) -> bool {
+        true
+    }
+
     fn as_any(&self) -> &dyn Any {
         self
     }
@@ -124,6 +326,11 @@ impl Message for AckMessage {
 pub struct CalculatePowerMessage {
     pub from: String,
     pub to: String,
+    // how long the receiver has to send back its CALC_RESPONSE
+    pub budget_ms: u64,
+    pub leader: String,
+    // id of the wave building the tree, leader's start time @ leader
+    pub wave: String,
 }
 
 impl Message for CalculatePowerMessage {
@@ -136,7 +343,11 @@ impl Message for CalculatePowerMessage {
     }
 
     fn serialize(&self) -> String {
-        format!("CALC|{}|{}", self.from, self.to)
+        format!("CALC|{}|{}|{}|{}|{}", self.from, self.to, self.budget_ms, self.leader, self.wave)
+    }
+
+    fn is_idempotent(&self) -> bool {
+        true
     }
 
     fn as_any(&self) -> &dyn Any {
@@ -153,6 +364,7 @@ pub struct CalculateResponseMessage {
     pub from: String,
     pub to: String,
     pub power: u32,
+    pub wave: String,
 }
 
 impl Message for CalculateResponseMessage {
@@ -165,7 +377,11 @@ impl Message for CalculateResponseMessage {
     }
 
     fn serialize(&self) -> String {
-        format!("CALC_RESPONSE|{}|{}|{}", self.from, self.to, self.power)
+        format!("CALC_RESPONSE|{}|{}|{}|{}", self.from, self.to, self.power, self.wave)
+    }
+
+    fn is_idempotent(&self) -> bool {
+        true
     }
 
     fn as_any(&self) -> &dyn Any {
@@ -177,17 +393,18 @@ impl Message for CalculateResponseMessage {
     }
 }
 
+// answer to a CALC from a node that won't be the sender's child - it is already in this wave
+// (reached through another path) or in another one
 #[derive(Clone, Debug)]
-pub struct SolveProblemMessage {
+pub struct CalcRejectMessage {
     pub from: String,
     pub to: String,
-    pub alphabet: String,
-    pub start: String,
-    pub end: String,
-    pub hash: String,
+    pub wave: String,
+    // the wave I belong to, None if I am not in one
+    pub joined: Option<String>,
 }
 
-impl Message for SolveProblemMessage {
+impl Message for CalcRejectMessage {
     fn from(&self) -> &str {
         &self.from
     }
@@ -197,10 +414,11 @@ impl Message for SolveProblemMessage {
     }
 
     fn serialize(&self) -> String {
-        format!(
-            "SOLVE|{}|{}|{}|{}|{}|{}",
-            self.from, self.to, self.alphabet, self.start, self.end, self.hash
-        )
+        format!("CALC_REJECT|{}|{}|{}|{}", self.from, self.to, self.wave, self.joined.as_deref().unwrap_or("-"))
+    }
+
+    fn is_idempotent(&self) -> bool {
+        true
     }
 
     fn as_any(&self) -> &dyn Any {
@@ -212,17 +430,267 @@ impl Message for SolveProblemMessage {
     }
 }
 
+// two leaders built their trees at the same time, sent to the leader of the losing wave,
+// which steps down and passes it on to the winner
 #[derive(Clone, Debug)]
-pub struct SolveResponseMessage {
+pub struct WaveLostMessage {
     pub from: String,
     pub to: String,
-    pub start: String,
-    pub end: String,
-    pub space_searched: bool,
-    pub solution: Option<String>,
+    pub loser: String,
+    pub winner: String,
 }
 
-impl Message for SolveResponseMessage {
+impl Message for WaveLostMessage {
+    fn from(&self) -> &str {
+        &self.from
+    }
+
+    fn to(&self) -> &str {
+        &self.to
+    }
+
+    fn serialize(&self) -> String {
+        format!("WAVE_LOST|{}|{}|{}|{}", self.from, self.to, self.loser, self.winner)
+    }
+
+    fn is_idempotent(&self) -> bool {
+        true
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn clone_box(&self) -> Box<dyn Message> {
+        Box::new(self.clone())
+    }
+}
+
+// takes apart the tree built by the wave, goes to all friends and every node of the wave passes it on
+#[derive(Clone, Debug)]
+pub struct ReleaseMessage {
+    pub from: String,
+    pub to: String,
+    pub wave: String,
+}
+
+impl Message for ReleaseMessage {
+    fn from(&self) -> &str {
+        &self.from
+    }
+
+    fn to(&self) -> &str {
+        &self.to
+    }
+
+    fn serialize(&self) -> String {
+        format!("RELEASE|{}|{}|{}", self.from, self.to, self.wave)
+    }
+
+    fn is_idempotent(&self) -> bool {
+        true
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn clone_box(&self) -> Box<dyn Message> {
+        Box::new(self.clone())
+    }
+}
+
+// goes down the tree, every node answers with the entries of its whole subtree
+#[derive(Clone, Debug)]
+pub struct TreeMessage {
+    pub from: String,
+    pub to: String,
+    pub id: String,
+    // how long the receiver has to send back its TREE_RESPONSE
+    pub budget_ms: u64,
+}
+
+impl Message for TreeMessage {
+    fn from(&self) -> &str {
+        &self.from
+    }
+
+    fn to(&self) -> &str {
+        &self.to
+    }
+
+    fn serialize(&self) -> String {
+        format!("TREE|{}|{}|{}|{}", self.from, self.to, self.id, self.budget_ms)
+    }
+
+    fn is_idempotent(&self) -> bool {
+        true
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn clone_box(&self) -> Box<dyn Message> {
+        Box::new(self.clone())
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TreeResponseMessage {
+    pub from: String,
+    pub to: String,
+    pub id: String,
+    pub entries: Vec<TreeEntry>,
+}
+
+impl Message for TreeResponseMessage {
+    fn from(&self) -> &str {
+        &self.from
+    }
+
+    fn to(&self) -> &str {
+        &self.to
+    }
+
+    fn serialize(&self) -> String {
+        format!("TREE_RESPONSE|{}|{}|{}|{}", self.from, self.to, self.id, encode_tree(&self.entries))
+    }
+
+    fn is_idempotent(&self) -> bool {
+        true
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn clone_box(&self) -> Box<dyn Message> {
+        Box::new(self.clone())
+    }
+}
+
+// snapshot marker, sent on every channel to a friend right after the sender recorded its state
+#[derive(Clone, Debug)]
+pub struct MarkerMessage {
+    pub from: String,
+    pub to: String,
+    pub id: String,
+    pub initiator: String,
+}
+
+impl Message for MarkerMessage {
+    fn from(&self) -> &str {
+        &self.from
+    }
+
+    fn to(&self) -> &str {
+        &self.to
+    }
+
+    fn serialize(&self) -> String {
+        format!("MARKER|{}|{}|{}|{}", self.from, self.to, self.id, self.initiator)
+    }
+
+    fn is_idempotent(&self) -> bool {
+        true
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn clone_box(&self) -> Box<dyn Message> {
+        Box::new(self.clone())
+    }
+}
+
+// a node's part of the snapshot, goes to the initiator
+#[derive(Clone, Debug)]
+pub struct SnapshotStateMessage {
+    pub from: String,
+    pub to: String,
+    pub id: String,
+    // so the initiator knows who else has to report
+    pub friends: Vec<String>,
+    pub report: String,
+}
+
+impl Message for SnapshotStateMessage {
+    fn from(&self) -> &str {
+        &self.from
+    }
+
+    fn to(&self) -> &str {
+        &self.to
+    }
+
+    fn serialize(&self) -> String {
+        format!("SNAPSHOT_STATE|{}|{}|{}|{}|{}", self.from, self.to, self.id, self.friends.join(","), escape_text(&self.report))
+    }
+
+    fn is_idempotent(&self) -> bool {
+        true
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn clone_box(&self) -> Box<dyn Message> {
+        Box::new(self.clone())
+    }
+}
+
+// registers a job once, parts of it then only refer to the job id
+#[derive(Clone, Debug)]
+pub struct JobMessage {
+    pub from: String,
+    pub to: String,
+    pub job_id: String,
+    pub algorithm: String,
+    pub alphabet: String,
+    pub min_len: usize,
+    pub max_len: usize,
+    pub targets: Vec<String>,
+    pub chunk_size: usize,
+    pub priority: u32,
+    pub share: u32,
+}
+
+impl JobMessage {
+    pub fn new(from: String, to: String, job: &Job) -> Self {
+        JobMessage {
+            from,
+            to,
+            job_id: job.id.clone(),
+            algorithm: job.algorithm.clone(),
+            alphabet: job.alphabet.clone(),
+            min_len: job.min_len,
+            max_len: job.max_len,
+            targets: job.targets.clone(),
+            chunk_size: job.chunk_size,
+            priority: job.priority,
+            share: job.share,
+        }
+    }
+
+    pub fn to_job(&self) -> Job {
+        Job {
+            id: self.job_id.clone(),
+            algorithm: self.algorithm.clone(),
+            alphabet: self.alphabet.clone(),
+            min_len: self.min_len,
+            max_len: self.max_len,
+            targets: self.targets.clone(),
+            chunk_size: self.chunk_size,
+            priority: self.priority,
+            share: self.share,
+        }
+    }
+}
+
+impl Message for JobMessage {
     fn from(&self) -> &str {
         &self.from
     }
@@ -232,16 +700,17 @@ impl Message for SolveResponseMessage {
     }
 
     fn serialize(&self) -> String {
-        let solution_str = match &self.solution {
-            Some(sol) => sol.clone(),
-            None => "NONE".to_string(),
-        };
         format!(
-            "SOLVE_RESPONSE|{}|{}|{}|{}|{}|{}",
-            self.from, self.to, self.start, self.end, solution_str, self.space_searched
+            "JOB|{}|{}|{}|{}|{}|{}|{}|{}|{}|{}|{}",
+            self.from, self.to, self.job_id, self.algorithm, escape_text(&self.alphabet), self.min_len, self.max_len,
+            self.targets.iter().map(|target| escape_text(target)).collect::<Vec<_>>().join(","), self.chunk_size, self.priority, self.share
         )
     }
 
+    fn is_idempotent(&self) -> bool {
+        true
+    }
+
     fn as_any(&self) -> &dyn Any {
         self
     }
@@ -251,13 +720,17 @@ impl Message for SolveResponseMessage {
     }
 }
 
+// range of candidate indices of a registered job
 #[derive(Clone, Debug)]
-pub struct StopCalculationMessage {
+pub struct SolveProblemMessage {
     pub from: String,
     pub to: String,
+    pub job_id: String,
+    pub start: usize,
+    pub end: usize,
 }
 
-impl Message for StopCalculationMessage {
+impl Message for SolveProblemMessage {
     fn from(&self) -> &str {
         &self.from
     }
@@ -267,7 +740,14 @@ impl Message for StopCalculationMessage {
     }
 
     fn serialize(&self) -> String {
-        format!("STOP_CALC|{}|{}", self.from, self.to)
+        format!(
+            "SOLVE|{}|{}|{}|{}|{}",
+            self.from, self.to, self.job_id, self.start, self.end
+        )
+    }
+
+    fn is_idempotent(&self) -> bool {
+        true
     }
 
     fn as_any(&self) -> &dyn Any {
@@ -279,9 +759,1112 @@ impl Message for StopCalculationMessage {
     }
 }
 
-// Implement Clone for Box<dyn Message>
-impl Clone for Box<dyn Message> {
-    fn clone(&self) -> Box<dyn Message> {
-        self.clone_box()
+#[derive(Clone, Debug)]
+pub struct SolveResponseMessage {
+    pub from: String,
+    pub to: String,
+    pub job_id: String,
+    pub start: usize,
+    pub end: usize,
+    pub space_searched: bool,
+    pub solution: Option<String>,
+}
+
+impl Message for SolveResponseMessage {
+    fn from(&self) -> &str {
+        &self.from
     }
-}
\ No newline at end of file
+
+    fn to(&self) -> &str {
+        &self.to
+    }
+
+    fn serialize(&self) -> String {
+        let solution_str = match &self.solution {
+            Some(sol) => escape_text(sol),
+            None => "NONE".to_string(),
+        };
+        format!(
+            "SOLVE_RESPONSE|{}|{}|{}|{}|{}|{}|{}",
+            self.from, self.to, self.job_id, self.start, self.end, solution_str, self.space_searched
+        )
+    }
+
+    fn is_idempotent(&self) -> bool {
+        true
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn clone_box(&self) -> Box<dyn Message> {
+        Box::new(self.clone())
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct StopCalculationMessage {
+    pub from: String,
+    pub to: String,
+}
+
+impl Message for StopCalculationMessage {
+    fn from(&self) -> &str {
+        &self.from
+    }
+
+    fn to(&self) -> &str {
+        &self.to
+    }
+
+    fn serialize(&self) -> String {
+        format!("STOP_CALC|{}|{}", self.from, self.to)
+    }
+
+    fn is_idempotent(&self) -> bool {
+        true
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn clone_box(&self) -> Box<dyn Message> {
+        Box::new(self.clone())
+    }
+}
+
+// stops one job in the whole subtree - paused jobs are kept registered, finished or cancelled ones are forgotten
+#[derive(Clone, Debug)]
+pub struct StopJobMessage {
+    pub from: String,
+    pub to: String,
+    pub job_id: String,
+    pub forget: bool,
+}
+
+impl Message for StopJobMessage {
+    fn from(&self) -> &str {
+        &self.from
+    }
+
+    fn to(&self) -> &str {
+        &self.to
+    }
+
+    fn serialize(&self) -> String {
+        format!("STOP_JOB|{}|{}|{}|{}", self.from, self.to, self.job_id, self.forget)
+    }
+
+    fn is_idempotent(&self) -> bool {
+        true
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn clone_box(&self) -> Box<dyn Message> {
+        Box::new(self.clone())
+    }
+}
+
+// whole subtree stops searching and keeps its parts, RESUME continues where it stopped
+#[derive(Clone, Debug)]
+pub struct PauseMessage {
+    pub from: String,
+    pub to: String,
+}
+
+impl Message for PauseMessage {
+    fn from(&self) -> &str {
+        &self.from
+    }
+
+    fn to(&self) -> &str {
+        &self.to
+    }
+
+    fn serialize(&self) -> String {
+        format!("PAUSE|{}|{}", self.from, self.to)
+    }
+
+    fn is_idempotent(&self) -> bool {
+        true
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn clone_box(&self) -> Box<dyn Message> {
+        Box::new(self.clone())
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ResumeMessage {
+    pub from: String,
+    pub to: String,
+}
+
+impl Message for ResumeMessage {
+    fn from(&self) -> &str {
+        &self.from
+    }
+
+    fn to(&self) -> &str {
+        &self.to
+    }
+
+    fn serialize(&self) -> String {
+        format!("RESUME|{}|{}", self.from, self.to)
+    }
+
+    fn is_idempotent(&self) -> bool {
+        true
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn clone_box(&self) -> Box<dyn Message> {
+        Box::new(self.clone())
+    }
+}
+
+// idle node that wants to join a tree with a job already running, answered with JOIN_RESPONSE
+#[derive(Clone, Debug)]
+pub struct JoinMessage {
+    pub from: String,
+    pub to: String,
+    pub power: u32,
+}
+
+impl Message for JoinMessage {
+    fn from(&self) -> &str {
+        &self.from
+    }
+
+    fn to(&self) -> &str {
+        &self.to
+    }
+
+    fn serialize(&self) -> String {
+        format!("JOIN|{}|{}|{}", self.from, self.to, self.power)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn clone_box(&self) -> Box<dyn Message> {
+        Box::new(self.clone())
+    }
+}
+
+// leader of the tree the sender is in, None if it didn't take the node as its child
+#[derive(Clone, Debug)]
+pub struct JoinResponseMessage {
+    pub from: String,
+    pub to: String,
+    pub leader: Option<String>,
+    // wave of the tree, the joiner becomes part of it
+    pub wave: String,
+}
+
+impl Message for JoinResponseMessage {
+    fn from(&self) -> &str {
+        &self.from
+    }
+
+    fn to(&self) -> &str {
+        &self.to
+    }
+
+    fn serialize(&self) -> String {
+        format!("JOIN_RESPONSE|{}|{}|{}|{}", self.from, self.to, self.leader.as_deref().unwrap_or(""), self.wave)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn clone_box(&self) -> Box<dyn Message> {
+        Box::new(self.clone())
+    }
+}
+
+// power of the sender's subtree changed (a node joined or left), passed up to the leader
+#[derive(Clone, Debug)]
+pub struct PowerUpdateMessage {
+    pub from: String,
+    pub to: String,
+    pub delta: i32,
+}
+
+impl Message for PowerUpdateMessage {
+    fn from(&self) -> &str {
+        &self.from
+    }
+
+    fn to(&self) -> &str {
+        &self.to
+    }
+
+    fn serialize(&self) -> String {
+        format!("POWER_UPDATE|{}|{}|{}", self.from, self.to, self.delta)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn clone_box(&self) -> Box<dyn Message> {
+        Box::new(self.clone())
+    }
+}
+
+// a leaving node hands its child over to its parent, with the ranges the child's subtree holds
+#[derive(Clone, Debug)]
+pub struct AdoptMessage {
+    pub from: String,
+    pub to: String,
+    pub child: String,
+    pub power: u32,
+    pub parts: Vec<PartOfAProblem>,
+}
+
+impl Message for AdoptMessage {
+    fn from(&self) -> &str {
+        &self.from
+    }
+
+    fn to(&self) -> &str {
+        &self.to
+    }
+
+    fn serialize(&self) -> String {
+        format!("ADOPT|{}|{}|{}|{}|{}", self.from, self.to, self.child, self.power, encode_parts(&self.parts))
+    }
+
+    fn is_idempotent(&self) -> bool {
+        true
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn clone_box(&self) -> Box<dyn Message> {
+        Box::new(self.clone())
+    }
+}
+
+// sent by a leaving node to its children, their parent is `parent` from now on
+#[derive(Clone, Debug)]
+pub struct NewParentMessage {
+    pub from: String,
+    pub to: String,
+    pub parent: String,
+}
+
+impl Message for NewParentMessage {
+    fn from(&self) -> &str {
+        &self.from
+    }
+
+    fn to(&self) -> &str {
+        &self.to
+    }
+
+    fn serialize(&self) -> String {
+        format!("NEW_PARENT|{}|{}|{}", self.from, self.to, self.parent)
+    }
+
+    fn is_idempotent(&self) -> bool {
+        true
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn clone_box(&self) -> Box<dyn Message> {
+        Box::new(self.clone())
+    }
+}
+
+// the sender left the tree, its children and ranges were already handed over
+#[derive(Clone, Debug)]
+pub struct LeaveMessage {
+    pub from: String,
+    pub to: String,
+}
+
+impl Message for LeaveMessage {
+    fn from(&self) -> &str {
+        &self.from
+    }
+
+    fn to(&self) -> &str {
+        &self.to
+    }
+
+    fn serialize(&self) -> String {
+        format!("LEAVE|{}|{}", self.from, self.to)
+    }
+
+    fn is_idempotent(&self) -> bool {
+        true
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn clone_box(&self) -> Box<dyn Message> {
+        Box::new(self.clone())
+    }
+}
+
+// members the sender knows, answered with the receiver's own list
+#[derive(Clone, Debug)]
+pub struct GossipMessage {
+    pub from: String,
+    pub to: String,
+    // (address, incarnation, heartbeat)
+    pub members: Vec<(String, u64, u64)>,
+}
+
+impl Message for GossipMessage {
+    fn from(&self) -> &str {
+        &self.from
+    }
+
+    fn to(&self) -> &str {
+        &self.to
+    }
+
+    fn serialize(&self) -> String {
+        format!("GOSSIP|{}|{}|{}", self.from, self.to, encode_members(&self.members))
+    }
+
+    fn is_idempotent(&self) -> bool {
+        true
+    }
+
+    fn is_background(&self) -> bool {
+        true
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn clone_box(&self) -> Box<dyn Message> {
+        Box::new(self.clone())
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct HeartbeatMessage {
+    pub from: String,
+    pub to: String,
+}
+
+impl Message for HeartbeatMessage {
+    fn from(&self) -> &str {
+        &self.from
+    }
+
+    fn to(&self) -> &str {
+        &self.to
+    }
+
+    fn serialize(&self) -> String {
+        format!("HEARTBEAT|{}|{}", self.from, self.to)
+    }
+
+    fn is_background(&self) -> bool {
+        true
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn clone_box(&self) -> Box<dyn Message> {
+        Box::new(self.clone())
+    }
+}
+
+// wave of a leader election, the highest (round, candidate) wins and the others die out
+#[derive(Clone, Debug)]
+pub struct ElectMessage {
+    pub from: String,
+    pub to: String,
+    pub round: u64,
+    pub candidate: String,
+    // the tree built by the election is a wave of its own, RELEASE and CALC go by it
+    pub wave: String,
+}
+
+impl Message for ElectMessage {
+    fn from(&self) -> &str {
+        &self.from
+    }
+
+    fn to(&self) -> &str {
+        &self.to
+    }
+
+    fn serialize(&self) -> String {
+        format!("ELECT|{}|{}|{}|{}|{}", self.from, self.to, self.round, self.candidate, self.wave)
+    }
+
+    fn is_idempotent(&self) -> bool {
+        true
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn clone_box(&self) -> Box<dyn Message> {
+        Box::new(self.clone())
+    }
+}
+
+// answer to ELECT - joined: the sender is my child now and reports its subtree,
+// otherwise it is already part of the wave (or of a stronger one) and reports nothing
+#[derive(Clone, Debug)]
+pub struct ElectEchoMessage {
+    pub from: String,
+    pub to: String,
+    pub round: u64,
+    pub candidate: String,
+    pub joined: bool,
+    pub power: u32,
+    // what the subtree searched (SearchedAndNotFound) and still holds (Distributed)
+    pub parts: Vec<PartOfAProblem>,
+}
+
+impl Message for ElectEchoMessage {
+    fn from(&self) -> &str {
+        &self.from
+    }
+
+    fn to(&self) -> &str {
+        &self.to
+    }
+
+    fn serialize(&self) -> String {
+        format!(
+            "ELECT_ECHO|{}|{}|{}|{}|{}|{}|{}",
+            self.from, self.to, self.round, self.candidate, self.joined, self.power, encode_parts(&self.parts)
+        )
+    }
+
+    fn is_idempotent(&self) -> bool {
+        true
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn clone_box(&self) -> Box<dyn Message> {
+        Box::new(self.clone())
+    }
+}
+
+// leader's interval map sent to its standbys
+#[derive(Clone, Debug)]
+pub struct ReplicaMessage {
+    pub from: String,
+    pub to: String,
+    pub leader: String,
+    pub version: u64,
+    // parts of all jobs of the leader
+    pub parts: Vec<PartOfAProblem>,
+}
+
+impl Message for ReplicaMessage {
+    fn from(&self) -> &str {
+        &self.from
+    }
+
+    fn to(&self) -> &str {
+        &self.to
+    }
+
+    fn serialize(&self) -> String {
+        format!(
+            "REPLICA|{}|{}|{}|{}|{}",
+            self.from, self.to, self.leader, self.version, encode_parts(&self.parts)
+        )
+    }
+
+    fn is_idempotent(&self) -> bool {
+        true
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn clone_box(&self) -> Box<dyn Message> {
+        Box::new(self.clone())
+    }
+}
+
+// idle node asking the leader for work, every hop on the way up adds itself to the path
+#[derive(Clone, Debug)]
+pub struct WorkRequestMessage {
+    pub from: String,
+    pub to: String,
+    pub path: Vec<String>,
+}
+
+impl Message for WorkRequestMessage {
+    fn from(&self) -> &str {
+        &self.from
+    }
+
+    fn to(&self) -> &str {
+        &self.to
+    }
+
+    fn serialize(&self) -> String {
+        format!("WORK_REQUEST|{}|{}|{}", self.from, self.to, self.path.join(","))
+    }
+
+    fn is_idempotent(&self) -> bool {
+        true
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn clone_box(&self) -> Box<dyn Message> {
+        Box::new(self.clone())
+    }
+}
+
+// give away a part of what you (or your subtree) search in this range
+#[derive(Clone, Debug)]
+pub struct SplitMessage {
+    pub from: String,
+    pub to: String,
+    pub job_id: String,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Message for SplitMessage {
+    fn from(&self) -> &str {
+        &self.from
+    }
+
+    fn to(&self) -> &str {
+        &self.to
+    }
+
+    fn serialize(&self) -> String {
+        format!("SPLIT|{}|{}|{}|{}|{}", self.from, self.to, self.job_id, self.start, self.end)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn clone_box(&self) -> Box<dyn Message> {
+        Box::new(self.clone())
+    }
+}
+
+// the range that was given away, None if there was nothing worth splitting
+#[derive(Clone, Debug)]
+pub struct SplitResponseMessage {
+    pub from: String,
+    pub to: String,
+    pub donated: Option<PartOfAProblem>,
+}
+
+impl Message for SplitResponseMessage {
+    fn from(&self) -> &str {
+        &self.from
+    }
+
+    fn to(&self) -> &str {
+        &self.to
+    }
+
+    fn serialize(&self) -> String {
+        format!("SPLIT_RESPONSE|{}|{}|{}", self.from, self.to, encode_parts(self.donated.as_slice()))
+    }
+
+    fn is_idempotent(&self) -> bool {
+        true
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn clone_box(&self) -> Box<dyn Message> {
+        Box::new(self.clone())
+    }
+}
+
+// stolen range on its way down to the idle node, `route` are the hops left after the receiver
+#[derive(Clone, Debug)]
+pub struct DonateMessage {
+    pub from: String,
+    pub to: String,
+    pub job_id: String,
+    pub start: usize,
+    pub end: usize,
+    pub route: Vec<String>,
+    // set when the leader leased the range, the last one on the route holds it
+    pub lease: Option<u64>,
+}
+
+impl Message for DonateMessage {
+    fn from(&self) -> &str {
+        &self.from
+    }
+
+    fn to(&self) -> &str {
+        &self.to
+    }
+
+    fn serialize(&self) -> String {
+        format!(
+            "DONATE|{}|{}|{}|{}|{}|{}|{}",
+            self.from, self.to, self.job_id, self.start, self.end, self.route.join(","), encode_lease(self.lease)
+        )
+    }
+
+    fn is_idempotent(&self) -> bool {
+        true
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn clone_box(&self) -> Box<dyn Message> {
+        Box::new(self.clone())
+    }
+}
+
+// the work of a job the receiver sent me is done (Dijkstra-Scholten), also everything I passed on of it
+#[derive(Clone, Debug)]
+pub struct SignalMessage {
+    pub from: String,
+    pub to: String,
+    pub job_id: String,
+}
+
+impl Message for SignalMessage {
+    fn from(&self) -> &str {
+        &self.from
+    }
+
+    fn to(&self) -> &str {
+        &self.to
+    }
+
+    fn serialize(&self) -> String {
+        format!("SIGNAL|{}|{}|{}", self.from, self.to, self.job_id)
+    }
+
+    fn is_idempotent(&self) -> bool {
+        true
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn clone_box(&self) -> Box<dyn Message> {
+        Box::new(self.clone())
+    }
+}
+
+// where a worker's search is, forwarded up to the leader
+#[derive(Clone, Debug)]
+pub struct ProgressMessage {
+    pub from: String,
+    pub to: String,
+    // the worker, `from` changes on every hop
+    pub origin: String,
+    pub job_id: String,
+    pub start: usize,
+    // everything before it is searched
+    pub position: usize,
+    pub end: usize,
+    // candidates per second
+    pub rate: u64,
+    // the lease the part is searched under, it is renewed by the report
+    pub lease: Option<u64>,
+}
+
+impl Message for ProgressMessage {
+    fn from(&self) -> &str {
+        &self.from
+    }
+
+    fn to(&self) -> &str {
+        &self.to
+    }
+
+    fn serialize(&self) -> String {
+        format!(
+            "PROGRESS|{}|{}|{}|{}|{}|{}|{}|{}|{}",
+            self.from, self.to, self.origin, self.job_id, self.start, self.position, self.end, self.rate, encode_lease(self.lease)
+        )
+    }
+
+    fn is_background(&self) -> bool {
+        true
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn clone_box(&self) -> Box<dyn Message> {
+        Box::new(self.clone())
+    }
+}
+
+// the lease expired, the holder stops searching what is left of it - it was handed out again
+#[derive(Clone, Debug)]
+pub struct RevokeMessage {
+    pub from: String,
+    pub to: String,
+    pub job_id: String,
+    pub lease: u64,
+}
+
+impl Message for RevokeMessage {
+    fn from(&self) -> &str {
+        &self.from
+    }
+
+    fn to(&self) -> &str {
+        &self.to
+    }
+
+    fn serialize(&self) -> String {
+        format!("REVOKE|{}|{}|{}|{}", self.from, self.to, self.job_id, self.lease)
+    }
+
+    fn is_idempotent(&self) -> bool {
+        true
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn clone_box(&self) -> Box<dyn Message> {
+        Box::new(self.clone())
+    }
+}
+
+// - for no lease
+fn encode_lease(lease: Option<u64>) -> String {
+    lease.map_or("-".to_string(), |lease| lease.to_string())
+}
+
+fn decode_lease(s: &str) -> Option<Option<u64>> {
+    if s == "-" {
+        return Some(None);
+    }
+    s.parse().ok().map(Some)
+}
+
+fn decode_addresses(s: &str) -> Vec<String> {
+    s.split(',').filter(|address| !address.is_empty()).map(|address| address.to_string()).collect()
+}
+
+// address,incarnation,heartbeat;...
+fn encode_members(members: &[(String, u64, u64)]) -> String {
+    members.iter()
+        .map(|(address, incarnation, heartbeat)| format!("{},{},{}", address, incarnation, heartbeat))
+        .collect::<Vec<_>>()
+        .join(";")
+}
+
+fn decode_members(s: &str) -> Option<Vec<(String, u64, u64)>> {
+    s.split(';')
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let fields: Vec<&str> = entry.split(',').collect();
+            Some((fields.first()?.to_string(), fields.get(1)?.parse().ok()?, fields.get(2)?.parse().ok()?))
+        })
+        .collect()
+}
+
+// address,parent,state,power,threads,job_id,start,end,position,pending;... with - for no parent / no part
+fn encode_tree(entries: &[TreeEntry]) -> String {
+    entries.iter()
+        .map(|entry| {
+            let part = match &entry.part {
+                Some((job_id, start, end, position)) => format!("{},{},{},{}", escape_text(job_id), start, end, position),
+                None => "-,0,0,0".to_string(),
+            };
+            format!("{},{},{},{},{},{},{}", escape_text(&entry.address), entry.parent.as_deref().map_or("-".to_string(), escape_text),
+                escape_text(&entry.state), entry.power, entry.threads, part, entry.pending)
+        })
+        .collect::<Vec<_>>()
+        .join(";")
+}
+
+fn decode_tree(s: &str) -> Option<Vec<TreeEntry>> {
+    s.split(';')
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let fields: Vec<&str> = entry.split(',').collect();
+            let [address, parent, state, power, threads, job_id, start, end, position, pending] = fields[..] else {
+                return None;
+            };
+            Some(TreeEntry {
+                address: unescape_text(address),
+                parent: Some(parent).filter(|parent| *parent != "-").map(unescape_text),
+                state: unescape_text(state),
+                power: power.parse().ok()?,
+                threads: threads.parse().ok()?,
+                part: if job_id == "-" { None } else { Some((unescape_text(job_id), start.parse().ok()?, end.parse().ok()?, position.parse().ok()?)) },
+                pending: pending.parse().ok()?,
+            })
+        })
+        .collect()
+}
+
+// text in one field - no new lines or | on the wire, and no , or ; so it can be an item of a list
+fn escape_text(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('\n', "\\n").replace('|', "\\p").replace(',', "\\c").replace(';', "\\s")
+}
+
+fn unescape_text(s: &str) -> String {
+    let mut output = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            output.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => output.push('\n'),
+            Some('p') => output.push('|'),
+            Some('c') => output.push(','),
+            Some('s') => output.push(';'),
+            Some(other) => output.push(other),
+            None => {}
+        }
+    }
+    output
+}
+
+// job_id,start,end,state;...
+pub fn encode_parts(parts: &[PartOfAProblem]) -> String {
+    parts.iter()
+        .map(|part| {
+            let state = match part.state {
+                PartOfAProblemState::NotDistributed => "not_distributed",
+                PartOfAProblemState::Distributed => "distributed",
+                PartOfAProblemState::SearchedAndNotFound => "searched",
+                PartOfAProblemState::Solving => "solving",
+            };
+            format!("{},{},{},{}", part.job_id, part.start, part.end, state)
+        })
+        .collect::<Vec<_>>()
+        .join(";")
+}
+
+pub fn decode_parts(s: &str) -> Option<Vec<PartOfAProblem>> {
+    s.split(';')
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let fields: Vec<&str> = entry.split(',').collect();
+            let mut part = PartOfAProblem::new(fields.first()?, fields.get(1)?.parse().ok()?, fields.get(2)?.parse().ok()?);
+            part.state = match *fields.get(3)? {
+                "not_distributed" => PartOfAProblemState::NotDistributed,
+                "distributed" => PartOfAProblemState::Distributed,
+                "searched" => PartOfAProblemState::SearchedAndNotFound,
+                "solving" => PartOfAProblemState::Solving,
+                _ => return None,
+            };
+            Some(part)
+        })
+        .collect()
+}
+
+// Implement Clone for Box<dyn Message>
+impl Clone for Box<dyn Message> {
+    fn clone(&self) -> Box<dyn Message> {
+        self.clone_box()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parts_survive_encoding() {
+        let mut parts = vec![
+            PartOfAProblem::new("127.0.0.1:3000#1", 0, 99),
+            PartOfAProblem::new("127.0.0.1:3000#1", 100, 199),
+            PartOfAProblem::new("127.0.0.1:3000#2", 0, usize::MAX),
+            PartOfAProblem::new("127.0.0.1:3000#2", 5, 5),
+        ];
+        parts[1].state = PartOfAProblemState::Distributed;
+        parts[2].state = PartOfAProblemState::SearchedAndNotFound;
+        parts[3].state = PartOfAProblemState::Solving;
+        let decoded = decode_parts(&encode_parts(&parts)).unwrap();
+        assert_eq!(decoded.len(), parts.len());
+        for (decoded, part) in decoded.iter().zip(parts.iter()) {
+            assert_eq!((&decoded.job_id, decoded.start, decoded.end, &decoded.state), (&part.job_id, part.start, part.end, &part.state));
+        }
+    }
+
+    #[test]
+    fn no_parts_is_an_empty_field() {
+        assert_eq!(encode_parts(&[]), "");
+        assert!(decode_parts("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn broken_parts_are_rejected() {
+        assert!(decode_parts("job,0,9").is_none());
+        assert!(decode_parts("job,x,9,searched").is_none());
+        assert!(decode_parts("job,0,9,lost").is_none());
+    }
+
+    #[test]
+    fn parts_survive_a_message() {
+        let echo = ElectEchoMessage {
+            from: "127.0.0.1:3001".to_string(),
+            to: "127.0.0.1:3000".to_string(),
+            round: 2,
+            candidate: "127.0.0.1:3000".to_string(),
+            joined: true,
+            power: 3,
+            parts: vec![PartOfAProblem::new("127.0.0.1:3000#1", 10, 20)],
+        };
+        let parsed = parse_message(&echo.serialize()).unwrap();
+        let parsed = parsed.as_any().downcast_ref::<ElectEchoMessage>().unwrap();
+        assert_eq!((parsed.parts[0].start, parsed.parts[0].end), (10, 20));
+        assert_eq!(parsed.power, 3);
+    }
+
+    #[test]
+    fn short_frames_are_rejected() {
+        for frame in ["PING", "JOB|a|b|job|sha256|abc|1", "SOLVE|a|b|job|0", "SOLVE_RESPONSE|a|b|job|0|9", "ELECT|a|b|1", "ELECT_ECHO|a|b|1|c|true|3", "REPLICA|a|b|c|1"] {
+            assert!(parse_message(frame).is_none(), "{}", frame);
+        }
+    }
+
+    #[test]
+    fn job_keeps_an_alphabet_with_separators() {
+        let job = JobMessage {
+            from: "127.0.0.1:3000".to_string(),
+            to: "127.0.0.1:3001".to_string(),
+            job_id: "127.0.0.1:3000#1".to_string(),
+            algorithm: "sha256".to_string(),
+            alphabet: "a|b,c\\d".to_string(),
+            min_len: 1,
+            max_len: 4,
+            targets: vec!["ab|cd".to_string(), "ef".to_string()],
+            chunk_size: 100,
+            priority: 2,
+            share: 3,
+        };
+        let parsed = parse_message(&job.serialize()).unwrap();
+        let parsed = parsed.as_any().downcast_ref::<JobMessage>().unwrap();
+        assert_eq!(parsed.alphabet, job.alphabet);
+        assert_eq!(parsed.targets, job.targets);
+        assert_eq!((parsed.max_len, parsed.chunk_size, parsed.priority, parsed.share), (4, 100, 2, 3));
+    }
+
+    #[test]
+    fn solve_response_keeps_a_solution_with_separators() {
+        let response = SolveResponseMessage {
+            from: "127.0.0.1:3001".to_string(),
+            to: "127.0.0.1:3000".to_string(),
+            job_id: "127.0.0.1:3000#1".to_string(),
+            start: 10,
+            end: 20,
+            solution: Some("a|b\\".to_string()),
+            space_searched: true,
+        };
+        let parsed = parse_message(&response.serialize()).unwrap();
+        let parsed = parsed.as_any().downcast_ref::<SolveResponseMessage>().unwrap();
+        assert_eq!((parsed.start, parsed.end, parsed.solution.clone(), parsed.space_searched), (10, 20, response.solution.clone(), true));
+    }
+
+    #[test]
+    fn text_survives_escaping() {
+        for text in ["", "plain", "a|b", "a,b;c", "two\nlines\n", "back\\slash\\", "\\n is not a new line", "\\p|\\c,\\s;\\\\"] {
+            let escaped = escape_text(text);
+            assert!(!escaped.contains(['|', ',', ';', '\n']), "{:?}", escaped);
+            assert_eq!(unescape_text(&escaped), text);
+        }
+    }
+
+    #[test]
+    fn snapshot_report_survives_a_message() {
+        let state = SnapshotStateMessage {
+            from: "127.0.0.1:3001".to_string(),
+            to: "127.0.0.1:3000".to_string(),
+            id: "1000@127.0.0.1:3000".to_string(),
+            friends: vec!["127.0.0.1:3000".to_string(), "127.0.0.1:3002".to_string()],
+            report: "--- Node 127.0.0.1:3001 ---\nChannel from 127.0.0.1:3000: 1 messages in flight\n  SOLVE|a|b|job|0|9\n  c:\\dir, x;y\n".to_string(),
+        };
+        let parsed = parse_message(&state.serialize()).unwrap();
+        let parsed = parsed.as_any().downcast_ref::<SnapshotStateMessage>().unwrap();
+        assert_eq!(parsed.report, state.report);
+        assert_eq!(parsed.friends, state.friends);
+    }
+
+    #[test]
+    fn tree_survives_encoding() {
+        let entries = vec![
+            TreeEntry {
+                address: "127.0.0.1:3000".to_string(),
+                parent: None,
+                state: "LEADER".to_string(),
+                power: 2,
+                threads: 1,
+                part: Some(("127.0.0.1:3000#1".to_string(), 0, 99, 42)),
+                pending: 3,
+            },
+            TreeEntry {
+                address: "node,a;b|c\\d".to_string(),
+                parent: Some("127.0.0.1:3000".to_string()),
+                state: "UNREACHABLE\n".to_string(),
+                power: 0,
+                threads: 0,
+                part: None,
+                pending: 0,
+            },
+        ];
+        let decoded = decode_tree(&encode_tree(&entries)).unwrap();
+        assert_eq!(decoded.len(), 2);
+        for (decoded, entry) in decoded.iter().zip(entries.iter()) {
+            assert_eq!((&decoded.address, &decoded.parent, &decoded.state), (&entry.address, &entry.parent, &entry.state));
+            assert_eq!((decoded.power, decoded.threads, &decoded.part, decoded.pending), (entry.power, entry.threads, &entry.part, entry.pending));
+        }
+        assert!(decode_tree("").unwrap().is_empty());
+        assert!(decode_tree("127.0.0.1:3000,-,LEADER,1,1,-,0,0").is_none());
+    }
+}
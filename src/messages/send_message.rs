@@ -1,11 +1,10 @@
-use std::net::TcpStream;
-use std::io::{Write, Read};
 use std::time::Duration;
+
 use crate::Node;
-use crate::messages::parse_message;
+use crate::communication::RequestError;
 use crate::messages::Message;
 
-pub fn send_message<T: Message>(message: &T, node: &Node) -> Option<Box<dyn Message>> {
+pub async fn send_message<T: Message>(message: &T, node: &Node) -> Option<Box<dyn Message>> {
     // Check if communication is enabled
     if !node.is_communicating() {
         eprintln!("Cannot send message, communication is off");
@@ -24,50 +23,41 @@ pub fn send_message<T: Message>(message: &T, node: &Node) -> Option<Box<dyn Mess
         return None;
     }
 
-    println!("Sending message to {}", message.to());
-    
-    // Set connection timeout to prevent stalling
-    match TcpStream::connect_timeout(
-        &message.to().parse().unwrap(),
-        Duration::from_secs(3)
-    ) {
-        Ok(mut stream) => {
-            // Set timeouts
-            let _ = stream.set_write_timeout(Some(Duration::from_secs(3)));
-            let _ = stream.set_read_timeout(Some(Duration::from_secs(3)));
-            
-            let serialized = message.serialize();
-            if let Err(e) = stream.write_all(serialized.as_bytes()) {
-                eprintln!("Failed to write to {}: {}", message.to(), e);
-                node.remove_friend(message.to());
-                return None;
-            }
-
-            // Wait for a response message and parse it
-            let mut buffer = [0u8; 1024];
-            match stream.read(&mut buffer) {
-                Ok(n) if n > 0 => {
-                    let response = String::from_utf8_lossy(&buffer[..n]).to_string();
-                    if let Some(parsed_msg) = parse_message(&response) {
-                        println!("Received valid response {}", parsed_msg.serialize());
-                        Some(parsed_msg)
-                    } else {
-                        eprintln!("Failed to parse response from {}: {}", message.to(), response);
-                        node.remove_friend(message.to());
-                        None
-                    }
+    // same id for every attempt, so the receiver can drop duplicates
+    let message_id = node.connections.new_message_id(node);
+    let max_attempts = if message.is_idempotent() { node.config.retries + 1 } else { 1 };
+    let mut backoff = Duration::from_millis(node.config.retry_backoff_ms);
+    let mut attempt = 1;
+    loop {
+        if !message.is_background() {
+            println!("Sending message {} to {} (attempt {}/{})", message_id, message.to(), attempt, max_attempts);
+        }
+        // reuses the open connection to the friend (or opens a new one)
+        let error = match node.connections.request(node, message.to(), &message_id, message).await {
+            Ok(response) => {
+                if !message.is_background() {
+                    println!("Received valid response {}", response.serialize());
                 }
-                Ok(_) | Err(_) => {
-                    eprintln!("No response received from {}", message.to());
+                return Some(response);
+            }
+            Err(e) => e,
+        };
+        eprintln!("Request {} to {} failed: {}", message_id, message.to(), error);
+        // the node is going away, it won't wait for anyone anymore
+        if attempt >= max_attempts || node.shutdown.is_cancelled() {
+            // only a friend we can't even reach is in trouble, a slow one is kept
+            // parent and children are just suspected, heartbeats decide if they are dead
+            if matches!(error, RequestError::Unreachable(_)) {
+                if node.is_tree_friend(message.to()) {
+                    node.suspect_friend(message.to());
+                } else {
                     node.remove_friend(message.to());
-                    None
                 }
             }
+            return None;
         }
-        Err(e) => {
-            eprintln!("Failed to connect to {}: {}", message.to(), e);
-            node.remove_friend(message.to());
-            None
-        }
+        tokio::time::sleep(backoff).await;
+        backoff *= 2;
+        attempt += 1;
     }
-}
\ No newline at end of file
+}
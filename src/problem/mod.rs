@@ -1,11 +1,80 @@
 use sha2::{Sha256, Digest};
-use std::sync::atomic::{AtomicBool, Ordering::Relaxed};
-use crate::{messages::SolveProblemMessage};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering::{Relaxed, SeqCst}};
+
+pub const SUPPORTED_ALGORITHMS: [&str; 1] = ["sha256"];
 
 pub trait Combinable {
     fn total_combinations(&self) -> usize;
 }
 
+/// Everything that is the same for all parts of one search. Sent once per job (JOB message),
+/// parts then only carry the job id and a range of candidate indices.
+#[derive(Debug, Clone)]
+pub struct Job {
+    pub id: String,
+    pub algorithm: String,
+    pub alphabet: String,
+    pub min_len: usize,
+    pub max_len: usize,
+    pub targets: Vec<String>,
+    // 0 = parts are split among the tree by power, otherwise workers lease chunks of this size from the leader
+    pub chunk_size: usize,
+    // queued jobs with a higher priority start first
+    pub priority: u32,
+    // slices of the solver a worker gives this job relative to other jobs it holds parts of
+    pub share: u32,
+}
+
+impl Combinable for Job {
+    // all strings of length min_len..=max_len
+    fn total_combinations(&self) -> usize {
+        let base = self.alphabet.chars().count();
+        (self.min_len..=self.max_len)
+            .map(|len| base.saturating_pow(len as u32))
+            .fold(0usize, |acc, count| acc.saturating_add(count))
+    }
+}
+
+impl Job {
+    /// Candidate with the given index. Candidates are ordered by length first, then by alphabet order.
+    pub fn index_to_candidate(&self, mut idx: usize) -> String {
+        let alphabet: Vec<char> = self.alphabet.chars().collect();
+        let base = alphabet.len();
+        let mut len = self.min_len;
+        while len < self.max_len {
+            let count = base.saturating_pow(len as u32);
+            if idx < count {
+                break;
+            }
+            idx -= count;
+            len += 1;
+        }
+        let mut chars = vec![alphabet[0]; len];
+        for c in chars.iter_mut().rev() {
+            *c = alphabet[idx % base];
+            idx /= base;
+        }
+        chars.iter().collect()
+    }
+
+    pub fn is_target(&self, candidate: &str) -> bool {
+        let digest = match self.algorithm.as_str() {
+            "sha256" => {
+                let mut hasher = Sha256::new();
+                hasher.update(candidate.as_bytes());
+                format!("{:x}", hasher.finalize())
+            }
+            _ => return false,
+        };
+        self.targets.contains(&digest)
+    }
+
+    /// Part covering the whole search space of the job.
+    pub fn whole_part(&self) -> PartOfAProblem {
+        PartOfAProblem::new(&self.id, 0, self.total_combinations().saturating_sub(1))
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum PartOfAProblemState {
     NotDistributed,
@@ -14,39 +83,33 @@ pub enum PartOfAProblemState {
     Solving,
 }
 
+/// Inclusive range of candidate indices of a job.
 #[derive(Debug, Clone)]
 pub struct PartOfAProblem {
-    pub start: String,
-    pub end: String,
-    pub alphabet: String,
-    pub hash: String,
+    pub job_id: String,
+    pub start: usize,
+    pub end: usize,
     pub state: PartOfAProblemState,
+    // id of the lease the range was granted under, kept by the holder for its progress reports
+    pub lease: Option<u64>,
 }
 
 impl PartOfAProblem {
-    pub fn new_from_problem(problem: &Problem, start: String, end: String) -> Self {
+    pub fn new(job_id: &str, start: usize, end: usize) -> Self {
         PartOfAProblem {
+            job_id: job_id.to_string(),
             start,
             end,
-            alphabet: problem.alphabet.clone(),
-            hash: problem.hash.clone(),
             state: PartOfAProblemState::NotDistributed,
+            lease: None,
         }
     }
 }
 
 impl Combinable for PartOfAProblem {
     fn total_combinations(&self) -> usize {
-        let alphabet_size = self.alphabet.len();
-        let str_to_index = |s: &str| -> usize {
-            s.chars().fold(0, |acc, c| {
-                acc * alphabet_size + self.alphabet.find(c).unwrap()
-            })
-        };
-        let start_index = str_to_index(&self.start);
-        let end_index = str_to_index(&self.end);
-        if end_index >= start_index {
-            end_index - start_index + 1
+        if self.end >= self.start {
+            self.end - self.start + 1
         } else {
             0
         }
@@ -54,163 +117,117 @@ impl Combinable for PartOfAProblem {
 }
 
 
-pub fn sort_vector_of_parts(parts: &mut Vec<PartOfAProblem>) {
-    // sort
-    let alphabet = parts[0].alphabet.clone();
-    let alphabet_str = &alphabet;
-    let str_to_index = |s: &str| -> usize {
-        let alphabet_size = alphabet_str.len();
-        s.chars().fold(0, |acc, c| {
-            acc * alphabet_size + alphabet_str.find(c).unwrap()
-        })
-    };
-    parts.sort_by_key(|p| str_to_index(&p.start));
+pub fn sort_vector_of_parts(parts: &mut [PartOfAProblem]) {
+    parts.sort_by_key(|p| p.start);
 }
 
 // merges as not distributed
-pub fn merge_parts(parts: &Vec<PartOfAProblem>) -> PartOfAProblem {    
-    sort_vector_of_parts(&mut parts.clone());
-    let alphabet = parts[0].alphabet.clone();
-    let hash = parts[0].hash.clone();
-    let start = parts.first().unwrap().start.clone();
-    let end = parts.last().unwrap().end.clone();
-    PartOfAProblem {
-        start,
-        end,
-        alphabet,
-        hash,
-        state: PartOfAProblemState::NotDistributed,
-    }
+pub fn merge_parts(parts: &[PartOfAProblem]) -> PartOfAProblem {
+    let mut sorted = parts.to_vec();
+    sort_vector_of_parts(&mut sorted);
+    PartOfAProblem::new(&sorted[0].job_id, sorted.first().unwrap().start, sorted.last().unwrap().end)
 }
 
-// vector of parts 
+// vector of parts
 pub fn update_state_of_parts(parts: &mut Vec<PartOfAProblem>, updated_part: &PartOfAProblem) {
     sort_vector_of_parts(parts);
 
     let mut new_parts = Vec::new();
-    let mut i = 0;
-    let n = parts.len();
     let mut updated = false;
 
-    while i < n {
-        let part = &parts[i];
+    for part in parts.iter() {
         // If no overlap, just push
         if updated_part.end < part.start || updated_part.start > part.end {
             new_parts.push(part.clone());
-            i += 1;
             continue;
         }
 
         // There is overlap, may need to split
         // 1. Left non-overlapping part
         if updated_part.start > part.start {
-            let left = PartOfAProblem {
-                start: part.start.clone(),
-                end: prev_str(&updated_part.start, &part.alphabet),
-                alphabet: part.alphabet.clone(),
-                hash: part.hash.clone(),
-                state: part.state.clone(),
-            };
-            new_parts.push(left);
+            new_parts.push(PartOfAProblem {
+                end: updated_part.start - 1,
+                ..part.clone()
+            });
         }
         // 2. Middle (overlapping) part: use updated_part's state
-        let overlap_start = std::cmp::max(part.start.clone(), updated_part.start.clone());
-        let overlap_end = std::cmp::min(part.end.clone(), updated_part.end.clone());
-        let middle = PartOfAProblem {
-            start: overlap_start,
-            end: overlap_end,
-            alphabet: part.alphabet.clone(),
-            hash: part.hash.clone(),
+        new_parts.push(PartOfAProblem {
+            start: part.start.max(updated_part.start),
+            end: part.end.min(updated_part.end),
             state: updated_part.state.clone(),
-        };
-        new_parts.push(middle);
+            ..part.clone()
+        });
         updated = true;
 
         // 3. Right non-overlapping part
         if updated_part.end < part.end {
-            let right = PartOfAProblem {
-                start: next_str(&updated_part.end, &part.alphabet),
-                end: part.end.clone(),
-                alphabet: part.alphabet.clone(),
-                hash: part.hash.clone(),
-                state: part.state.clone(),
-            };
-            new_parts.push(right);
+            new_parts.push(PartOfAProblem {
+                start: updated_part.end + 1,
+                ..part.clone()
+            });
         }
-        i += 1;
     }
 
     // If no overlap found, just insert the updated_part
     if !updated {
         new_parts.push(updated_part.clone());
+        sort_vector_of_parts(&mut new_parts);
     }
 
     // Merge adjacent parts with same state
     let mut merged: Vec<PartOfAProblem> = Vec::new();
     for part in new_parts.into_iter() {
-        if let Some(last) = merged.last_mut() {
-            if last.end == prev_str(&part.start, &part.alphabet) && last.state == part.state {
-                last.end = part.end.clone();
-                continue;
-            }
+        if let Some(last) = merged.last_mut()
+            && last.end + 1 == part.start && last.state == part.state {
+            last.end = part.end;
+            continue;
         }
         merged.push(part);
     }
     *parts = merged;
 }
 
-// Helper: get previous string in alphabet order
-fn prev_str(s: &str, alphabet: &str) -> String {
-    let mut chars: Vec<char> = s.chars().collect();
-    for i in (0..chars.len()).rev() {
-        let pos = alphabet.find(chars[i]).unwrap();
-        if pos > 0 {
-            chars[i] = alphabet.chars().nth(pos - 1).unwrap();
-            break;
-        } else {
-            chars[i] = alphabet.chars().last().unwrap();
+// removes the range from the parts of the same job, splitting parts that overlap it only partially
+pub fn remove_range(parts: &mut Vec<PartOfAProblem>, range: &PartOfAProblem) {
+    let mut remaining = Vec::new();
+    for part in parts.drain(..) {
+        if part.job_id != range.job_id || range.end < part.start || range.start > part.end {
+            remaining.push(part);
+            continue;
+        }
+        if range.start > part.start {
+            remaining.push(PartOfAProblem { end: range.start - 1, ..part.clone() });
+        }
+        if range.end < part.end {
+            remaining.push(PartOfAProblem { start: range.end + 1, ..part });
         }
     }
-    chars.iter().collect()
+    *parts = remaining;
 }
 
-// Helper: get next string in alphabet order
-fn next_str(s: &str, alphabet: &str) -> String {
-    let mut chars: Vec<char> = s.chars().collect();
-    let base = alphabet.len();
-    for i in (0..chars.len()).rev() {
-        let pos = alphabet.find(chars[i]).unwrap();
-        if pos + 1 < base {
-            chars[i] = alphabet.chars().nth(pos + 1).unwrap();
-            break;
-        } else {
-            chars[i] = alphabet.chars().nth(0).unwrap();
-        }
+// the pieces of the range that are not known to be searched yet - a range that comes back unsearched
+// may have been reported searched in part already (progress, a checkpoint)
+pub fn unsearched_gaps(parts: &[PartOfAProblem], range: &PartOfAProblem) -> Vec<PartOfAProblem> {
+    let mut gaps = vec![PartOfAProblem { state: PartOfAProblemState::NotDistributed, ..range.clone() }];
+    for part in parts.iter().filter(|part| part.state == PartOfAProblemState::SearchedAndNotFound) {
+        remove_range(&mut gaps, part);
     }
-    chars.iter().collect()
+    gaps
 }
 
+/// Search of one range of a job, `current` is the next index to try.
 #[derive(Debug, Clone)]
 pub struct Problem {
-    pub alphabet: String,
-    pub start: String,
-    pub end: String,
-    pub hash: String,
-    pub current: String,
+    pub job: Job,
+    pub start: usize,
+    pub end: usize,
+    pub current: usize,
 }
 
 impl Combinable for Problem {
     fn total_combinations(&self) -> usize {
-        let alphabet_size = self.alphabet.len();
-        let str_to_index = |s: &str| -> usize {
-            s.chars().fold(0, |acc, c| {
-                acc * alphabet_size + self.alphabet.find(c).unwrap()
-            })
-        };
-        let start_index = str_to_index(&self.start);
-        let end_index = str_to_index(&self.end);
-        if end_index >= start_index {
-            end_index - start_index + 1
+        if self.end >= self.start {
+            self.end - self.start + 1
         } else {
             0
         }
@@ -218,79 +235,50 @@ impl Combinable for Problem {
 }
 
 impl Problem {
-    pub fn new(
-        alphabet: String,
-        start: String,
-        end: String,
-        hash: String,
-    ) -> Self {
-        Problem {
-            alphabet,
-            start: start.clone(),
-            end: end.clone(),
-            hash,
-            current: start,
-        }
-    }
-
-    pub fn new_from_solve_message(message: &SolveProblemMessage) -> Self {
+    pub fn new(job: Job) -> Self {
+        let end = job.total_combinations().saturating_sub(1);
         Problem {
-            alphabet: message.alphabet.clone(),
-            start: message.start.clone(),
-            end: message.end.clone(),
-            hash: message.hash.clone(),
-            current: message.start.clone(),
+            job,
+            start: 0,
+            end,
+            current: 0,
         }
     }
 
-    pub fn new_from_part(part: &PartOfAProblem) -> Self {
+    pub fn new_from_part(job: &Job, part: &PartOfAProblem) -> Self {
         Problem {
-            alphabet: part.alphabet.clone(),
-            start: part.start.clone(),
-            end: part.end.clone(),
-            hash: part.hash.clone(),
-            current: part.start.clone(),
+            job: job.clone(),
+            start: part.start,
+            end: part.end,
+            current: part.start,
         }
     }
 
-    pub fn brute_force(&mut self, stop_flag: &AtomicBool) -> Option<String> {
+    // `position` is kept at `current` so other threads can see how far the search got, `end` starts at `self.end`
+    // and may be lowered by another thread meanwhile (the rest of the range was given away).
+    // position is published before end is checked, see `split_own_part`.
+    // At most `budget` candidates are tried per call, the next call continues where this one stopped.
+    // It also returns early when `paused` is set, `current` is where it paused.
+    pub fn brute_force(&mut self, stop_flag: &AtomicBool, paused: &AtomicBool, position: &AtomicUsize, end: &AtomicUsize, budget: usize) -> Option<String> {
+        let limit = self.current.saturating_add(budget);
         loop {
+            position.store(self.current, SeqCst);
+            if self.current > end.load(SeqCst) || self.current >= limit {
+                return None;
+            }
             if stop_flag.load(Relaxed) {
                 println!("Brute force stopped by stop flag.");
                 return None;
             }
-            if self.check_hash(&self.current) {
-                return Some(self.current.clone());
+            if paused.load(Relaxed) {
+                return None;
             }
-            if let None = self.next() {
-                break;
+            let candidate = self.job.index_to_candidate(self.current);
+            if self.job.is_target(&candidate) {
+                return Some(candidate);
             }
+            self.current += 1;
         }
-        None
-    }
-
-    /// Helper: convert a string to its index in the given alphabet base
-    fn str_to_index(&self, s: &str) -> usize {
-        let alphabet_size = self.alphabet.len();
-        s.chars().fold(0, |acc, c| {
-            acc * alphabet_size + self.alphabet.find(c).unwrap()
-        })
-    }
-
-    /// Helper: convert an index to a string in the given alphabet base, with minimum length
-    fn index_to_str(&self, mut idx: usize, min_len: usize) -> String {
-        let alphabet: Vec<char> = self.alphabet.chars().collect();
-        let base = alphabet.len();
-        let mut chars = Vec::new();
-        while idx > 0 {
-            chars.push(alphabet[idx % base]);
-            idx /= base;
-        }
-        while chars.len() < min_len {
-            chars.push(alphabet[0]);
-        }
-        chars.reverse();
-        chars.iter().collect()
     }
 
     /// Divide the problem into n parts, each with roughly the same number of combinations
@@ -300,69 +288,145 @@ impl Problem {
             return vec![];
         }
         let num_parts = n.min(total); // never create more parts than total combinations
-        let min_len = self.start.len().max(self.end.len());
-        let start_idx = self.str_to_index(&self.start);
-        let end_idx = self.str_to_index(&self.end);
         let mut parts = Vec::new();
-        let mut prev_start = start_idx;
+        let mut prev_start = self.start;
         let mut remaining = total;
         for i in 0..num_parts {
-            let part_size = if i == num_parts - 1 {
-                remaining
-            } else {
-                (remaining + (num_parts - i) - 1) / (num_parts - i) // ceil division for fair split
-            };
-            let part_end = if i == num_parts - 1 {
-                end_idx
-            } else {
-                prev_start + part_size - 1
-            };
-            if part_end > end_idx {
-                break;
-            }
-            let part = PartOfAProblem {
-                start: self.index_to_str(prev_start, min_len),
-                end: self.index_to_str(part_end, min_len),
-                alphabet: self.alphabet.clone(),
-                hash: self.hash.clone(),
-                state: PartOfAProblemState::NotDistributed,
-            };
-            parts.push(part);
-            prev_start = part_end + 1;
-            if remaining < part_size { break; }
+            let part_size = remaining.div_ceil(num_parts - i); // ceil division for fair split
+            parts.push(PartOfAProblem::new(&self.job.id, prev_start, prev_start + part_size - 1));
+            prev_start += part_size;
             remaining -= part_size;
-            if prev_start > end_idx { break; }
         }
         parts
     }
+}
 
-    pub fn next(&mut self) -> Option<String> {
-        if self.current == self.end {
-            return None;
-        }
-        let mut chars: Vec<char> = self.current.chars().collect();
-        for i in (0..chars.len()).rev() {
-            if let Some(pos) = self.alphabet.find(chars[i]) {
-                if pos + 1 < self.alphabet.len() {
-                    chars[i] = self.alphabet.chars().nth(pos + 1).unwrap();
-                    self.current = chars.iter().collect();
-                    return Some(self.current.clone());
-                } else {
-                    chars[i] = self.alphabet.chars().nth(0).unwrap();
-                }
-            }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job(alphabet: &str, min_len: usize, max_len: usize) -> Job {
+        Job {
+            id: "job".to_string(),
+            algorithm: "sha256".to_string(),
+            alphabet: alphabet.to_string(),
+            min_len,
+            max_len,
+            targets: Vec::new(),
+            chunk_size: 0,
+            priority: 0,
+            share: 1,
         }
-        // All characters wrapped, increase length by one
-        chars.insert(0, self.alphabet.chars().nth(0).unwrap());
-        self.current = chars.iter().collect();
-        Some(self.current.clone())
     }
 
-    pub fn check_hash(&self, candidate: &str) -> bool {
-        let mut hasher = Sha256::new();
-        hasher.update(candidate.as_bytes());
-        let result = hasher.finalize();
-        let hash_string = format!("{:x}", result);
-        hash_string == self.hash
+    fn part(start: usize, end: usize, state: PartOfAProblemState) -> PartOfAProblem {
+        PartOfAProblem { state, ..PartOfAProblem::new("job", start, end) }
     }
-}
\ No newline at end of file
+
+    fn ranges(parts: &[PartOfAProblem]) -> Vec<(usize, usize, PartOfAProblemState)> {
+        parts.iter().map(|part| (part.start, part.end, part.state.clone())).collect()
+    }
+
+    #[test]
+    fn candidates_go_by_length_then_alphabet() {
+        let job = job("abc", 1, 3);
+        assert_eq!(job.total_combinations(), 3 + 9 + 27);
+        assert_eq!(job.index_to_candidate(0), "a");
+        assert_eq!(job.index_to_candidate(2), "c");
+        // first and last of length 2
+        assert_eq!(job.index_to_candidate(3), "aa");
+        assert_eq!(job.index_to_candidate(11), "cc");
+        assert_eq!(job.index_to_candidate(12), "aaa");
+        assert_eq!(job.index_to_candidate(job.total_combinations() - 1), "ccc");
+    }
+
+    #[test]
+    fn candidates_start_at_min_len() {
+        let job = job("ab", 3, 3);
+        assert_eq!(job.total_combinations(), 8);
+        assert_eq!(job.index_to_candidate(0), "aaa");
+        assert_eq!(job.index_to_candidate(5), "bab");
+        assert_eq!(job.index_to_candidate(7), "bbb");
+        assert_eq!(job.whole_part().end, 7);
+    }
+
+    #[test]
+    fn every_index_is_a_different_candidate() {
+        let job = job("xyz", 2, 4);
+        let candidates: std::collections::HashSet<String> = (0..job.total_combinations())
+            .map(|idx| job.index_to_candidate(idx))
+            .collect();
+        assert_eq!(candidates.len(), job.total_combinations());
+        assert!(candidates.iter().all(|candidate| (2..=4).contains(&candidate.len())));
+    }
+
+    #[test]
+    fn update_splits_the_overlapped_part() {
+        let mut parts = vec![part(0, 99, PartOfAProblemState::NotDistributed)];
+        update_state_of_parts(&mut parts, &part(10, 19, PartOfAProblemState::Distributed));
+        assert_eq!(ranges(&parts), vec![
+            (0, 9, PartOfAProblemState::NotDistributed),
+            (10, 19, PartOfAProblemState::Distributed),
+            (20, 99, PartOfAProblemState::NotDistributed),
+        ]);
+    }
+
+    #[test]
+    fn update_merges_adjacent_parts_of_one_state() {
+        let mut parts = vec![
+            part(0, 9, PartOfAProblemState::SearchedAndNotFound),
+            part(10, 19, PartOfAProblemState::Distributed),
+            part(20, 29, PartOfAProblemState::SearchedAndNotFound),
+        ];
+        update_state_of_parts(&mut parts, &part(10, 19, PartOfAProblemState::SearchedAndNotFound));
+        assert_eq!(ranges(&parts), vec![(0, 29, PartOfAProblemState::SearchedAndNotFound)]);
+    }
+
+    #[test]
+    fn update_over_several_parts_and_at_the_edges() {
+        let mut parts = vec![
+            part(0, 9, PartOfAProblemState::Distributed),
+            part(10, 19, PartOfAProblemState::NotDistributed),
+        ];
+        update_state_of_parts(&mut parts, &part(5, 10, PartOfAProblemState::SearchedAndNotFound));
+        assert_eq!(ranges(&parts), vec![
+            (0, 4, PartOfAProblemState::Distributed),
+            (5, 10, PartOfAProblemState::SearchedAndNotFound),
+            (11, 19, PartOfAProblemState::NotDistributed),
+        ]);
+        // a range nobody had yet is added
+        update_state_of_parts(&mut parts, &part(20, 29, PartOfAProblemState::NotDistributed));
+        assert_eq!(ranges(&parts).last(), Some(&(11, 29, PartOfAProblemState::NotDistributed)));
+    }
+
+    #[test]
+    fn remove_range_keeps_the_rest() {
+        let mut parts = vec![part(0, 9, PartOfAProblemState::Distributed), part(20, 29, PartOfAProblemState::Distributed)];
+        remove_range(&mut parts, &PartOfAProblem::new("job", 5, 24));
+        assert_eq!(ranges(&parts), vec![(0, 4, PartOfAProblemState::Distributed), (25, 29, PartOfAProblemState::Distributed)]);
+        // exactly a whole part, and one from the middle
+        remove_range(&mut parts, &PartOfAProblem::new("job", 0, 4));
+        remove_range(&mut parts, &PartOfAProblem::new("job", 27, 27));
+        assert_eq!(ranges(&parts), vec![(25, 26, PartOfAProblemState::Distributed), (28, 29, PartOfAProblemState::Distributed)]);
+    }
+
+    #[test]
+    fn remove_range_leaves_other_jobs_alone() {
+        let mut parts = vec![PartOfAProblem::new("other", 0, 9)];
+        remove_range(&mut parts, &PartOfAProblem::new("job", 0, 9));
+        assert_eq!(parts.len(), 1);
+    }
+
+    #[test]
+    fn gaps_skip_what_was_searched() {
+        let parts = vec![
+            part(0, 9, PartOfAProblemState::SearchedAndNotFound),
+            part(10, 19, PartOfAProblemState::Distributed),
+            part(20, 24, PartOfAProblemState::SearchedAndNotFound),
+            part(25, 39, PartOfAProblemState::Distributed),
+        ];
+        let gaps = unsearched_gaps(&parts, &part(5, 29, PartOfAProblemState::Distributed));
+        assert_eq!(ranges(&gaps), vec![(10, 19, PartOfAProblemState::NotDistributed), (25, 29, PartOfAProblemState::NotDistributed)]);
+        assert!(unsearched_gaps(&parts, &part(0, 9, PartOfAProblemState::Distributed)).is_empty());
+    }
+}
@@ -1,7 +1,15 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use crate::problem::{PartOfAProblem, Problem};
-use std::sync::atomic::{AtomicBool};
+use crate::communication::ConnectionManager;
+use crate::problem::{Combinable, Job, PartOfAProblem, PartOfAProblemState, Problem, remove_range, update_state_of_parts};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use tokio::runtime::Handle;
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
 
 #[derive(PartialEq, Eq, Clone, Copy, Debug)]
 pub enum FriendType {
@@ -11,12 +19,23 @@ pub enum FriendType {
 }
 
 
+// what heartbeats tell about a friend: Alive -> Suspected after a few missed ones -> Dead
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum FriendHealth {
+    Alive,
+    Suspected,
+    Dead,
+}
+
 #[derive(Debug)]
 pub struct Friend {
     pub address: String,
     pub friend_type: FriendType,
     pub power: u32,
-    pub solving_part_of_a_problem: Option<PartOfAProblem>
+    // ranges sent to this friend's subtree that it did not report as searched yet
+    pub assigned_parts: Vec<PartOfAProblem>,
+    pub health: FriendHealth,
+    pub missed_heartbeats: u32,
 }
 
 impl Friend {
@@ -25,7 +44,9 @@ impl Friend {
             address,
             friend_type: FriendType::NotSpecified,
             power: 0,
-            solving_part_of_a_problem: None,
+            assigned_parts: Vec::new(),
+            health: FriendHealth::Alive,
+            missed_heartbeats: 0,
         }
     }
 
@@ -41,18 +62,301 @@ impl Friend {
     pub fn is_child(&self) -> bool {
         matches!(self.friend_type, FriendType::Child)
     }
+
+    // parent and children are watched by heartbeats
+    pub fn is_in_tree(&self) -> bool {
+        matches!(self.friend_type, FriendType::Parent | FriendType::Child)
+    }
 }
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug)]
 pub enum NodeState {
     IDLE,
     LEADER {
-        problem: Option<Problem>,
-        parts: Vec<PartOfAProblem>,
+        // in the order they were submitted
+        jobs: Vec<LeaderJob>,
     },
     WORKER,
 }
 
+impl NodeState {
+    // leader that knows this job, results of paused jobs are still recorded
+    pub fn is_active_job(&self, job_id: &str) -> bool {
+        self.leader_job(job_id).is_some()
+    }
+
+    pub fn leader_job(&self, job_id: &str) -> Option<&LeaderJob> {
+        match self {
+            NodeState::LEADER { jobs } => jobs.iter().find(|job| job.problem.job.id == job_id),
+            _ => None,
+        }
+    }
+
+    pub fn leader_job_mut(&mut self, job_id: &str) -> Option<&mut LeaderJob> {
+        match self {
+            NodeState::LEADER { jobs } => jobs.iter_mut().find(|job| job.problem.job.id == job_id),
+            _ => None,
+        }
+    }
+
+    // highest priority first, then the older one
+    pub fn running_jobs(&self) -> Vec<Job> {
+        let NodeState::LEADER { jobs } = self else {
+            return Vec::new();
+        };
+        let mut running: Vec<Job> = jobs.iter()
+            .filter(|job| job.status == JobStatus::Running)
+            .map(|job| job.problem.job.clone())
+            .collect();
+        running.sort_by_key(|job| std::cmp::Reverse(job.priority));
+        running
+    }
+}
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum JobStatus {
+    // waits until fewer than max_jobs jobs run
+    Queued,
+    Running,
+    // nobody searches it until it is resumed
+    Paused,
+}
+
+// job of the leader with its interval map
+#[derive(Debug)]
+pub struct LeaderJob {
+    pub problem: Problem,
+    pub parts: Vec<PartOfAProblem>,
+    pub status: JobStatus,
+}
+
+// power calculation in progress, finished when no friend is waited for or at the deadline
+#[derive(Debug)]
+pub struct PowerCalculation {
+    // None for the leader
+    pub parent: Option<String>,
+    pub waiting: HashSet<String>,
+    pub total: u32,
+    pub deadline: Instant,
+}
+
+// leader election this node takes part in, kept after it ends so older waves are recognized
+#[derive(Debug)]
+pub struct Election {
+    pub round: u64,
+    pub candidate: String,
+    // None for the candidate itself
+    pub parent: Option<String>,
+    pub waiting: HashSet<String>,
+    // power and parts reported by my subtree so far
+    pub power: u32,
+    pub parts: Vec<PartOfAProblem>,
+    pub finished: bool,
+}
+
+// chunk of a job held by a worker until `expires`, progress reports move the expiry
+#[derive(Debug, Clone)]
+pub struct Lease {
+    // the holder reports progress with it, a range leased again gets a new one
+    pub id: u64,
+    pub holder: String,
+    pub part: PartOfAProblem,
+    // everything before it is searched
+    pub position: usize,
+    pub expires: Instant,
+}
+
+// leader side of the lease scheduler
+#[derive(Debug)]
+pub struct LeaseQueue {
+    pub job_id: String,
+    pub chunk_size: usize,
+    // ranges nobody holds, chunks are cut from the front
+    pub free: VecDeque<PartOfAProblem>,
+    pub leases: Vec<Lease>,
+    pub next_id: u64,
+}
+
+// last progress report of a worker, kept by the leader
+#[derive(Debug, Clone)]
+pub struct WorkerProgress {
+    pub job_id: String,
+    pub position: usize,
+    pub end: usize,
+    pub rate: u64,
+    pub updated: Instant,
+}
+
+// what gossip knows about a node - a higher (incarnation, heartbeat) is newer, the incarnation changes on restart
+#[derive(Debug, Clone)]
+pub struct Member {
+    pub incarnation: u64,
+    pub heartbeat: u64,
+    // when the heartbeat last went up
+    pub updated: Instant,
+    // forgotten, kept so older gossip doesn't bring it back
+    pub gone: bool,
+}
+
+// one node of the tree as reported to the `tree` command
+#[derive(Debug, Clone)]
+pub struct TreeEntry {
+    pub address: String,
+    // None for the leader (or a node that lost its parent)
+    pub parent: Option<String>,
+    // IDLE, WORKER, LEADER, or UNREACHABLE for a child that didn't answer
+    pub state: String,
+    pub power: u32,
+    // solver threads running
+    pub threads: u32,
+    // the part being searched: job id, start, end (moves when stolen from) and the position reached
+    pub part: Option<(String, usize, usize, usize)>,
+    // my own parts waiting for the solver
+    pub pending: usize,
+}
+
+// the `tree` command's echo going through this node, like the power calculation
+#[derive(Debug)]
+pub struct TreeCollection {
+    pub id: String,
+    // None on the node the command runs on
+    pub parent: Option<String>,
+    // children that didn't answer yet, with the power I know of them
+    pub waiting: HashMap<String, u32>,
+    pub entries: Vec<TreeEntry>,
+    // sent to the parent, or ready for the command
+    pub done: bool,
+}
+
+// my part of a snapshot (Chandy-Lamport), kept after it is reported so later markers of it are ignored
+#[derive(Debug, Clone)]
+pub struct LocalSnapshot {
+    pub id: String,
+    // gets the report
+    pub initiator: String,
+    // what print_info showed at the cut, once the handlers of what came before the first marker were done
+    pub state: String,
+    // cancelled once the state is recorded and my markers are written, messages that came meanwhile wait for it
+    pub captured: CancellationToken,
+    // messages and responses that came after the cut, by sender - in flight at the moment of the cut
+    pub channels: HashMap<String, Vec<String>>,
+    // message ids (and response request ids) in the channels, a retry is recorded once
+    pub recorded: HashSet<String>,
+    // senders whose marker came, nothing after it belongs to the snapshot
+    pub closed: HashSet<String>,
+    pub done: bool,
+}
+
+// the reports of a snapshot collected by its initiator, by address: (friends of the node, report)
+#[derive(Debug, Clone)]
+pub struct SnapshotReports {
+    pub id: String,
+    pub reports: HashMap<String, (Vec<String>, String)>,
+}
+
+// Dijkstra-Scholten state of a job on this node, there is one while the node is engaged in the job
+#[derive(Debug, Clone, Default)]
+pub struct Engagement {
+    // whose work engaged me, it gets the signal once I am done - None on the leader, the root
+    pub parent: Option<String>,
+    // work sent and not signaled back yet, by receiver (my own address while I am still handling work that came)
+    pub deficit: HashMap<String, u32>,
+}
+
+// copy of the leader's interval map kept by a standby, newer versions replace older ones
+#[derive(Debug, Clone)]
+pub struct LeaderReplica {
+    pub leader: String,
+    pub version: u64,
+    pub parts: Vec<PartOfAProblem>,
+}
+
+// tunables of a node, filled from command line arguments
+#[derive(Debug, Clone)]
+pub struct NodeConfig {
+    // max concurrent outbound requests and max concurrently handled inbound requests
+    pub max_in_flight: usize,
+    // how many times an idempotent message is resent when no response arrives
+    pub retries: u32,
+    // delay before the first retry, doubled for every next one
+    pub retry_backoff_ms: u64,
+    // time the leader gives the whole tree to report its power
+    pub calc_timeout_ms: u64,
+    // how often parent and children exchange heartbeats
+    pub heartbeat_interval_ms: u64,
+    // missed heartbeats in a row before a friend is suspected
+    pub suspect_after: u32,
+    // missed heartbeats in a row before a friend is declared dead
+    pub dead_after: u32,
+    // children of the leader that get a copy of its job state
+    pub standbys: usize,
+    // ranges with less candidates left are not split for idle nodes
+    pub steal_min_size: usize,
+    // how often workers report their position to the leader
+    pub progress_interval_ms: u64,
+    // 0 = jobs are split among the tree by power, otherwise workers lease chunks of this size
+    pub chunk_size: usize,
+    // how long a chunk stays leased without a progress report
+    pub lease_ms: u64,
+    // checkpoints go here, none without it
+    pub state_dir: Option<PathBuf>,
+    pub checkpoint_interval_ms: u64,
+    // jobs the leader runs at the same time, the others wait in its queue
+    pub max_jobs: usize,
+    // candidates a worker searches of one job (times its share) before it looks at parts of other jobs
+    pub slice_size: usize,
+    // the leader releases the tree when its last job is over
+    pub release_on_finish: bool,
+    // 0 = no gossip, friends are only the ones given by hand
+    pub gossip_interval_ms: u64,
+    pub gossip_fanout: usize,
+    // gossip adds friends only up to this many
+    pub max_friends: usize,
+    // where the listener is bound, none = my own address
+    pub listen_address: Option<SocketAddrV4>,
+    // multicast group of the LAN discovery, none = discovery off
+    pub discovery_group: Option<SocketAddrV4>,
+    pub discovery_interface: Ipv4Addr,
+    pub discovery_interval_ms: u64,
+    // announcements of other clusters (or without the key) are ignored
+    pub cluster_name: String,
+    pub cluster_key: Option<String>,
+}
+
+impl Default for NodeConfig {
+    fn default() -> Self {
+        NodeConfig {
+            max_in_flight: 64,
+            retries: 3,
+            retry_backoff_ms: 200,
+            calc_timeout_ms: 10000,
+            heartbeat_interval_ms: 1000,
+            suspect_after: 2,
+            dead_after: 5,
+            standbys: 1,
+            steal_min_size: 1000,
+            progress_interval_ms: 2000,
+            chunk_size: 0,
+            lease_ms: 10000,
+            state_dir: None,
+            checkpoint_interval_ms: 5000,
+            max_jobs: 2,
+            slice_size: 1000000,
+            release_on_finish: false,
+            gossip_interval_ms: 1000,
+            gossip_fanout: 2,
+            max_friends: 8,
+            listen_address: None,
+            discovery_group: None,
+            discovery_interface: Ipv4Addr::UNSPECIFIED,
+            discovery_interval_ms: 2000,
+            cluster_name: "dsva".to_string(),
+            cluster_key: None,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Node {
     pub address: String,
@@ -61,12 +365,63 @@ pub struct Node {
     pub state: Arc<Mutex<NodeState>>,
     pub power: u32,
     pub solving_part_of_a_problem: Arc<Mutex<Option<PartOfAProblem>>>,
+    // my own parts waiting for the solver thread
+    pub pending_parts: Arc<Mutex<VecDeque<PartOfAProblem>>>,
+    pub solver_running: Arc<AtomicBool>,
+    // set while I am handing everything over to my parent, what I hold isn't mine to report as done
+    pub leaving: Arc<AtomicBool>,
+    // index the solver is at in solving_part_of_a_problem and where it stops, the end moves when work is stolen
+    pub current_position: Arc<AtomicUsize>,
+    pub current_end: Arc<AtomicUsize>,
+    // candidates my solver tried so far, for the rate in progress reports
+    pub searched: Arc<AtomicUsize>,
+    pub power_calculation: Arc<Mutex<Option<PowerCalculation>>>,
+    pub tree_collection: Arc<Mutex<Option<TreeCollection>>>,
+    // address of the leader of my tree
+    pub leader: Arc<Mutex<Option<String>>>,
+    // id of the CALC wave that built my tree, CALCs of any other wave (or of this one from another path) are rejected
+    pub wave: Arc<Mutex<Option<String>>>,
+    pub election: Arc<Mutex<Option<Election>>>,
+    // set on standbys of the leader
+    pub replica: Arc<Mutex<Option<LeaderReplica>>>,
+    // bumped on every change of the leader's parts, the replication task is woken up
+    pub leader_version: Arc<AtomicU64>,
+    pub leader_changed: Arc<Notify>,
+    // leader only, jobs in lease mode by job id
+    pub leases: Arc<Mutex<HashMap<String, LeaseQueue>>>,
+    // leader only, paths of idle nodes that asked for work while there was nothing to give
+    pub work_waiting: Arc<Mutex<Vec<Vec<String>>>>,
+    // leader only, by worker address
+    pub worker_progress: Arc<Mutex<HashMap<String, WorkerProgress>>>,
+    // nodes of the cluster learned by gossip (me included), by address
+    pub members: Arc<Mutex<HashMap<String, Member>>>,
+    // start time of this process, newer than anything gossiped about my previous run
+    pub incarnation: u64,
+    pub snapshot: Arc<Mutex<Option<LocalSnapshot>>>,
+    // initiator of a snapshot only
+    pub snapshot_reports: Arc<Mutex<Option<SnapshotReports>>>,
+    // jobs I am engaged in for the termination detection, by job id
+    pub engagements: Arc<Mutex<HashMap<String, Engagement>>>,
+    // serialized messages for the leader that had nowhere to go while the tree was being rebuilt
+    pub undelivered: Arc<Mutex<Vec<String>>>,
+    // jobs registered by the leader, by job id
+    pub jobs: Arc<Mutex<HashMap<String, Job>>>,
     // default true = not solving
     pub stop_flag: Arc<AtomicBool>,
+    // the whole tree is paused, parts wait in pending_parts until resume
+    pub paused: Arc<AtomicBool>,
+    // open connections to friends, shared by all threads
+    pub connections: ConnectionManager,
+    pub config: NodeConfig,
+    // runtime running the networking, used by plain threads (commands, solver) to send messages
+    pub runtime: Handle,
+    // cancelled when the node is shutting down
+    pub shutdown: CancellationToken,
 }
 
 impl Node {
-    pub fn new(address: String, friends: Vec<Friend>) -> Self {
+    // must be called from within the tokio runtime
+    pub fn new(address: String, friends: Vec<Friend>, config: NodeConfig) -> Self {
         Node {
             address,
             friends: Arc::new(Mutex::new(friends)),
@@ -74,11 +429,45 @@ impl Node {
             state: Arc::new(Mutex::new(NodeState::IDLE)),
             power: 1,
             solving_part_of_a_problem: Arc::new(Mutex::new(None)),
+            pending_parts: Arc::new(Mutex::new(VecDeque::new())),
+            solver_running: Arc::new(AtomicBool::new(false)),
+            leaving: Arc::new(AtomicBool::new(false)),
+            current_position: Arc::new(AtomicUsize::new(0)),
+            current_end: Arc::new(AtomicUsize::new(0)),
+            searched: Arc::new(AtomicUsize::new(0)),
+            power_calculation: Arc::new(Mutex::new(None)),
+            tree_collection: Arc::new(Mutex::new(None)),
+            leader: Arc::new(Mutex::new(None)),
+            wave: Arc::new(Mutex::new(None)),
+            election: Arc::new(Mutex::new(None)),
+            replica: Arc::new(Mutex::new(None)),
+            leader_version: Arc::new(AtomicU64::new(0)),
+            leader_changed: Arc::new(Notify::new()),
+            worker_progress: Arc::new(Mutex::new(HashMap::new())),
+            leases: Arc::new(Mutex::new(HashMap::new())),
+            work_waiting: Arc::new(Mutex::new(Vec::new())),
+            members: Arc::new(Mutex::new(HashMap::new())),
+            incarnation: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64,
+            snapshot: Arc::new(Mutex::new(None)),
+            snapshot_reports: Arc::new(Mutex::new(None)),
+            engagements: Arc::new(Mutex::new(HashMap::new())),
+            undelivered: Arc::new(Mutex::new(Vec::new())),
+            jobs: Arc::new(Mutex::new(HashMap::new())),
             stop_flag: Arc::new(AtomicBool::new(true)),
+            paused: Arc::new(AtomicBool::new(false)),
+            connections: ConnectionManager::new(config.max_in_flight),
+            config,
+            runtime: Handle::current(),
+            shutdown: CancellationToken::new(),
         }
     }
     
     pub fn print_info(&self) {
+        print!("{}", self.info());
+    }
+
+    // everything print_info shows, also recorded by snapshots
+    pub fn info(&self) -> String {
         let friends = self.friends.lock().unwrap();
         let communicating = self.communicating.lock().unwrap();
         let state = self.state.lock().unwrap();
@@ -87,15 +476,71 @@ impl Node {
         output.push_str("=== Node Information ===\n");
         output.push_str(&format!("Node Address: {}\n", self.address));
         output.push_str(&format!("Communicating: {}\n", *communicating));
-        output.push_str(&format!("State: {:?}\n", *state));
+        output.push_str(&format!("Paused: {}\n", self.paused.load(Ordering::SeqCst)));
+        match &*state {
+            NodeState::LEADER { jobs } => {
+                output.push_str("State: LEADER\n");
+                for job in jobs.iter() {
+                    output.push_str(&format!("Job {} ({:?}, priority {}, share {}): {:?}\n",
+                        job.problem.job.id, job.status, job.problem.job.priority, job.problem.job.share, job.parts));
+                    output.push_str(&self.progress_summary(job));
+                }
+            }
+            state => output.push_str(&format!("State: {:?}\n", state)),
+        }
+        for queue in self.leases.lock().unwrap().values() {
+            output.push_str(&format!("Free ranges of job {}: {:?}\n", queue.job_id, queue.free));
+            for lease in queue.leases.iter() {
+                output.push_str(&format!(" - {} leases {}..={} (at {}), expires in {:?}\n",
+                    lease.holder, lease.part.start, lease.part.end, lease.position, lease.expires.saturating_duration_since(Instant::now())));
+            }
+        }
+        output.push_str(&format!("Leader: {:?}\n", *self.leader.lock().unwrap()));
+        output.push_str(&format!("Wave: {:?}\n", *self.wave.lock().unwrap()));
+        if let Some(replica) = &*self.replica.lock().unwrap() {
+            output.push_str(&format!("Standby of {} (version {}): {:?}\n", replica.leader, replica.version, replica.parts));
+        }
         output.push_str(&format!("Solving Part Of A Problem: {:?}\n", *self.solving_part_of_a_problem.lock().unwrap()));
+        if self.solver_running.load(Ordering::SeqCst) {
+            output.push_str(&format!("Solver at {} of ..={}\n", self.current_position.load(Ordering::SeqCst), self.current_end.load(Ordering::SeqCst)));
+        }
+        output.push_str(&format!("Pending Parts: {:?}\n", *self.pending_parts.lock().unwrap()));
+        for (job_id, engagement) in self.engagements.lock().unwrap().iter() {
+            output.push_str(&format!("Engaged in job {} by {:?}, waiting for {:?}\n", job_id, engagement.parent, engagement.deficit));
+        }
+        let members = self.members.lock().unwrap();
+        let mut known: Vec<&String> = members.iter().filter(|(_, member)| !member.gone).map(|(address, _)| address).collect();
+        known.sort();
+        output.push_str(&format!("Members: {:?}\n", known));
         output.push_str("Friends:\n");
         for friend in friends.iter() {
             output.push_str(&format!(" - {:?}\n", friend));
         }
         output.push_str("========================\n");
+        output
+    }
 
-        print!("{}", output);
+    // percent done, speed of the workers that reported recently and the time left at that speed
+    pub fn progress_summary(&self, job: &LeaderJob) -> String {
+        let total = job.problem.total_combinations();
+        let searched: usize = job.parts.iter()
+            .filter(|part| part.state == PartOfAProblemState::SearchedAndNotFound)
+            .map(|part| part.total_combinations())
+            .sum();
+        let percent = if total == 0 { 100.0 } else { searched as f64 * 100.0 / total as f64 };
+        let recent = Duration::from_millis(self.config.progress_interval_ms * 3);
+        let progress = self.worker_progress.lock().unwrap();
+        let recent_workers: Vec<&WorkerProgress> = progress.values()
+            .filter(|worker| worker.job_id == job.problem.job.id && worker.updated.elapsed() <= recent)
+            .collect();
+        let rate: u64 = recent_workers.iter().map(|worker| worker.rate).sum();
+        let eta = ((total - searched) as u64).checked_div(rate)
+            .map_or("unknown".to_string(), |secs| format_duration(Duration::from_secs(secs)));
+        let mut output = format!("Progress: {}/{} ({:.2}%), {} candidates/s from {} workers, ETA {}\n", searched, total, percent, rate, recent_workers.len(), eta);
+        for (worker, report) in progress.iter().filter(|(_, report)| report.job_id == job.problem.job.id) {
+            output.push_str(&format!(" - {} at {}..={}, {} candidates/s\n", worker, report.position, report.end, report.rate));
+        }
+        output
     }
 
     pub fn is_friend(&self, address: &str) -> bool {
@@ -107,7 +552,55 @@ impl Node {
         let mut friends = self.friends.lock().unwrap();
         friends.retain(|f| f.address() != address);
         println!("Removed friend: {}", address);
-        // TODO if calc - if child - inform parent - if parent - stop and propagate...
+    }
+
+    // removes the friend and returns it, so whatever it was doing can be recovered
+    pub fn take_friend(&self, address: &str) -> Option<Friend> {
+        let mut friends = self.friends.lock().unwrap();
+        let index = friends.iter().position(|f| f.address() == address)?;
+        println!("Removed friend: {}", address);
+        Some(friends.remove(index))
+    }
+
+    pub fn is_tree_friend(&self, address: &str) -> bool {
+        let friends = self.friends.lock().unwrap();
+        friends.iter().any(|f| f.address() == address && f.is_in_tree())
+    }
+
+    // any message or answered heartbeat from a friend proves it is alive
+    pub fn mark_friend_alive(&self, address: &str) {
+        let mut friends = self.friends.lock().unwrap();
+        if let Some(friend) = friends.iter_mut().find(|f| f.address() == address) {
+            if friend.health == FriendHealth::Suspected {
+                println!("Friend {} is alive again", address);
+            }
+            friend.health = FriendHealth::Alive;
+            friend.missed_heartbeats = 0;
+        }
+    }
+
+    // failed send - only suspicion, death is decided by heartbeats
+    pub fn suspect_friend(&self, address: &str) {
+        let mut friends = self.friends.lock().unwrap();
+        if let Some(friend) = friends.iter_mut().find(|f| f.address() == address)
+            && friend.health == FriendHealth::Alive {
+            println!("Friend {} is suspected", address);
+            friend.health = FriendHealth::Suspected;
+        }
+    }
+
+    // returns the new health of the friend, None if it is not a friend anymore
+    pub fn mark_friend_missed_heartbeat(&self, address: &str) -> Option<FriendHealth> {
+        let mut friends = self.friends.lock().unwrap();
+        let friend = friends.iter_mut().find(|f| f.address() == address)?;
+        friend.missed_heartbeats += 1;
+        if friend.missed_heartbeats >= self.config.dead_after {
+            friend.health = FriendHealth::Dead;
+        } else if friend.missed_heartbeats >= self.config.suspect_after && friend.health == FriendHealth::Alive {
+            println!("Friend {} is suspected ({} missed heartbeats)", address, friend.missed_heartbeats);
+            friend.health = FriendHealth::Suspected;
+        }
+        Some(friend.health)
     }
 
     pub fn add_friend(&self, address: String) {
@@ -132,19 +625,45 @@ impl Node {
         matches!(*self.state.lock().unwrap(), NodeState::LEADER { .. })
     }
 
-    pub fn get_parent_address(&self) -> String {
+    // None if there is no parent (leader, or the parent died)
+    pub fn parent_address(&self) -> Option<String> {
         let friends = self.friends.lock().unwrap();
-        for friend in friends.iter() {
-            if friend.friend_type == FriendType::Parent {
-                return friend.address.clone();
-            }
+        friends.iter()
+            .find(|friend| friend.friend_type == FriendType::Parent)
+            .map(|friend| friend.address.clone())
+    }
+
+    // the child's subtree reported the range as searched or gave it back, it no longer holds it
+    pub fn release_child_part(&self, address: &str, part: &PartOfAProblem) {
+        let mut friends = self.friends.lock().unwrap();
+        if let Some(friend) = friends.iter_mut().find(|f| f.address() == address && f.is_child()) {
+            remove_range(&mut friend.assigned_parts, part);
         }
-        panic!("Parent friend not found");
     }
 
     pub fn set_state_leader(&self) {
         let mut state = self.state.lock().unwrap();
-        *state = NodeState::LEADER { problem: None, parts: Vec::new()};
+        *state = NodeState::LEADER { jobs: Vec::new() };
+        *self.leader.lock().unwrap() = Some(self.address.clone());
+    }
+
+    pub fn is_electing(&self) -> bool {
+        self.election.lock().unwrap().as_ref().is_some_and(|election| !election.finished)
+    }
+
+    // every change of the leader's interval map goes through here, so it reaches the standbys
+    pub fn update_leader_parts(&self, part: &PartOfAProblem) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(job) = state.leader_job_mut(&part.job_id) {
+            update_state_of_parts(&mut job.parts, part);
+            drop(state);
+            self.leader_parts_changed();
+        }
+    }
+
+    pub fn leader_parts_changed(&self) {
+        self.leader_version.fetch_add(1, Ordering::SeqCst);
+        self.leader_changed.notify_one();
     }
 
     pub fn set_state_worker(&self) {
@@ -152,23 +671,116 @@ impl Node {
         *state = NodeState::WORKER;
     }
 
+    // start time @ my address, no other wave gets the same id, see wave_beats
+    pub fn new_wave_id(&self) -> String {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+        format!("{}@{}", now, self.address)
+    }
+
+    // takes me into the tree of the wave if I am idle, or in a wave it beats that has no jobs yet - checked and set
+    // under one lock, so two waves arriving at the same time can't both get me.
+    // Ok(the wave I was in before), Err(the wave I stay in)
+    pub fn try_join_wave(&self, wave: &str, leader: &str) -> Result<Option<String>, Option<String>> {
+        let has_jobs = !self.jobs.lock().unwrap().is_empty();
+        let mut state = self.state.lock().unwrap();
+        let mut current = self.wave.lock().unwrap();
+        let idle = matches!(*state, NodeState::IDLE);
+        let beaten = current.as_ref().is_some_and(|current| wave_beats(wave, current)) && !has_jobs;
+        if !idle && !beaten {
+            return Err(current.clone());
+        }
+        *state = if leader == self.address { NodeState::LEADER { jobs: Vec::new() } } else { NodeState::WORKER };
+        *self.leader.lock().unwrap() = Some(leader.to_string());
+        Ok(current.replace(wave.to_string()).filter(|_| !idle))
+    }
+
+    // back to idle outside of any tree, my friends are neither parent nor children anymore
+    pub fn forget_tree(&self) {
+        *self.state.lock().unwrap() = NodeState::IDLE;
+        *self.wave.lock().unwrap() = None;
+        *self.leader.lock().unwrap() = None;
+        *self.power_calculation.lock().unwrap() = None;
+        let mut friends = self.friends.lock().unwrap();
+        for friend in friends.iter_mut() {
+            friend.set_type(FriendType::NotSpecified);
+            friend.power = 0;
+            friend.assigned_parts.clear();
+        }
+    }
+
+    // there is only one parent, a sender I didn't know yet (found me by gossip, discovery) becomes a friend first
     pub fn set_parent(&self, address: &str) {
+        if !self.is_friend(address) {
+            self.add_friend(address.to_string());
+        }
         let mut friends = self.friends.lock().unwrap();
         for friend in friends.iter_mut() {
             if friend.address().trim() == address.trim() {
                 friend.set_type(FriendType::Parent);
-                return;
+            } else if friend.friend_type == FriendType::Parent {
+                friend.set_type(FriendType::NotSpecified);
             }
         }
-        panic!("Parent friend with address '{}' not found", address);
     }
 }
 
 
+// waves are started by `cal`, the earlier one wins. If they started in the same millisecond the lower address does -
+// by IP, then by port as a number (so 127.0.0.1:9000 beats 127.0.0.1:10000), an address that isn't IP:port loses
+// to one that is and those go by text
+pub fn wave_beats(wave: &str, other: &str) -> bool {
+    wave_key(wave) < wave_key(other)
+}
+
+// a wave id is start time @ leader address
+pub fn wave_leader(wave: &str) -> &str {
+    wave.split_once('@').map_or(wave, |(_, leader)| leader)
+}
+
+fn wave_key(wave: &str) -> (u64, bool, Option<SocketAddr>, &str) {
+    let (time, leader) = wave.split_once('@').unwrap_or(("", wave));
+    let address: Option<SocketAddr> = leader.parse().ok();
+    (time.parse().unwrap_or(u64::MAX), address.is_none(), address, leader)
+}
+
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    format!("{}h {}m {}s", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
 pub fn parse_address(input: &str) -> String {
     if input.contains(':') {
         input.to_string()
     } else {
         format!("127.0.0.1:{}", input)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn earlier_wave_wins() {
+        assert!(wave_beats("1000@127.0.0.1:3005", "1001@127.0.0.1:3000"));
+        assert!(!wave_beats("1001@127.0.0.1:3000", "1000@127.0.0.1:3005"));
+        // a broken time never wins
+        assert!(wave_beats("1000@127.0.0.1:3005", "x@127.0.0.1:3000"));
+    }
+
+    #[test]
+    fn same_time_goes_by_address() {
+        assert!(wave_beats("1000@127.0.0.1:3000", "1000@127.0.0.1:3001"));
+        assert!(!wave_beats("1000@127.0.0.1:3001", "1000@127.0.0.1:3000"));
+        // the port is a number, the IP goes first
+        assert!(wave_beats("1000@127.0.0.1:9000", "1000@127.0.0.1:10000"));
+        assert!(wave_beats("1000@127.0.0.2:9000", "1000@127.0.0.10:3000"));
+        assert!(wave_beats("1000@127.0.0.1:9000", "1000@node:3000"));
+    }
+
+    #[test]
+    fn wave_does_not_beat_itself() {
+        assert!(!wave_beats("1000@127.0.0.1:3000", "1000@127.0.0.1:3000"));
+        assert_eq!(wave_leader("1000@127.0.0.1:3000"), "127.0.0.1:3000");
+    }
+}